@@ -1,5 +1,6 @@
 use std::{fmt, ops};
 
+#[derive(Clone, Eq, PartialEq, Hash)]
 pub struct ByteString(Vec<u8>);
 
 impl ByteString {
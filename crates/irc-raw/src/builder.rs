@@ -0,0 +1,271 @@
+//! A builder for outgoing messages that checks that the message can be written to the wire
+//! unchanged.
+//!
+//! `Message::write_bytes` writes whatever it is given, so a parameter with an embedded CRLF can
+//! end up as a second command on the connection. `MessageBuilder::build` checks for this, and the
+//! codec runs the same checks before encoding.
+
+use crate::messages::{Command, Message};
+use crate::tags::{Tag, Tags};
+use minibot_byte_string::ByteString;
+
+/// The most parameters a message may have, per RFC 1459.
+pub const MAX_PARAMS: usize = 15;
+
+#[derive(thiserror::Error, Debug, Clone, Eq, PartialEq)]
+pub enum BuildError {
+    #[error("Invalid command name {0:?}")]
+    InvalidCommand(String),
+
+    #[error("Invalid tag key {0:?}")]
+    InvalidTagKey(String),
+
+    #[error("Value for tag {0:?} contains a NUL byte")]
+    InvalidTagValue(String),
+
+    #[error("Parameter {index} contains forbidden byte {byte:#04x}")]
+    ForbiddenByte { index: usize, byte: u8 },
+
+    /// Empty parameters, and ones that start with ':' or contain a space, have to be sent as the
+    /// trailing parameter.
+    #[error("Parameter {index} can only be sent as the last parameter")]
+    TrailingNotLast { index: usize },
+
+    #[error("Message has {0} parameters, but at most {} are allowed", MAX_PARAMS)]
+    TooManyParams(usize),
+
+    #[error("Message has no parameter {0}")]
+    NoSuchParam(usize),
+}
+
+type Result<T> = std::result::Result<T, BuildError>;
+
+fn is_valid_tag_key(key: &str) -> bool {
+    let key = key.strip_prefix('+').unwrap_or(key);
+    let (vendor, name) = match key.rfind('/') {
+        Some(p) => (Some(&key[..p]), &key[p + 1..]),
+        None => (None, key),
+    };
+
+    let vendor_ok = match vendor {
+        None => true,
+        Some(vendor) => {
+            !vendor.is_empty()
+                && vendor
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'-')
+        }
+    };
+
+    vendor_ok && !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+}
+
+pub(crate) fn check_command(command: &Command) -> Result<()> {
+    match command {
+        Command::Name(name) => {
+            if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphabetic()) {
+                return Err(BuildError::InvalidCommand(name.clone()));
+            }
+        }
+        // Command numbers are checked when they are created.
+        Command::Num(_) => {}
+    }
+    Ok(())
+}
+
+pub(crate) fn check_tags<'a>(tags: impl IntoIterator<Item = (&'a str, &'a str)>) -> Result<()> {
+    for (key, value) in tags {
+        if !is_valid_tag_key(key) {
+            return Err(BuildError::InvalidTagKey(key.to_string()));
+        }
+
+        // Everything else in a value can be escaped.
+        if value.contains('\0') {
+            return Err(BuildError::InvalidTagValue(key.to_string()));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn check_params<S: AsRef<[u8]>>(params: &[S]) -> Result<()> {
+    if params.len() > MAX_PARAMS {
+        return Err(BuildError::TooManyParams(params.len()));
+    }
+
+    for (index, param) in params.iter().enumerate() {
+        check_param(index, params.len(), param.as_ref())?;
+    }
+    Ok(())
+}
+
+/// Checks parameter `index` of a message with `count` parameters.
+pub(crate) fn check_param(index: usize, count: usize, param: &[u8]) -> Result<()> {
+    if let Some(&byte) = param.iter().find(|b| matches!(b, b'\0' | b'\r' | b'\n')) {
+        return Err(BuildError::ForbiddenByte { index, byte });
+    }
+
+    let is_last = index + 1 == count;
+    let needs_trailing = param.is_empty() || param[0] == b':' || param.contains(&b' ');
+    if needs_trailing && !is_last {
+        return Err(BuildError::TrailingNotLast { index });
+    }
+    Ok(())
+}
+
+/// Builds a `Message`, checking that it is well-formed.
+///
+/// ```
+/// # use minibot_irc_raw::MessageBuilder;
+/// let msg = MessageBuilder::named("PRIVMSG")
+///     .tag("+reply-parent-msg-id", "abc")
+///     .param("#channel")
+///     .param("Hello, world!")
+///     .build()
+///     .unwrap();
+///
+/// assert!(MessageBuilder::named("PRIVMSG")
+///     .param("#channel")
+///     .param("hi\r\nPRIVMSG #other :hi")
+///     .build()
+///     .is_err());
+/// ```
+pub struct MessageBuilder {
+    tags: Vec<(String, String)>,
+    command: Command,
+    params: Vec<ByteString>,
+}
+
+impl MessageBuilder {
+    pub fn new(command: Command) -> Self {
+        MessageBuilder {
+            tags: Vec::new(),
+            command,
+            params: Vec::new(),
+        }
+    }
+
+    /// Starts a message with a named command. Unlike `Command::from_name`, an invalid name is
+    /// reported by `build()` instead of panicking.
+    pub fn named(name: &str) -> Self {
+        MessageBuilder::new(Command::Name(name.to_string()))
+    }
+
+    pub fn tag(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.tags.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn param(mut self, param: impl AsRef<[u8]>) -> Self {
+        self.params.push(ByteString::from_slice(param.as_ref()));
+        self
+    }
+
+    pub fn params<T: IntoIterator<Item = S>, S: AsRef<[u8]>>(mut self, params: T) -> Self {
+        self.params.extend(
+            params
+                .into_iter()
+                .map(|p| ByteString::from_slice(p.as_ref())),
+        );
+        self
+    }
+
+    pub fn build(self) -> Result<Message> {
+        let MessageBuilder {
+            tags,
+            command,
+            params,
+        } = self;
+
+        check_command(&command)?;
+        check_tags(tags.iter().map(|(k, v)| (k.as_str(), v.as_str())))?;
+        check_params(&params)?;
+
+        let tags: Tags = tags.into_iter().map(|(k, v)| Tag::new(k, v)).collect();
+        Ok(Message::from_parts(tags, None, command, params))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rejects_injected_lines() {
+        let result = MessageBuilder::named("PRIVMSG")
+            .param("#chan")
+            .param("hi\r\nPRIVMSG #other :gotcha")
+            .build();
+        assert_eq!(
+            result.unwrap_err(),
+            BuildError::ForbiddenByte {
+                index: 1,
+                byte: b'\r'
+            }
+        );
+    }
+
+    #[test]
+    fn only_last_param_may_be_trailing() {
+        assert!(MessageBuilder::named("PRIVMSG")
+            .params(["#chan", "two words"])
+            .build()
+            .is_ok());
+        assert_eq!(
+            MessageBuilder::named("PRIVMSG")
+                .params(["two words", "#chan"])
+                .build()
+                .unwrap_err(),
+            BuildError::TrailingNotLast { index: 0 }
+        );
+        assert_eq!(
+            MessageBuilder::named("MODE")
+                .params([":x", "y"])
+                .build()
+                .unwrap_err(),
+            BuildError::TrailingNotLast { index: 0 }
+        );
+    }
+
+    #[test]
+    fn set_param_checks_like_build() {
+        let mut msg = MessageBuilder::named("PRIVMSG")
+            .params(["#chan", "hi"])
+            .build()
+            .unwrap();
+        assert!(msg.set_param(1, "two words").is_ok());
+        assert_eq!(
+            msg.set_param(0, "two words"),
+            Err(BuildError::TrailingNotLast { index: 0 })
+        );
+        assert_eq!(
+            msg.set_param(1, "hi\r\nQUIT"),
+            Err(BuildError::ForbiddenByte {
+                index: 1,
+                byte: b'\r'
+            })
+        );
+        assert_eq!(msg.set_param(2, "x"), Err(BuildError::NoSuchParam(2)));
+        assert_eq!(msg.to_wire().as_ref(), b"PRIVMSG #chan :two words");
+    }
+
+    #[test]
+    fn checks_commands_and_tags() {
+        assert!(matches!(
+            MessageBuilder::named("PRIV MSG").build(),
+            Err(BuildError::InvalidCommand(_))
+        ));
+        assert!(MessageBuilder::named("PRIVMSG")
+            .tag("+draft/reply", "x")
+            .tag("twitch.tv/foo", "a b;c")
+            .build()
+            .is_ok());
+        assert!(matches!(
+            MessageBuilder::named("PRIVMSG").tag("bad key", "").build(),
+            Err(BuildError::InvalidTagKey(_))
+        ));
+        assert!(matches!(
+            MessageBuilder::named("PRIVMSG").tag("id", "a\0b").build(),
+            Err(BuildError::InvalidTagValue(_))
+        ));
+    }
+}
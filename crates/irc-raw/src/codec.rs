@@ -1,12 +1,127 @@
+use crate::message_ref::MessageRef;
 use crate::read_bytes::ReadBytes;
 use crate::write_bytes::WriteBytes;
 use crate::Message;
 use bytes::buf::Buf;
+use futures_codec::{BytesMut, Decoder};
+use minibot_byte_string::{ByteStr, ByteString};
 
 const MSG_TERM: &[u8] = b"\r\n";
 
+/// A single line read from the connection, without its CRLF terminator.
+pub struct IrcFrame(BytesMut);
+
+impl IrcFrame {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0[..]
+    }
+
+    /// Parses the frame into a message that borrows from the frame's buffer.
+    pub fn message(&self) -> Result<MessageRef<'_>, crate::messages::Error> {
+        MessageRef::parse(&self.0[..])
+    }
+}
+
+impl std::fmt::Debug for IrcFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_tuple("IrcFrame")
+            .field(&ByteStr::from(&self.0[..]))
+            .finish()
+    }
+}
+
+/// The longest line accepted by default, including the CRLF terminator. This is the IRCv3 limit of
+/// 8191 bytes for the tags section plus 512 bytes for the rest of the message.
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 8191 + 512;
+
+/// How the codec treats lines that can't be parsed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DecodeMode {
+    /// Parse failures are errors, and the stream ends after the first one.
+    Strict,
+    /// Parse failures produce an `Error::Malformed` item, and the stream continues with the next
+    /// line.
+    Lenient,
+}
+
+/// Splits a stream into lines without parsing them. Used with `IrcFrame::message()` to look at
+/// messages without copying them.
 #[derive(Clone, Debug)]
-pub struct IrcCodec;
+pub struct IrcFrameCodec {
+    max_line_length: usize,
+    // Set while skipping the remainder of a line that went over the limit.
+    discarding: bool,
+}
+
+impl IrcFrameCodec {
+    pub fn new() -> Self {
+        IrcFrameCodec {
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            discarding: false,
+        }
+    }
+
+    pub fn with_max_line_length(mut self, max_line_length: usize) -> Self {
+        assert!(max_line_length > MSG_TERM.len());
+        self.max_line_length = max_line_length;
+        self
+    }
+
+    pub fn max_line_length(&self) -> usize {
+        self.max_line_length
+    }
+
+    /// Drops everything in the buffer except a possible partial terminator at the end.
+    fn discard_partial(data: &mut BytesMut) {
+        let keep = if data.ends_with(&MSG_TERM[..1]) { 1 } else { 0 };
+        let drop_len = data.len() - keep;
+        data.advance(drop_len);
+    }
+}
+
+impl Default for IrcFrameCodec {
+    fn default() -> Self {
+        IrcFrameCodec::new()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct IrcCodec {
+    frames: IrcFrameCodec,
+    mode: DecodeMode,
+}
+
+impl IrcCodec {
+    pub fn new(mode: DecodeMode) -> Self {
+        IrcCodec {
+            frames: IrcFrameCodec::new(),
+            mode,
+        }
+    }
+
+    pub fn strict() -> Self {
+        IrcCodec::new(DecodeMode::Strict)
+    }
+
+    pub fn lenient() -> Self {
+        IrcCodec::new(DecodeMode::Lenient)
+    }
+
+    pub fn with_max_line_length(mut self, max_line_length: usize) -> Self {
+        self.frames = self.frames.with_max_line_length(max_line_length);
+        self
+    }
+
+    pub fn mode(&self) -> DecodeMode {
+        self.mode
+    }
+}
+
+impl Default for IrcCodec {
+    fn default() -> Self {
+        IrcCodec::strict()
+    }
+}
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -15,6 +130,27 @@ pub enum Error {
 
     #[error(transparent)]
     Message(#[from] crate::messages::Error),
+
+    #[error("Malformed message {raw:?}: {error}")]
+    Malformed {
+        raw: ByteString,
+        #[source]
+        error: crate::messages::Error,
+    },
+
+    #[error("Line is longer than the limit of {limit} bytes")]
+    LineTooLong { limit: usize },
+
+    #[error("Refusing to send invalid message: {0}")]
+    InvalidMessage(#[from] crate::builder::BuildError),
+}
+
+impl Error {
+    /// Returns true if the stream can continue to be read after this error. This is only the case
+    /// for single bad lines from a lenient codec, or from an `IrcFrameCodec`.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, Error::Malformed { .. } | Error::LineTooLong { .. })
+    }
 }
 
 impl From<std::convert::Infallible> for Error {
@@ -23,26 +159,47 @@ impl From<std::convert::Infallible> for Error {
     }
 }
 
-impl futures_codec::Decoder for IrcCodec {
-    type Item = Message;
+impl Decoder for IrcFrameCodec {
+    type Item = IrcFrame;
     type Error = Error;
 
-    fn decode(
-        &mut self,
-        data: &mut futures_codec::BytesMut,
-    ) -> Result<Option<Self::Item>, Self::Error> {
+    fn decode(&mut self, data: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         // Try to find a terminator for the next message.
         loop {
             match data.windows(MSG_TERM.len()).position(|w| w == MSG_TERM) {
-                None => break Ok(None),
+                None => {
+                    if self.discarding {
+                        IrcFrameCodec::discard_partial(data);
+                    } else if data.len() >= self.max_line_length {
+                        // Even if the next byte ends the line, it would be too long.
+                        IrcFrameCodec::discard_partial(data);
+                        self.discarding = true;
+                        break Err(Error::LineTooLong {
+                            limit: self.max_line_length,
+                        });
+                    }
+                    break Ok(None);
+                }
                 Some(pos) => {
                     // Read the message contents up to here
                     let message_contents = data.split_to(pos);
                     data.advance(MSG_TERM.len());
 
+                    if self.discarding {
+                        // This was the tail end of a line that was already reported.
+                        self.discarding = false;
+                        continue;
+                    }
+
+                    if pos + MSG_TERM.len() > self.max_line_length {
+                        break Err(Error::LineTooLong {
+                            limit: self.max_line_length,
+                        });
+                    }
+
                     // Empty messages can just be skipped.
                     if pos > 0 {
-                        break Ok(Some(Message::read_bytes(&message_contents)?));
+                        break Ok(Some(IrcFrame(message_contents)));
                     }
                 }
             }
@@ -50,6 +207,32 @@ impl futures_codec::Decoder for IrcCodec {
     }
 }
 
+impl Decoder for IrcCodec {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, data: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let frame = match (self.frames.decode(data), self.mode) {
+            (Ok(None), _) => return Ok(None),
+            (Ok(Some(frame)), _) => frame,
+            // In strict mode, an overlong line ends the stream like any other bad line.
+            (Err(e @ Error::LineTooLong { .. }), DecodeMode::Strict) => {
+                return Err(crate::messages::Error::Text(e.to_string()).into())
+            }
+            (Err(e), _) => return Err(e),
+        };
+
+        match (Message::read_bytes(frame.as_bytes()), self.mode) {
+            (Ok(msg), _) => Ok(Some(msg)),
+            (Err(e), DecodeMode::Strict) => Err(e.into()),
+            (Err(error), DecodeMode::Lenient) => Err(Error::Malformed {
+                raw: ByteString::from_slice(frame.as_bytes()),
+                error,
+            }),
+        }
+    }
+}
+
 impl futures_codec::Encoder for IrcCodec {
     type Item = crate::Message;
     type Error = Error;
@@ -59,6 +242,7 @@ impl futures_codec::Encoder for IrcCodec {
         msg: crate::Message,
         out: &mut futures_codec::BytesMut,
     ) -> Result<(), Self::Error> {
+        msg.validate()?;
         let mut msg_bytes: Vec<u8> = Vec::new();
         msg.write_bytes(&mut msg_bytes)?;
         out.reserve(msg_bytes.len() + MSG_TERM.len());
@@ -67,3 +251,81 @@ impl futures_codec::Encoder for IrcCodec {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode_all(codec: &mut IrcCodec, data: &[u8]) -> Vec<Result<Message, Error>> {
+        let mut buf = BytesMut::from(data);
+        let mut results = Vec::new();
+        loop {
+            match codec.decode(&mut buf) {
+                Ok(None) => break,
+                Ok(Some(msg)) => results.push(Ok(msg)),
+                Err(e) => results.push(Err(e)),
+            }
+        }
+        results
+    }
+
+    #[test]
+    fn lenient_codec_skips_malformed_lines() {
+        let results = decode_all(
+            &mut IrcCodec::lenient(),
+            b"PING :a\r\n:bad! 12 x\r\nPING :b\r\n",
+        );
+
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_ok());
+        match &results[1] {
+            Err(e @ Error::Malformed { raw, .. }) => {
+                assert!(raw.eq_bytes(b":bad! 12 x"));
+                assert!(e.is_recoverable());
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(results[2].as_ref().unwrap().has_named_command("PING"));
+    }
+
+    #[test]
+    fn lenient_codec_skips_zero_numerics() {
+        let results = decode_all(&mut IrcCodec::lenient(), b"000 x\r\nPING :a\r\n");
+
+        assert_eq!(results.len(), 2);
+        match &results[0] {
+            Err(Error::Malformed { raw, .. }) => assert!(raw.eq_bytes(b"000 x")),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(results[1].as_ref().unwrap().has_named_command("PING"));
+    }
+
+    #[test]
+    fn strict_codec_reports_parse_errors() {
+        let results = decode_all(&mut IrcCodec::strict(), b"12 x\r\n");
+        assert!(matches!(results[..], [Err(Error::Message(_))]));
+
+        let mut codec = IrcCodec::strict().with_max_line_length(16);
+        let mut buf = BytesMut::from(&b"PRIVMSG #a :way too long\r\n"[..]);
+        let error = codec.decode(&mut buf).unwrap_err();
+        assert!(matches!(error, Error::Message(_)));
+        assert!(!error.is_recoverable());
+    }
+
+    #[test]
+    fn overlong_lines_are_discarded() {
+        let mut codec = IrcCodec::lenient().with_max_line_length(16);
+        let mut buf = BytesMut::from(&b"PRIVMSG #a :way too long"[..]);
+
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(Error::LineTooLong { limit: 16 })
+        ));
+        assert!(buf.is_empty());
+
+        buf.extend_from_slice(b" still going\r\nPING :ok\r\n");
+        let msg = codec.decode(&mut buf).unwrap().unwrap();
+        assert!(msg.has_named_command("PING"));
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+}
@@ -0,0 +1,110 @@
+//! CTCP framing inside PRIVMSG and NOTICE text.
+//!
+//! A CTCP message is the text `\x01COMMAND params\x01`. The most common one is `ACTION`, which is
+//! what `/me waves` is sent as. Some clients leave off the closing delimiter, so it is optional
+//! when parsing.
+
+use minibot_byte_string::{ByteStr, ByteString};
+
+pub const CTCP_DELIM: u8 = 0x01;
+
+pub const ACTION: &str = "ACTION";
+
+/// A CTCP payload, borrowed from the message text.
+#[derive(Copy, Clone, Debug)]
+pub struct CtcpRef<'a> {
+    command: &'a str,
+    params: &'a ByteStr,
+}
+
+impl<'a> CtcpRef<'a> {
+    /// Extracts the CTCP payload from message text. Returns `None` if the text is not CTCP.
+    pub fn parse(text: &'a [u8]) -> Option<Self> {
+        let inner = text.strip_prefix(&[CTCP_DELIM])?;
+        let inner = inner.strip_suffix(&[CTCP_DELIM]).unwrap_or(inner);
+        let (command, params): (&[u8], &[u8]) = match inner.iter().position(|b| b == &b' ') {
+            Some(p) => (&inner[..p], &inner[p + 1..]),
+            None => (inner, &[]),
+        };
+
+        if command.is_empty() || command.contains(&CTCP_DELIM) {
+            return None;
+        }
+
+        Some(CtcpRef {
+            command: std::str::from_utf8(command).ok()?,
+            params: ByteStr::from(params),
+        })
+    }
+
+    pub fn command(&self) -> &'a str {
+        self.command
+    }
+
+    pub fn params(&self) -> &'a ByteStr {
+        self.params
+    }
+
+    pub fn is_action(&self) -> bool {
+        self.command == ACTION
+    }
+}
+
+/// Frames a CTCP command and its parameters as message text. Delimiters and NULs are dropped from
+/// the parameters, since they would end the CTCP message early.
+pub fn encode(command: &str, params: &[u8]) -> ByteString {
+    let mut text = Vec::with_capacity(command.len() + params.len() + 3);
+    text.push(CTCP_DELIM);
+    text.extend_from_slice(command.as_bytes());
+    if !params.is_empty() {
+        text.push(b' ');
+        text.extend(params.iter().filter(|&&b| b != CTCP_DELIM && b != 0));
+    }
+    text.push(CTCP_DELIM);
+    ByteString::from(text)
+}
+
+/// Frames an action (`/me`) as message text.
+pub fn encode_action(text: &str) -> ByteString {
+    encode(ACTION, text.as_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::read_bytes::ReadBytes;
+    use crate::Message;
+
+    #[test]
+    fn parses_actions() {
+        let msg = Message::read_bytes(b":foo PRIVMSG #bar :\x01ACTION waves\x01").unwrap();
+        let ctcp = msg.ctcp().unwrap();
+        assert!(ctcp.is_action());
+        assert!(ctcp.params().eq_bytes(b"waves"));
+
+        // The closing delimiter is optional.
+        let ctcp = CtcpRef::parse(b"\x01VERSION").unwrap();
+        assert_eq!(ctcp.command(), "VERSION");
+        assert!(ctcp.params().eq_bytes(b""));
+    }
+
+    #[test]
+    fn ignores_plain_text() {
+        let msg = Message::read_bytes(b":foo PRIVMSG #bar :just text").unwrap();
+        assert!(msg.ctcp().is_none());
+        assert!(CtcpRef::parse(b"\x01\x01").is_none());
+    }
+
+    #[test]
+    fn encodes_actions() {
+        let text = encode_action("waves");
+        assert!(text.eq_bytes(b"\x01ACTION waves\x01"));
+        assert!(CtcpRef::parse(text.as_ref()).unwrap().is_action());
+    }
+
+    #[test]
+    fn strips_delimiters_from_params() {
+        let text = encode_action("waves\x01 and\0 leaves");
+        assert!(text.eq_bytes(b"\x01ACTION waves and leaves\x01"));
+    }
+}
@@ -0,0 +1,176 @@
+//! A catalog of the commands and numeric replies that the bot deals with.
+//!
+//! `Command` can hold any command, which is what the wire format needs. `KnownCommand` names the
+//! ones we care about so that code can match on `KnownCommand::RplEndOfMotd` instead of `376`.
+//! Conversion in both directions is lossless: a command that is not in the catalog just has no
+//! `KnownCommand`, and stays usable as a plain `Command`.
+
+use crate::messages::{Command, CommandNumber};
+
+macro_rules! known_commands {
+    (
+        names { $($name_variant:ident => $name:literal,)* }
+        numerics { $($num_variant:ident => $num:literal,)* }
+    ) => {
+        #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+        pub enum KnownCommand {
+            $($name_variant,)*
+            $($num_variant,)*
+        }
+
+        impl KnownCommand {
+            pub const ALL: &'static [KnownCommand] = &[
+                $(KnownCommand::$name_variant,)*
+                $(KnownCommand::$num_variant,)*
+            ];
+
+            /// Looks up a command by its wire name, e.g. `"PRIVMSG"` or `"376"`. Names are
+            /// matched exactly, so that converting back gives the same name.
+            pub fn from_wire(command: &str) -> Option<Self> {
+                match command {
+                    $($name => Some(KnownCommand::$name_variant),)*
+                    $(stringify!($num) => Some(KnownCommand::$num_variant),)*
+                    _ => None,
+                }
+            }
+
+            /// The name of a named command, or `None` for numeric replies.
+            pub fn name(self) -> Option<&'static str> {
+                match self {
+                    $(KnownCommand::$name_variant => Some($name),)*
+                    _ => None,
+                }
+            }
+
+            /// The number of a numeric reply, or `None` for named commands.
+            pub fn number(self) -> Option<u16> {
+                match self {
+                    $(KnownCommand::$num_variant => Some(
+                        // Strip the leading zeros the catalog uses for readability.
+                        stringify!($num).parse().unwrap()
+                    ),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+known_commands! {
+    names {
+        // RFC 1459/2812 and IRCv3
+        Authenticate => "AUTHENTICATE",
+        Cap => "CAP",
+        Error => "ERROR",
+        Join => "JOIN",
+        Mode => "MODE",
+        Names => "NAMES",
+        Nick => "NICK",
+        Notice => "NOTICE",
+        Part => "PART",
+        Pass => "PASS",
+        Ping => "PING",
+        Pong => "PONG",
+        Privmsg => "PRIVMSG",
+        Quit => "QUIT",
+        User => "USER",
+
+        // Twitch
+        ClearChat => "CLEARCHAT",
+        ClearMsg => "CLEARMSG",
+        GlobalUserState => "GLOBALUSERSTATE",
+        HostTarget => "HOSTTARGET",
+        Reconnect => "RECONNECT",
+        RoomState => "ROOMSTATE",
+        UserNotice => "USERNOTICE",
+        UserState => "USERSTATE",
+        Whisper => "WHISPER",
+    }
+    numerics {
+        RplWelcome => 001,
+        RplYourHost => 002,
+        RplCreated => 003,
+        RplMyInfo => 004,
+        RplISupport => 005,
+        RplNamReply => 353,
+        RplEndOfNames => 366,
+        RplMotd => 372,
+        RplMotdStart => 375,
+        RplEndOfMotd => 376,
+        ErrUnknownCommand => 421,
+        ErrNoMotd => 422,
+        ErrErroneousNickname => 432,
+        ErrNicknameInUse => 433,
+        ErrNickCollision => 436,
+        ErrNotRegistered => 451,
+        ErrPasswdMismatch => 464,
+        RplLoggedIn => 900,
+        RplSaslSuccess => 903,
+        ErrSaslFail => 904,
+        ErrSaslTooLong => 905,
+        ErrSaslAborted => 906,
+        ErrSaslAlready => 907,
+    }
+}
+
+impl KnownCommand {
+    pub fn from_command(command: &Command) -> Option<Self> {
+        match command {
+            Command::Name(name) => match KnownCommand::from_wire(name) {
+                // Names that look like numbers are not valid named commands.
+                Some(known) if known.name().is_some() => Some(known),
+                _ => None,
+            },
+            Command::Num(num) => KnownCommand::ALL
+                .iter()
+                .copied()
+                .find(|known| known.number() == Some(num.number())),
+        }
+    }
+
+    pub fn to_command(self) -> Command {
+        match (self.name(), self.number()) {
+            (Some(name), _) => Command::Name(name.to_string()),
+            (None, Some(num)) => Command::Num(CommandNumber::new(num)),
+            (None, None) => unreachable!("Every known command has a name or a number"),
+        }
+    }
+}
+
+impl From<KnownCommand> for Command {
+    fn from(known: KnownCommand) -> Command {
+        known.to_command()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_command() {
+        for &known in KnownCommand::ALL {
+            let command = Command::from(known);
+            assert_eq!(KnownCommand::from_command(&command), Some(known));
+        }
+    }
+
+    #[test]
+    fn unknown_commands_are_preserved() {
+        let command = Command::from_name("FOO");
+        assert_eq!(KnownCommand::from_command(&command), None);
+        assert_eq!(
+            KnownCommand::from_command(&Command::from_numeric(999)),
+            None
+        );
+        assert_eq!(
+            KnownCommand::from_command(&Command::from_numeric(376)),
+            Some(KnownCommand::RplEndOfMotd)
+        );
+        assert_eq!(
+            KnownCommand::from_wire("005"),
+            Some(KnownCommand::RplISupport)
+        );
+        assert_eq!(KnownCommand::from_wire("5"), None);
+    }
+}
@@ -3,17 +3,41 @@
 //! involve itself with capability negotiation or reply-response pairs, and should be handled at
 //! a higher layer.
 
+macro_rules! ensure {
+    ($e:expr, $($fmt:expr),+) => {
+        if !$e {
+            return Err(Error::Text(std::format!($($fmt),*).into()));
+        }
+    };
+}
+
+macro_rules! bail {
+    ($($fmt:expr),+) => {
+        return Err(Error::Text(std::format!($($fmt),*).into()))
+    }
+}
+
+mod builder;
 mod codec;
+pub mod ctcp;
+mod known_command;
+mod message_ref;
 mod messages;
 mod read_bytes;
+mod tags;
+pub mod twitch_tags;
 mod write_bytes;
 
+pub use builder::{BuildError, MessageBuilder, MAX_PARAMS};
 pub use codec::Error;
-pub use codec::IrcCodec;
+pub use codec::{DecodeMode, IrcCodec, IrcFrame, IrcFrameCodec, DEFAULT_MAX_LINE_LENGTH};
 pub use futures::prelude::*;
-pub use messages::{Command, CommandNumber, Message};
+pub use known_command::KnownCommand;
+pub use message_ref::{MessageRef, SourceRef, TagValueRef, TagsRef, TagsRefIter};
+pub use messages::{Command, CommandNumber, Message, Source};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+pub use tags::{Tag, Tags};
 
 use futures_codec::{FramedRead, FramedWrite};
 
@@ -26,14 +50,44 @@ pub fn from_channel<
     (IrcStream::new(read_half), IrcSink::new(write_half))
 }
 
-pub struct IrcStream(Box<dyn Stream<Item = Result<Message, Error>> + Unpin + Send + 'static>);
+pub struct IrcStream {
+    inner: Box<dyn Stream<Item = Result<Message, Error>> + Unpin + Send + 'static>,
+    mode: DecodeMode,
+    ended: bool,
+}
 
 impl IrcStream {
     pub fn new<T>(read: T) -> Self
     where
         T: AsyncRead + Unpin + Send + 'static,
     {
-        IrcStream(Box::new(FramedRead::new(read, IrcCodec)))
+        IrcStream::with_codec(read, IrcCodec::default())
+    }
+
+    /// Creates a stream with a specific codec configuration. In strict mode the stream ends after
+    /// the first error. In lenient mode, it only ends after errors that are not recoverable.
+    pub fn with_codec<T>(read: T, codec: IrcCodec) -> Self
+    where
+        T: AsyncRead + Unpin + Send + 'static,
+    {
+        IrcStream {
+            mode: codec.mode(),
+            inner: Box::new(FramedRead::new(read, codec)),
+            ended: false,
+        }
+    }
+
+    /// Wraps an existing stream of messages, such as one that records or replays another stream.
+    /// Like a lenient stream, it only ends after errors that are not recoverable.
+    pub fn from_stream<S>(stream: S) -> Self
+    where
+        S: Stream<Item = Result<Message, Error>> + Unpin + Send + 'static,
+    {
+        IrcStream {
+            mode: DecodeMode::Lenient,
+            inner: Box::new(stream),
+            ended: false,
+        }
     }
 }
 
@@ -44,6 +98,41 @@ impl Stream for IrcStream {
         mut self: Pin<&mut Self>,
         cx: &mut Context,
     ) -> Poll<Option<Result<Message, crate::codec::Error>>> {
+        if self.ended {
+            return Poll::Ready(None);
+        }
+
+        let result = futures::ready!(Pin::new(&mut self.inner).poll_next(cx));
+        match &result {
+            None => self.ended = true,
+            Some(Err(e)) if self.mode == DecodeMode::Strict || !e.is_recoverable() => {
+                self.ended = true
+            }
+            _ => {}
+        }
+        Poll::Ready(result)
+    }
+}
+
+/// A stream of unparsed lines. Each frame can be parsed in place with `IrcFrame::message()`.
+pub struct IrcFrameStream(Box<dyn Stream<Item = Result<IrcFrame, Error>> + Unpin + Send + 'static>);
+
+impl IrcFrameStream {
+    pub fn new<T>(read: T) -> Self
+    where
+        T: AsyncRead + Unpin + Send + 'static,
+    {
+        IrcFrameStream(Box::new(FramedRead::new(read, IrcFrameCodec::new())))
+    }
+}
+
+impl Stream for IrcFrameStream {
+    type Item = Result<IrcFrame, crate::codec::Error>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<Option<Result<IrcFrame, crate::codec::Error>>> {
         Pin::new(&mut self.0).poll_next(cx)
     }
 }
@@ -55,7 +144,15 @@ impl IrcSink {
     where
         T: AsyncWrite + Unpin + Send + 'static,
     {
-        IrcSink(Box::new(FramedWrite::new(write, IrcCodec)))
+        IrcSink(Box::new(FramedWrite::new(write, IrcCodec::default())))
+    }
+
+    /// Wraps an existing sink of messages, such as one that records messages on their way out.
+    pub fn from_sink<S>(sink: S) -> Self
+    where
+        S: Sink<Message, Error = Error> + Unpin + Send + 'static,
+    {
+        IrcSink(Box::new(sink))
     }
 }
 
@@ -0,0 +1,392 @@
+//! Borrowed views of IRC messages.
+//!
+//! A `MessageRef` points directly into the buffer it was parsed from. Nothing is copied during
+//! parsing, and tag values are only unescaped when they are asked for. This makes it cheap to
+//! inspect and discard messages, and only pay for an owned `Message` when one is kept.
+
+use crate::known_command::KnownCommand;
+use crate::messages::{
+    check_command, parse_command, unescape_tag_value, Command, Error, Message, Result, Source,
+};
+use crate::tags::{Tag, Tags};
+use minibot_byte_string::{ByteStr, ByteString};
+use std::borrow::Cow;
+
+fn split_tag(term: &[u8]) -> (&[u8], Option<&[u8]>) {
+    match term.iter().position(|c| c == &b'=') {
+        None => (term, None),
+        Some(p) => (&term[..p], Some(&term[p + 1..])),
+    }
+}
+
+/// The still-escaped value of a single tag.
+#[derive(Copy, Clone, Debug)]
+pub struct TagValueRef<'a>(Option<&'a ByteStr>);
+
+impl<'a> TagValueRef<'a> {
+    /// Returns the value as it appeared on the wire, without unescaping. A tag without a value
+    /// has an empty value.
+    pub fn raw(&self) -> &'a ByteStr {
+        self.0.unwrap_or_else(|| ByteStr::from(&[]))
+    }
+
+    /// True if the tag had a `=` on the wire, even if the value after it was empty.
+    pub fn has_value(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Unescapes the value. If the value has no escape sequences, this does not allocate.
+    pub fn unescape(&self) -> Result<Cow<'a, str>> {
+        let bytes = self.raw().as_ref();
+        if bytes.contains(&b'\\') {
+            Ok(Cow::Owned(unescape_tag_value(bytes)?))
+        } else {
+            Ok(Cow::Borrowed(std::str::from_utf8(bytes)?))
+        }
+    }
+}
+
+/// The tags section of a message, as a borrowed slice.
+#[derive(Copy, Clone)]
+pub struct TagsRef<'a>(&'a [u8]);
+
+impl<'a> TagsRef<'a> {
+    fn parse(tag_word: &'a [u8]) -> Result<Self> {
+        for term in tag_word.split(is_tag_separator) {
+            std::str::from_utf8(split_tag(term).0)?;
+        }
+        Ok(TagsRef(tag_word))
+    }
+
+    fn empty() -> Self {
+        TagsRef(&[])
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> TagsRefIter<'a> {
+        TagsRefIter(if self.0.is_empty() {
+            None
+        } else {
+            Some(self.0.split(is_tag_separator as fn(&u8) -> bool))
+        })
+    }
+
+    /// Returns the value for a key. As with `Tags::get`, the last value wins if the key appears
+    /// more than once.
+    pub fn get(&self, key: &str) -> Option<TagValueRef<'a>> {
+        self.iter()
+            .filter(|(k, _)| *k == key)
+            .last()
+            .map(|(_, v)| v)
+    }
+}
+
+impl std::fmt::Debug for TagsRef<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+fn is_tag_separator(c: &u8) -> bool {
+    c == &b';'
+}
+
+type TagSplit<'a> = std::slice::Split<'a, u8, fn(&u8) -> bool>;
+
+pub struct TagsRefIter<'a>(Option<TagSplit<'a>>);
+
+impl<'a> Iterator for TagsRefIter<'a> {
+    type Item = (&'a str, TagValueRef<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = split_tag(self.0.as_mut()?.next()?);
+        // Keys were checked for valid UTF-8 when the tags were parsed.
+        let key = std::str::from_utf8(key).expect("Tag keys are validated on parse");
+        Some((key, TagValueRef(value.map(ByteStr::from))))
+    }
+}
+
+/// The source (prefix) of a message, borrowed from the message buffer.
+#[derive(Copy, Clone)]
+pub struct SourceRef<'a> {
+    nick: Option<&'a str>,
+    user: Option<&'a str>,
+    host: Option<&'a ByteStr>,
+}
+
+impl<'a> SourceRef<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<Self> {
+        let bang_index = buf.iter().position(|c| c == &b'!');
+        let at_index = buf.iter().position(|c| c == &b'@');
+        let (nick, user, host): (&[u8], &[u8], &[u8]) = match (bang_index, at_index) {
+            (None, None) => (&[], &[], buf),
+            (Some(bang_index), None) => (&buf[..bang_index], &buf[bang_index + 1..], &[]),
+            (None, Some(at_index)) => (&buf[..at_index], &[], &buf[at_index + 1..]),
+            (Some(bang_index), Some(at_index)) => {
+                ensure!(
+                    bang_index < at_index,
+                    "! must come before @ in source. Source: {:?}",
+                    buf
+                );
+                (
+                    &buf[..bang_index],
+                    &buf[bang_index + 1..at_index],
+                    &buf[at_index + 1..],
+                )
+            }
+        };
+
+        fn none_if_empty<T: AsRef<[u8]> + ?Sized>(opt_text: &T) -> Option<&T> {
+            if opt_text.as_ref().is_empty() {
+                None
+            } else {
+                Some(opt_text)
+            }
+        }
+
+        Ok(SourceRef {
+            nick: none_if_empty(std::str::from_utf8(nick)?),
+            user: none_if_empty(std::str::from_utf8(user)?),
+            host: none_if_empty(ByteStr::from(host)),
+        })
+    }
+
+    pub fn nick(&self) -> Option<&'a str> {
+        self.nick
+    }
+
+    pub fn user(&self) -> Option<&'a str> {
+        self.user
+    }
+
+    pub fn host(&self) -> Option<&'a ByteStr> {
+        self.host
+    }
+
+    pub fn to_owned(&self) -> Source {
+        Source::from_parts(
+            self.nick.map(str::to_string),
+            self.user.map(str::to_string),
+            self.host.map(ByteStr::to_byte_string),
+        )
+    }
+}
+
+impl std::fmt::Debug for SourceRef<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.to_owned().fmt(f)
+    }
+}
+
+/// A parsed IRC message that borrows from the buffer it was read from.
+#[derive(Clone)]
+pub struct MessageRef<'a> {
+    tags: TagsRef<'a>,
+    source: Option<SourceRef<'a>>,
+    command: &'a str,
+    params: Vec<&'a ByteStr>,
+}
+
+impl<'a> MessageRef<'a> {
+    /// Parses a single message. The buffer must not contain the trailing CRLF.
+    pub fn parse(buf: &'a [u8]) -> Result<Self> {
+        fn eat_space(text: &mut &[u8]) {
+            for (i, ch) in text.iter().copied().enumerate() {
+                if ch != b' ' {
+                    *text = &text[i..];
+                    return;
+                }
+            }
+            *text = &[];
+        }
+
+        fn until_space<'a>(text: &mut &'a [u8]) -> &'a [u8] {
+            for (i, ch) in text.iter().copied().enumerate() {
+                if ch == b' ' {
+                    let word_slice = &text[..i];
+                    *text = &text[i..];
+                    eat_space(text);
+                    return word_slice;
+                }
+            }
+
+            let word_slice = &text[..];
+            *text = &[];
+            word_slice
+        }
+
+        ensure!(!buf.is_empty(), "Message must not be empty.");
+
+        let mut remaining_text = buf;
+        let tags = if let Some((b'@', rest)) = remaining_text.split_first() {
+            remaining_text = rest;
+            let tags_word = until_space(&mut remaining_text);
+            ensure!(!remaining_text.is_empty(), "Did not find IRC command");
+            TagsRef::parse(tags_word)?
+        } else {
+            TagsRef::empty()
+        };
+
+        let source = if let Some((b':', rest)) = remaining_text.split_first() {
+            remaining_text = rest;
+            let source_word = until_space(&mut remaining_text);
+            ensure!(!remaining_text.is_empty(), "Did not find IRC command");
+            Some(SourceRef::parse(source_word)?)
+        } else {
+            None
+        };
+
+        let command_word = until_space(&mut remaining_text);
+        check_command(command_word)?;
+        // The command check ensures that the command is all ascii.
+        let command = std::str::from_utf8(command_word).unwrap();
+
+        let mut params = Vec::new();
+
+        while !remaining_text.is_empty() {
+            if let Some((b':', rest)) = remaining_text.split_first() {
+                params.push(ByteStr::from(rest));
+                remaining_text = &[];
+            } else {
+                let param_word = until_space(&mut remaining_text);
+                params.push(ByteStr::from(param_word));
+            }
+        }
+
+        Ok(MessageRef {
+            tags,
+            source,
+            command,
+            params,
+        })
+    }
+
+    pub fn tags(&self) -> TagsRef<'a> {
+        self.tags
+    }
+
+    pub fn source(&self) -> Option<SourceRef<'a>> {
+        self.source
+    }
+
+    /// Returns the command as it appeared on the wire, e.g. `"PRIVMSG"` or `"001"`.
+    pub fn command_str(&self) -> &'a str {
+        self.command
+    }
+
+    pub fn command(&self) -> Command {
+        parse_command(self.command.as_bytes()).expect("Command was validated on parse")
+    }
+
+    pub fn known_command(&self) -> Option<KnownCommand> {
+        KnownCommand::from_wire(self.command)
+    }
+
+    pub fn has_command(&self, command: KnownCommand) -> bool {
+        self.known_command() == Some(command)
+    }
+
+    pub fn has_named_command(&self, name: &str) -> bool {
+        !self.command.as_bytes()[0].is_ascii_digit() && self.command == name
+    }
+
+    pub fn has_num_command(&self, num: u16) -> bool {
+        self.command.as_bytes()[0].is_ascii_digit() && self.command.parse() == Ok(num)
+    }
+
+    pub fn params(&self) -> &[&'a ByteStr] {
+        &self.params[..]
+    }
+
+    /// Creates an owned copy of this message, unescaping all tag values.
+    pub fn to_owned(&self) -> Result<Message> {
+        let mut tags = Tags::new();
+        for (key, value) in self.tags.iter() {
+            let value = match value.0 {
+                Some(_) => Some(value.unescape()?.into_owned()),
+                None => None,
+            };
+            tags.push(Tag::from_wire(key.to_string(), value));
+        }
+
+        Ok(Message::from_parts(
+            tags,
+            self.source.as_ref().map(SourceRef::to_owned),
+            self.command(),
+            self.params
+                .iter()
+                .copied()
+                .map(ByteStr::to_byte_string)
+                .collect::<Vec<ByteString>>(),
+        ))
+    }
+}
+
+impl std::fmt::Debug for MessageRef<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut f = f.debug_struct("MessageRef");
+
+        f.field("command", &self.command);
+
+        if !self.tags.is_empty() {
+            f.field("tags", &self.tags);
+        }
+
+        if let Some(source) = &self.source {
+            f.field("source", source);
+        }
+
+        if !self.params.is_empty() {
+            f.field("params", &self.params);
+        }
+
+        f.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::write_bytes::WriteBytes;
+
+    #[test]
+    fn parses_without_copying() -> Result<()> {
+        let line = b"@id=abc;display-name=Foo :foo!foo@foo.tmi.twitch.tv PRIVMSG #bar :hello there";
+        let msg = MessageRef::parse(line)?;
+
+        assert!(msg.has_named_command("PRIVMSG"));
+        assert_eq!(msg.source().and_then(|s| s.nick()), Some("foo"));
+        assert_eq!(msg.params().len(), 2);
+        assert!(msg.params()[1].eq_bytes(b"hello there"));
+
+        let value = msg.tags().get("display-name").unwrap().unescape()?;
+        assert!(matches!(value, Cow::Borrowed("Foo")));
+        Ok(())
+    }
+
+    #[test]
+    fn unescapes_tag_values_lazily() -> Result<()> {
+        let msg = MessageRef::parse(b"@msg=a\\sb\\:c;bad=\\x 001 me")?;
+
+        assert!(msg.has_num_command(1));
+        assert!(msg.tags().get("msg").unwrap().raw().eq_bytes(b"a\\sb\\:c"));
+        assert_eq!(msg.tags().get("msg").unwrap().unescape()?, "a b;c");
+        assert!(msg.tags().get("bad").unwrap().unescape().is_err());
+        assert!(msg.tags().get("missing").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn to_owned_round_trips() -> Result<()> {
+        let line: &[u8] = b"@id=a\\sb :tmi.twitch.tv CAP * ACK :twitch.tv/tags";
+        let owned = MessageRef::parse(line)?.to_owned()?;
+
+        let mut written = Vec::new();
+        owned.write_bytes(&mut written).unwrap();
+        assert_eq!(written, line);
+        Ok(())
+    }
+}
@@ -1,24 +1,14 @@
+use super::builder::{self, BuildError};
+use super::ctcp::CtcpRef;
+use super::known_command::KnownCommand;
+use super::message_ref::{MessageRef, SourceRef};
 use super::read_bytes::ReadBytes;
+use super::tags::Tags;
 use super::write_bytes::{ByteSink, WriteBytes};
-use minibot_byte_string::ByteString;
+use minibot_byte_string::{ByteStr, ByteString};
 use std::borrow::Cow;
-use std::collections::HashMap;
 use std::fmt;
 
-macro_rules! ensure {
-    ($e:expr, $($fmt:expr),+) => {
-        if !$e {
-            return Err(Error::Text(std::format!($($fmt),*).into()));
-        }
-    };
-}
-
-macro_rules! bail {
-    ($($fmt:expr),+) => {
-        return Err(Error::Text(std::format!($($fmt),*).into()));
-    }
-}
-
 #[derive(thiserror::Error, Debug)]
 #[non_exhaustive]
 pub enum Error {
@@ -29,9 +19,9 @@ pub enum Error {
     Utf8Error(#[from] std::str::Utf8Error),
 }
 
-type Result<T> = std::result::Result<T, Error>;
+pub(crate) type Result<T> = std::result::Result<T, Error>;
 
-fn unescape_tag_value(val: &[u8]) -> Result<String> {
+pub(crate) fn unescape_tag_value(val: &[u8]) -> Result<String> {
     let value_chars = std::str::from_utf8(val)?;
     let mut result = String::new();
     let mut char_iter = value_chars.chars();
@@ -70,22 +60,6 @@ fn escape_tag_value<T: ByteSink>(val: &str, out: &mut T) -> std::result::Result<
     Ok(())
 }
 
-fn parse_tags(tag_word: &[u8]) -> Result<HashMap<String, String>> {
-    let mut result = HashMap::new();
-    for term in tag_word.split(|c| c == &b';') {
-        let (key_bytes, value_bytes): (&[u8], &[u8]) = match term.iter().position(|c| c == &b'=') {
-            None => (term, &[]),
-            Some(p) => (&term[..p], &term[p + 1..]),
-        };
-
-        result.insert(
-            std::str::from_utf8(key_bytes)?.to_string(),
-            unescape_tag_value(value_bytes)?,
-        );
-    }
-    Ok(result)
-}
-
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub struct CommandNumber(u16);
 
@@ -116,6 +90,7 @@ impl fmt::Debug for CommandNumber {
     }
 }
 
+#[derive(Clone, Eq, PartialEq)]
 pub enum Command {
     Name(String),
     Num(CommandNumber),
@@ -145,34 +120,47 @@ impl fmt::Debug for Command {
     }
 }
 
+/// Checks that the bytes form a valid command, without allocating.
+pub(crate) fn check_command(buf: &[u8]) -> Result<()> {
+    ensure!(!buf.is_empty(), "Command must not be empty");
+    if buf[0].is_ascii_digit() {
+        ensure!(
+            buf.len() == 3,
+            "Numeric command must be exactly 3 characters long. Got {:?}",
+            String::from_utf8_lossy(buf)
+        );
+        ensure!(
+            buf.iter().all(u8::is_ascii_digit),
+            "Numeric command must be all ascii numbers."
+        );
+        ensure!(buf != b"000", "Numeric command must not be 000.");
+    } else {
+        ensure!(
+            buf.iter().all(u8::is_ascii_alphabetic),
+            "Name command must be all ascii letters."
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn parse_command(buf: &[u8]) -> Result<Command> {
+    check_command(buf)?;
+    if buf[0].is_ascii_digit() {
+        let mut total = 0u16;
+        for &b in buf {
+            total = total * 10 + (b - b'0') as u16;
+        }
+
+        Ok(Command::Num(CommandNumber::new(total)))
+    } else {
+        Ok(Command::Name(String::from_utf8(buf.to_vec()).unwrap()))
+    }
+}
+
 impl ReadBytes for Command {
     type Err = Error;
     fn read_bytes(buf: &[u8]) -> Result<Self> {
-        ensure!(!buf.is_empty(), "Command must not be empty");
-        if buf[0].is_ascii_digit() {
-            ensure!(
-                buf.len() == 3,
-                "Numeric command must be exactly 3 characters long. Got {:?}",
-                String::from_utf8_lossy(buf)
-            );
-            ensure!(
-                buf.iter().all(u8::is_ascii_digit),
-                "Numeric command must be all ascii numbers."
-            );
-
-            let mut total = 0u16;
-            for &b in buf {
-                total = total * 10 + (b - b'0') as u16;
-            }
-
-            Ok(Command::Num(CommandNumber::new(total)))
-        } else {
-            ensure!(
-                buf.iter().all(u8::is_ascii_alphabetic),
-                "Name command must be all ascii letters."
-            );
-            Ok(Command::Name(String::from_utf8(buf.to_vec()).unwrap()))
-        }
+        parse_command(buf)
     }
 }
 
@@ -185,6 +173,7 @@ impl WriteBytes for Command {
     }
 }
 
+#[derive(Clone)]
 pub struct Source {
     nick: Option<String>,
     user: Option<String>,
@@ -210,42 +199,32 @@ impl std::fmt::Debug for Source {
     }
 }
 
-impl ReadBytes for Source {
-    type Err = Error;
-    fn read_bytes(buf: &[u8]) -> Result<Self> {
-        let bang_index = buf.iter().position(|c| c == &b'!');
-        let at_index = buf.iter().position(|c| c == &b'@');
-        let (nick, user, host): (&[u8], &[u8], &[u8]) = match (bang_index, at_index) {
-            (None, None) => (&[], &[], buf),
-            (Some(bang_index), None) => (&buf[..bang_index], &buf[bang_index + 1..], &[]),
-            (None, Some(at_index)) => (&buf[..at_index], &[], &buf[at_index + 1..]),
-            (Some(bang_index), Some(at_index)) => {
-                ensure!(
-                    bang_index < at_index,
-                    "! must come before @ in source. Source: {:?}",
-                    buf
-                );
-                (
-                    &buf[..bang_index],
-                    &buf[bang_index + 1..at_index],
-                    &buf[at_index + 1..],
-                )
-            }
-        };
+impl Source {
+    pub(crate) fn from_parts(
+        nick: Option<String>,
+        user: Option<String>,
+        host: Option<ByteString>,
+    ) -> Self {
+        Source { nick, user, host }
+    }
 
-        fn none_if_empty<T: AsRef<[u8]>>(opt_text: T) -> Option<T> {
-            if opt_text.as_ref().is_empty() {
-                None
-            } else {
-                Some(opt_text)
-            }
-        }
+    pub fn nick(&self) -> Option<&str> {
+        self.nick.as_deref()
+    }
 
-        let nick = none_if_empty(std::str::from_utf8(nick)?.to_string());
-        let user = none_if_empty(std::str::from_utf8(user)?.to_string());
-        let host = none_if_empty(host.iter().copied().collect());
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
 
-        Ok(Source { nick, user, host })
+    pub fn host(&self) -> Option<&ByteStr> {
+        self.host.as_deref()
+    }
+}
+
+impl ReadBytes for Source {
+    type Err = Error;
+    fn read_bytes(buf: &[u8]) -> Result<Self> {
+        Ok(SourceRef::parse(buf)?.to_owned())
     }
 }
 
@@ -275,14 +254,29 @@ impl WriteBytes for Source {
     }
 }
 
+#[derive(Clone)]
 pub struct Message {
-    tags: HashMap<String, String>,
+    tags: Tags,
     source: Option<Source>,
     command: Command,
     params: Vec<ByteString>,
 }
 
 impl Message {
+    pub(crate) fn from_parts(
+        tags: Tags,
+        source: Option<Source>,
+        command: Command,
+        params: Vec<ByteString>,
+    ) -> Self {
+        Message {
+            tags,
+            source,
+            command,
+            params,
+        }
+    }
+
     pub fn from_named_command_params<T: IntoIterator<Item = S>, S: AsRef<[u8]>>(
         cmd: &str,
         params: T,
@@ -303,7 +297,7 @@ impl Message {
             .map(|p| ByteString::from_slice(p.as_ref()))
             .collect();
         Message {
-            tags: HashMap::new(),
+            tags: Tags::new(),
             source: None,
             command: cmd,
             params,
@@ -312,13 +306,29 @@ impl Message {
 
     pub fn from_command(cmd: Command) -> Self {
         Message {
-            tags: HashMap::new(),
+            tags: Tags::new(),
             source: None,
             command: cmd,
             params: Vec::new(),
         }
     }
 
+    pub fn source(&self) -> Option<&Source> {
+        self.source.as_ref()
+    }
+
+    pub fn command(&self) -> &Command {
+        &self.command
+    }
+
+    pub fn known_command(&self) -> Option<KnownCommand> {
+        KnownCommand::from_command(&self.command)
+    }
+
+    pub fn has_command(&self, command: KnownCommand) -> bool {
+        self.known_command() == Some(command)
+    }
+
     pub fn has_named_command(&self, name: &str) -> bool {
         match &self.command {
             Command::Num(_) => false,
@@ -332,9 +342,69 @@ impl Message {
             Command::Name(_) => false,
         }
     }
+
+    /// Returns the unescaped value of a tag, if present.
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.get(key)
+    }
+
+    pub fn tags(&self) -> &Tags {
+        &self.tags
+    }
+
+    pub fn tags_mut(&mut self) -> &mut Tags {
+        &mut self.tags
+    }
+
     pub fn params(&self) -> &[ByteString] {
         &self.params[..]
     }
+
+    /// Replaces an existing parameter, checking it as `MessageBuilder` would. On error, the
+    /// message is left as it was.
+    pub fn set_param(
+        &mut self,
+        index: usize,
+        param: impl AsRef<[u8]>,
+    ) -> std::result::Result<(), BuildError> {
+        let param = param.as_ref();
+        if index >= self.params.len() {
+            return Err(BuildError::NoSuchParam(index));
+        }
+        builder::check_param(index, self.params.len(), param)?;
+        self.params[index] = ByteString::from_slice(param);
+        Ok(())
+    }
+
+    /// Returns the CTCP payload of a PRIVMSG or NOTICE, if it has one.
+    pub fn ctcp(&self) -> Option<CtcpRef<'_>> {
+        if !(self.has_command(KnownCommand::Privmsg) || self.has_command(KnownCommand::Notice)) {
+            return None;
+        }
+
+        match &self.params[..] {
+            [_, text] => CtcpRef::parse(text.as_ref()),
+            _ => None,
+        }
+    }
+
+    /// Checks that the message can be written to the wire without changing its meaning. See
+    /// `MessageBuilder` for the rules.
+    pub fn validate(&self) -> std::result::Result<(), BuildError> {
+        builder::check_command(&self.command)?;
+        builder::check_tags(self.tags.iter().map(|t| (t.key(), t.value())))?;
+        builder::check_params(&self.params)
+    }
+
+    /// The message as it is written to the wire, without the trailing CRLF.
+    pub fn to_wire(&self) -> ByteString {
+        let mut buf = Vec::new();
+        match self.write_bytes(&mut buf) {
+            Ok(()) => {}
+            Err(e) => match e {},
+        }
+        ByteString::from(buf)
+    }
 }
 
 impl std::fmt::Debug for Message {
@@ -363,79 +433,7 @@ impl std::fmt::Debug for Message {
 impl ReadBytes for Message {
     type Err = Error;
     fn read_bytes(buf: &[u8]) -> Result<Self> {
-        fn eat_space(text: &mut &[u8]) {
-            for (i, ch) in text.iter().copied().enumerate() {
-                if ch != b' ' {
-                    *text = &text[i..];
-                    return;
-                }
-            }
-            *text = &[];
-        }
-
-        fn until_space<'a>(text: &mut &'a [u8]) -> &'a [u8] {
-            for (i, ch) in text.iter().copied().enumerate() {
-                if ch == b' ' {
-                    let word_slice = &text[..i];
-                    *text = &text[i..];
-                    eat_space(text);
-                    return word_slice;
-                }
-            }
-
-            let word_slice = &text[..];
-            *text = &[];
-            word_slice
-        }
-
-        fn get_first_char(text: &[u8]) -> Option<u8> {
-            match text.iter().next() {
-                Some(ch) => Some(*ch),
-                None => None,
-            }
-        }
-
-        ensure!(!buf.is_empty(), "Message must not be empty.");
-        let mut remaining_text = buf;
-        let tags = if let Some((b'@', rest)) = remaining_text.split_first() {
-            remaining_text = rest;
-            let tags_word = until_space(&mut remaining_text);
-            ensure!(!remaining_text.is_empty(), "Did not find IRC command");
-            parse_tags(tags_word)?
-        } else {
-            HashMap::new()
-        };
-
-        let source = if let Some((b':', rest)) = remaining_text.split_first() {
-            remaining_text = rest;
-            let source_word = until_space(&mut remaining_text);
-            ensure!(!remaining_text.is_empty(), "Did not find IRC command");
-            Some(Source::read_bytes(source_word)?)
-        } else {
-            None
-        };
-
-        let command_word = until_space(&mut remaining_text);
-        let command = Command::read_bytes(command_word)?;
-
-        let mut params = Vec::new();
-
-        while !remaining_text.is_empty() {
-            if get_first_char(remaining_text) == Some(b':') {
-                params.push(ByteString::from_slice(&remaining_text[1..]));
-                remaining_text = &[];
-            } else {
-                let param_word = until_space(&mut remaining_text);
-                params.push(ByteString::from_slice(param_word));
-            }
-        }
-
-        Ok(Message {
-            tags,
-            source,
-            command,
-            params,
-        })
+        MessageRef::parse(buf)?.to_owned()
     }
 }
 
@@ -444,18 +442,18 @@ impl WriteBytes for Message {
         if !self.tags.is_empty() {
             out.write(b"@")?;
             let mut first_tag = true;
-            for (k, v) in self.tags.iter() {
+            for tag in self.tags.iter() {
                 if first_tag {
                     first_tag = false;
                 } else {
                     out.write(b";")?;
                 }
 
-                assert!(!k.is_empty());
-                out.write(k.as_bytes())?;
-                if !v.is_empty() {
+                assert!(!tag.key().is_empty());
+                out.write(tag.key().as_bytes())?;
+                if let Some(value) = tag.wire_value() {
                     out.write(b"=")?;
-                    escape_tag_value(&v, out)?;
+                    escape_tag_value(value, out)?;
                 }
             }
 
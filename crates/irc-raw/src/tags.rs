@@ -0,0 +1,235 @@
+//! Owned message tags.
+//!
+//! Tags are kept in the order they appeared on the wire, and duplicate keys are kept rather than
+//! overwritten. A message that is read and written back produces the same tag section, byte for
+//! byte, as long as its escapes were valid.
+
+use std::fmt;
+
+/// A single tag. The key is kept in its wire form, e.g. `+draft/reply`, and split into its parts
+/// on demand.
+#[derive(Clone, Eq, PartialEq)]
+pub struct Tag {
+    key: String,
+    // `None` if the tag had no `=` at all, which is distinct from an empty value on the wire.
+    value: Option<String>,
+}
+
+impl Tag {
+    /// Creates a tag. An empty value is written without an `=`.
+    pub fn new(key: impl Into<String>, value: impl Into<String>) -> Self {
+        let value = value.into();
+        Tag {
+            key: key.into(),
+            value: if value.is_empty() { None } else { Some(value) },
+        }
+    }
+
+    pub(crate) fn from_wire(key: String, value: Option<String>) -> Self {
+        Tag { key, value }
+    }
+
+    /// The full key, including any client-only marker and vendor.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    fn key_without_marker(&self) -> &str {
+        self.key.strip_prefix('+').unwrap_or(&self.key)
+    }
+
+    /// True for client-only tags, whose key starts with `+`.
+    pub fn is_client_only(&self) -> bool {
+        self.key.starts_with('+')
+    }
+
+    /// The vendor namespace of the key, e.g. `twitch.tv` for `twitch.tv/foo`.
+    pub fn vendor(&self) -> Option<&str> {
+        let key = self.key_without_marker();
+        key.rfind('/').map(|p| &key[..p])
+    }
+
+    /// The key without the client-only marker or the vendor.
+    pub fn name(&self) -> &str {
+        let key = self.key_without_marker();
+        match key.rfind('/') {
+            Some(p) => &key[p + 1..],
+            None => key,
+        }
+    }
+
+    /// The unescaped value. Tags without a value have an empty value.
+    pub fn value(&self) -> &str {
+        self.value.as_deref().unwrap_or("")
+    }
+
+    pub(crate) fn wire_value(&self) -> Option<&str> {
+        self.value.as_deref()
+    }
+}
+
+impl fmt::Debug for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}={:?}", self.key, value),
+            None => f.write_str(&self.key),
+        }
+    }
+}
+
+#[derive(Clone, Default, Eq, PartialEq)]
+pub struct Tags(Vec<Tag>);
+
+impl Tags {
+    pub fn new() -> Self {
+        Tags(Vec::new())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Iterates over the tags in wire order, including duplicates.
+    pub fn iter(&self) -> std::slice::Iter<'_, Tag> {
+        self.0.iter()
+    }
+
+    /// Returns the value for a key. If the key appears more than once, the last value wins. Use
+    /// `get_all()` to see every value.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .rev()
+            .find(|tag| tag.key == key)
+            .map(Tag::value)
+    }
+
+    /// Returns every value for a key, in wire order.
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter(move |tag| tag.key == key)
+            .map(Tag::value)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.0.iter().any(|tag| tag.key == key)
+    }
+
+    /// Appends a tag, keeping any existing tags with the same key.
+    pub fn push(&mut self, tag: Tag) {
+        self.0.push(tag);
+    }
+
+    /// Sets the value for a key. The first tag with the key is updated in place, and any
+    /// duplicates are removed. If the key is not present, the tag is appended.
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        let tag = Tag::new(key, value);
+        match self.0.iter().position(|t| t.key == tag.key) {
+            Some(pos) => {
+                let mut index = 0;
+                self.0.retain(|t| {
+                    let keep = index <= pos || t.key != tag.key;
+                    index += 1;
+                    keep
+                });
+                self.0[pos] = tag;
+            }
+            None => self.0.push(tag),
+        }
+    }
+
+    /// Removes all tags with the given key, returning the last value that was removed.
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let mut removed = None;
+        self.0.retain(|tag| {
+            if tag.key == key {
+                removed = Some(tag.value().to_string());
+                false
+            } else {
+                true
+            }
+        });
+        removed
+    }
+
+    /// Iterates over only the client-only (`+`) tags.
+    pub fn client_only(&self) -> impl Iterator<Item = &Tag> {
+        self.0.iter().filter(|tag| tag.is_client_only())
+    }
+}
+
+impl fmt::Debug for Tags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.0.iter()).finish()
+    }
+}
+
+impl std::iter::FromIterator<Tag> for Tags {
+    fn from_iter<T: IntoIterator<Item = Tag>>(iter: T) -> Self {
+        Tags(iter.into_iter().collect())
+    }
+}
+
+impl<'a> IntoIterator for &'a Tags {
+    type Item = &'a Tag;
+    type IntoIter = std::slice::Iter<'a, Tag>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::read_bytes::ReadBytes;
+    use crate::write_bytes::WriteBytes;
+    use crate::Message;
+
+    #[test]
+    fn writes_back_identically() {
+        let line: &[u8] =
+            b"@z=1;+draft/reply=abc;a;b=;dup=1;twitch.tv/x=a\\sb\\:c;dup=2 :foo PRIVMSG #bar :hi";
+        let msg = Message::read_bytes(line).unwrap();
+
+        let mut written = Vec::new();
+        msg.write_bytes(&mut written).unwrap();
+        assert_eq!(written, line);
+    }
+
+    #[test]
+    fn keeps_duplicates_and_key_parts() {
+        let msg = Message::read_bytes(b"@dup=1;+twitch.tv/foo=bar;dup=2 PRIVMSG #bar :hi").unwrap();
+        let tags = msg.tags();
+
+        assert_eq!(tags.get("dup"), Some("2"));
+        assert_eq!(tags.get_all("dup").collect::<Vec<_>>(), vec!["1", "2"]);
+
+        let client_tags = tags.client_only().collect::<Vec<_>>();
+        assert_eq!(client_tags.len(), 1);
+        assert_eq!(client_tags[0].vendor(), Some("twitch.tv"));
+        assert_eq!(client_tags[0].name(), "foo");
+        assert_eq!(client_tags[0].value(), "bar");
+    }
+
+    #[test]
+    fn set_replaces_in_place() {
+        let mut tags = super::Tags::new();
+        tags.set("a", "1");
+        tags.set("b", "2");
+        tags.push(super::Tag::new("a", "3"));
+        tags.set("a", "4");
+
+        let keys = tags
+            .iter()
+            .map(|t| (t.key(), t.value()))
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec![("a", "4"), ("b", "2")]);
+        assert_eq!(tags.remove("b"), Some("2".to_string()));
+        assert_eq!(tags.len(), 1);
+    }
+}
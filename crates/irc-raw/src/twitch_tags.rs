@@ -0,0 +1,359 @@
+//! Typed access to the IRCv3 tags that Twitch attaches to messages.
+//!
+//! Each accessor parses a single tag on demand. A missing tag is `Ok(None)`, while a tag that is
+//! present but can't be parsed gives a `TagError` naming the tag, so that one bad field does not
+//! prevent reading the others.
+
+use crate::Message;
+use std::time::{Duration, SystemTime};
+
+#[derive(thiserror::Error, Debug)]
+#[error("Invalid value for tag {tag:?} ({value:?}): {reason}")]
+pub struct TagError {
+    tag: &'static str,
+    value: String,
+    reason: String,
+}
+
+impl TagError {
+    fn new(tag: &'static str, value: &str, reason: impl std::fmt::Display) -> Self {
+        TagError {
+            tag,
+            value: value.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+
+    pub fn tag(&self) -> &'static str {
+        self.tag
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+}
+
+pub type TagResult<T> = Result<Option<T>, TagError>;
+
+/// A single badge, such as `subscriber/12`. In `badge-info`, the version is the extra detail
+/// (e.g. the number of months subscribed).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Badge {
+    pub name: String,
+    pub version: String,
+}
+
+/// An inclusive range of unicode scalar values in the message text.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct EmoteRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Emote {
+    pub id: String,
+    pub ranges: Vec<EmoteRange>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+/// The message that a chat message replies to, from the `reply-parent-*` tags.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReplyParent {
+    pub msg_id: String,
+    pub user_id: u64,
+    pub user_login: String,
+    pub display_name: String,
+    pub msg_body: String,
+}
+
+fn parse_badges(tag: &'static str, value: &str) -> Result<Vec<Badge>, TagError> {
+    if value.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    value
+        .split(',')
+        .map(|badge| match badge.find('/') {
+            Some(p) => Ok(Badge {
+                name: badge[..p].to_string(),
+                version: badge[p + 1..].to_string(),
+            }),
+            None => Err(TagError::new(tag, value, "badge is missing a '/'")),
+        })
+        .collect()
+}
+
+fn parse_emotes(tag: &'static str, value: &str) -> Result<Vec<Emote>, TagError> {
+    if value.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let parse_index = |s: &str| s.parse::<usize>().map_err(|e| TagError::new(tag, value, e));
+
+    let mut emotes = Vec::new();
+    for emote in value.split('/') {
+        let colon = emote
+            .find(':')
+            .ok_or_else(|| TagError::new(tag, value, "emote is missing a ':'"))?;
+        let mut ranges = Vec::new();
+        for range in emote[colon + 1..].split(',') {
+            let dash = range
+                .find('-')
+                .ok_or_else(|| TagError::new(tag, value, "emote range is missing a '-'"))?;
+            let start = parse_index(&range[..dash])?;
+            let end = parse_index(&range[dash + 1..])?;
+            if end < start {
+                return Err(TagError::new(
+                    tag,
+                    value,
+                    "emote range ends before it starts",
+                ));
+            }
+            ranges.push(EmoteRange { start, end });
+        }
+        emotes.push(Emote {
+            id: emote[..colon].to_string(),
+            ranges,
+        });
+    }
+    Ok(emotes)
+}
+
+fn parse_color(tag: &'static str, value: &str) -> TagResult<Color> {
+    // Users that have never picked a color have an empty color tag.
+    if value.is_empty() {
+        return Ok(None);
+    }
+
+    let hex = value
+        .strip_prefix('#')
+        .filter(|hex| hex.len() == 6 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+        .ok_or_else(|| TagError::new(tag, value, "expected a color of the form #RRGGBB"))?;
+    let component =
+        |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|e| TagError::new(tag, value, e));
+    Ok(Some(Color {
+        r: component(0)?,
+        g: component(2)?,
+        b: component(4)?,
+    }))
+}
+
+fn parse_flag(tag: &'static str, value: &str) -> Result<bool, TagError> {
+    match value {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        _ => Err(TagError::new(tag, value, "expected 0 or 1")),
+    }
+}
+
+fn parse_number(tag: &'static str, value: &str) -> Result<u64, TagError> {
+    value.parse().map_err(|e| TagError::new(tag, value, e))
+}
+
+/// A typed view of the Twitch tags on a message.
+#[derive(Copy, Clone)]
+pub struct TwitchTags<'a>(&'a Message);
+
+impl<'a> TwitchTags<'a> {
+    pub fn new(msg: &'a Message) -> Self {
+        TwitchTags(msg)
+    }
+
+    fn parse<T>(
+        &self,
+        tag: &'static str,
+        parser: impl FnOnce(&'static str, &'a str) -> Result<T, TagError>,
+    ) -> TagResult<T> {
+        self.0.tag(tag).map(|value| parser(tag, value)).transpose()
+    }
+
+    /// Returns the raw value of a tag, for tags that have no typed accessor.
+    pub fn raw(&self, tag: &str) -> Option<&'a str> {
+        self.0.tag(tag)
+    }
+
+    pub fn badges(&self) -> TagResult<Vec<Badge>> {
+        self.parse("badges", parse_badges)
+    }
+
+    pub fn badge_info(&self) -> TagResult<Vec<Badge>> {
+        self.parse("badge-info", parse_badges)
+    }
+
+    pub fn emotes(&self) -> TagResult<Vec<Emote>> {
+        self.parse("emotes", parse_emotes)
+    }
+
+    pub fn color(&self) -> TagResult<Color> {
+        Ok(self.parse("color", parse_color)?.flatten())
+    }
+
+    pub fn display_name(&self) -> Option<&'a str> {
+        self.raw("display-name")
+    }
+
+    /// The unique id of this message, from the `id` tag.
+    pub fn id(&self) -> Option<&'a str> {
+        self.raw("id")
+    }
+
+    pub fn msg_id(&self) -> Option<&'a str> {
+        self.raw("msg-id")
+    }
+
+    pub fn sent_ts(&self) -> TagResult<SystemTime> {
+        self.parse("tmi-sent-ts", |tag, value| {
+            let millis = parse_number(tag, value)?;
+            SystemTime::UNIX_EPOCH
+                .checked_add(Duration::from_millis(millis))
+                .ok_or_else(|| TagError::new(tag, value, "timestamp out of range"))
+        })
+    }
+
+    pub fn user_id(&self) -> TagResult<u64> {
+        self.parse("user-id", parse_number)
+    }
+
+    pub fn room_id(&self) -> TagResult<u64> {
+        self.parse("room-id", parse_number)
+    }
+
+    pub fn is_mod(&self) -> TagResult<bool> {
+        self.parse("mod", parse_flag)
+    }
+
+    pub fn is_subscriber(&self) -> TagResult<bool> {
+        self.parse("subscriber", parse_flag)
+    }
+
+    pub fn bits(&self) -> TagResult<u64> {
+        self.parse("bits", parse_number)
+    }
+
+    /// The slow mode delay from ROOMSTATE. Zero means slow mode is off.
+    pub fn slow(&self) -> TagResult<Duration> {
+        self.parse("slow", |tag, value| {
+            parse_number(tag, value).map(Duration::from_secs)
+        })
+    }
+
+    /// Returns the parent message if this message is a reply. Once `reply-parent-msg-id` is
+    /// present, a missing `reply-parent-*` tag is reported as an error.
+    pub fn reply_parent(&self) -> TagResult<ReplyParent> {
+        let msg_id = match self.raw("reply-parent-msg-id") {
+            Some(msg_id) => msg_id,
+            None => return Ok(None),
+        };
+
+        let required = |tag: &'static str| {
+            self.raw(tag)
+                .ok_or_else(|| TagError::new(tag, "", "missing from reply"))
+        };
+
+        Ok(Some(ReplyParent {
+            msg_id: msg_id.to_string(),
+            user_id: parse_number("reply-parent-user-id", required("reply-parent-user-id")?)?,
+            user_login: required("reply-parent-user-login")?.to_string(),
+            display_name: required("reply-parent-display-name")?.to_string(),
+            msg_body: required("reply-parent-msg-body")?.to_string(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::read_bytes::ReadBytes;
+
+    fn privmsg(tags: &str) -> Message {
+        let line = format!(
+            "@{} :foo!foo@foo.tmi.twitch.tv PRIVMSG #bar :Kappa hi Kappa",
+            tags
+        );
+        Message::read_bytes(line.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn parses_typed_tags() -> Result<(), TagError> {
+        let msg = privmsg(
+            "badge-info=subscriber/14;badges=moderator/1,subscriber/12;color=#1E90FF;\
+             emotes=25:0-4,12-16;mod=1;subscriber=1;room-id=1234;user-id=5678;\
+             tmi-sent-ts=1600000000000;bits=100",
+        );
+        let tags = TwitchTags::new(&msg);
+
+        assert_eq!(
+            tags.badges()?.unwrap()[1],
+            Badge {
+                name: "subscriber".into(),
+                version: "12".into()
+            }
+        );
+        assert_eq!(tags.badge_info()?.unwrap().len(), 1);
+        assert_eq!(
+            tags.color()?,
+            Some(Color {
+                r: 0x1e,
+                g: 0x90,
+                b: 0xff
+            })
+        );
+        assert_eq!(
+            tags.emotes()?.unwrap()[0].ranges,
+            vec![
+                EmoteRange { start: 0, end: 4 },
+                EmoteRange { start: 12, end: 16 }
+            ]
+        );
+        assert_eq!(tags.is_mod()?, Some(true));
+        assert_eq!(tags.is_subscriber()?, Some(true));
+        assert_eq!(tags.room_id()?, Some(1234));
+        assert_eq!(tags.user_id()?, Some(5678));
+        assert_eq!(tags.bits()?, Some(100));
+        assert_eq!(
+            tags.sent_ts()?,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000))
+        );
+        assert_eq!(tags.reply_parent()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn errors_are_per_field() {
+        let msg = privmsg("color=blue;user-id=abc;mod=0;badges=");
+        let tags = TwitchTags::new(&msg);
+
+        assert_eq!(tags.color().unwrap_err().tag(), "color");
+        assert_eq!(tags.user_id().unwrap_err().tag(), "user-id");
+        assert_eq!(tags.is_mod().unwrap(), Some(false));
+        assert_eq!(tags.badges().unwrap(), Some(Vec::new()));
+        assert_eq!(tags.bits().unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_signed_color_components() {
+        let msg = privmsg("color=#+1+2+3");
+        assert_eq!(TwitchTags::new(&msg).color().unwrap_err().tag(), "color");
+    }
+
+    #[test]
+    fn parses_reply_parent() -> Result<(), TagError> {
+        let msg = privmsg(
+            "reply-parent-msg-id=abc-123;reply-parent-user-id=42;reply-parent-user-login=foo;\
+             reply-parent-display-name=Foo;reply-parent-msg-body=hello\\sworld",
+        );
+        let parent = TwitchTags::new(&msg).reply_parent()?.unwrap();
+
+        assert_eq!(parent.msg_id, "abc-123");
+        assert_eq!(parent.user_id, 42);
+        assert_eq!(parent.msg_body, "hello world");
+        Ok(())
+    }
+}
@@ -0,0 +1,883 @@
+//! An in-process stand-in for Twitch's chat server (TMI), for testing IRC clients without a
+//! network connection.
+//!
+//! The server listens on a local port and speaks enough of Twitch's dialect for
+//! `minibot_irc::client`: CAP negotiation, PASS/NICK login, JOIN/PART with NAMES, PRIVMSG between
+//! connected clients, USERSTATE/ROOMSTATE, whispers and PING. Tests script the rest through
+//! `TestServer`: chat from users that aren't connected, RECONNECT, rate limit NOTICEs, dropped
+//! connections, and a log of everything clients sent.
+
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use minibot_irc_raw::{KnownCommand, Message, MessageRef};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+const HOST: &str = "tmi.twitch.tv";
+const CAPS: &[&str] = &[
+    "twitch.tv/tags",
+    "twitch.tv/commands",
+    "twitch.tv/membership",
+];
+
+fn parse(line: &str) -> Message {
+    MessageRef::parse(line.as_bytes())
+        .and_then(|msg| msg.to_owned())
+        .unwrap_or_else(|e| panic!("Bad test server message {:?}: {}", line, e))
+}
+
+fn source(nick: &str) -> String {
+    format!("{0}!{0}@{0}.tmi.twitch.tv", nick)
+}
+
+fn channel_name(target: &str) -> String {
+    target.trim_start_matches('#').to_lowercase()
+}
+
+fn is_anonymous(nick: &str) -> bool {
+    nick.starts_with("justinfan")
+}
+
+/// A message a client sent to the server.
+#[derive(Clone, Debug)]
+pub struct Received {
+    /// The client's nick, or `None` if it hadn't logged in yet.
+    pub nick: Option<String>,
+    pub message: Message,
+}
+
+enum Outgoing {
+    Message(Message),
+    Close,
+}
+
+struct Connection {
+    // Set once the client has logged in.
+    nick: Option<String>,
+    caps: BTreeSet<String>,
+    sender: mpsc::UnboundedSender<Outgoing>,
+    // When each recent chat message was sent, oldest first.
+    chat_sent: VecDeque<Instant>,
+}
+
+#[derive(Default)]
+struct Channel {
+    connections: BTreeSet<u64>,
+    // Users that are in the channel without being connected, added by `TestServer::join_user`.
+    users: BTreeSet<String>,
+    slow: u32,
+}
+
+#[derive(Clone, Default)]
+struct Config {
+    users: BTreeMap<String, String>,
+    moderators: BTreeSet<(String, String)>,
+    slow: BTreeMap<String, u32>,
+    chat_limit: Option<(usize, Duration)>,
+}
+
+struct State {
+    config: Config,
+    next_connection: u64,
+    next_msg_id: u64,
+    connections: BTreeMap<u64, Connection>,
+    channels: BTreeMap<String, Channel>,
+    user_ids: BTreeMap<String, u64>,
+    received: mpsc::UnboundedSender<Received>,
+}
+
+impl State {
+    fn send(&self, id: u64, line: &str) {
+        if let Some(connection) = self.connections.get(&id) {
+            let _ = connection
+                .sender
+                .unbounded_send(Outgoing::Message(parse(line)));
+        }
+    }
+
+    /// Sends `line` with `tags` in front, if the client asked for tags.
+    fn send_tagged(&self, id: u64, tags: &str, line: &str) {
+        if self.has_cap(id, "twitch.tv/tags") {
+            self.send(id, &format!("@{} {}", tags, line));
+        } else {
+            self.send(id, line);
+        }
+    }
+
+    fn close(&self, id: u64) {
+        if let Some(connection) = self.connections.get(&id) {
+            let _ = connection.sender.unbounded_send(Outgoing::Close);
+        }
+    }
+
+    fn nick(&self, id: u64) -> Option<&str> {
+        self.connections.get(&id)?.nick.as_deref()
+    }
+
+    fn has_cap(&self, id: u64, cap: &str) -> bool {
+        self.connections
+            .get(&id)
+            .is_some_and(|connection| connection.caps.contains(cap))
+    }
+
+    fn connections_for(&self, nick: &str) -> Vec<u64> {
+        self.connections
+            .iter()
+            .filter(|(_, connection)| connection.nick.as_deref() == Some(nick))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    fn user_id(&mut self, nick: &str) -> u64 {
+        let next = self.user_ids.len() as u64 + 1000;
+        *self.user_ids.entry(nick.to_string()).or_insert(next)
+    }
+
+    fn msg_id(&mut self) -> String {
+        self.next_msg_id += 1;
+        format!("00000000-0000-0000-0000-{:012}", self.next_msg_id)
+    }
+
+    fn is_moderator(&self, channel: &str, nick: &str) -> bool {
+        channel == nick
+            || self
+                .config
+                .moderators
+                .contains(&(channel.to_string(), nick.to_string()))
+    }
+
+    fn badges(&self, channel: &str, nick: &str) -> &'static str {
+        if channel == nick {
+            "broadcaster/1"
+        } else if self.is_moderator(channel, nick) {
+            "moderator/1"
+        } else {
+            ""
+        }
+    }
+
+    fn members(&self, channel: &str) -> Vec<String> {
+        let channel = match self.channels.get(channel) {
+            Some(channel) => channel,
+            None => return Vec::new(),
+        };
+        let mut members = channel
+            .connections
+            .iter()
+            .filter_map(|id| self.nick(*id))
+            .map(str::to_string)
+            .chain(channel.users.iter().cloned())
+            .collect::<Vec<_>>();
+        members.sort();
+        members.dedup();
+        members
+    }
+
+    /// Sends a JOIN or PART to the connections in a channel that asked for membership, except for
+    /// `except`.
+    fn send_membership(&self, channel: &str, nick: &str, command: &str, except: Option<u64>) {
+        let line = format!(":{} {} #{}", source(nick), command, channel);
+        for id in self.channel_connections(channel) {
+            if Some(id) != except && self.has_cap(id, "twitch.tv/membership") {
+                self.send(id, &line);
+            }
+        }
+    }
+
+    fn channel_connections(&self, channel: &str) -> Vec<u64> {
+        self.channels
+            .get(channel)
+            .map(|channel| channel.connections.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    fn login(&mut self, id: u64, nick: &str, pass: Option<&str>) {
+        let token = pass.and_then(|pass| pass.strip_prefix("oauth:"));
+        let accepted = is_anonymous(nick)
+            || match (token, self.config.users.get(nick)) {
+                (Some(token), Some(expected)) => token == expected,
+                (Some(token), None) => self.config.users.is_empty() && !token.is_empty(),
+                (None, _) => false,
+            };
+        if !accepted {
+            let text = if token.is_some() {
+                "Login authentication failed"
+            } else {
+                "Improperly formatted auth"
+            };
+            self.send(id, &format!(":{} NOTICE * :{}", HOST, text));
+            self.close(id);
+            return;
+        }
+
+        if let Some(connection) = self.connections.get_mut(&id) {
+            connection.nick = Some(nick.to_string());
+        }
+        let welcome = [
+            ("001", "Welcome, GLHF!"),
+            ("002", "Your host is tmi.twitch.tv"),
+            ("003", "This server is rather new"),
+            ("004", "-"),
+            ("375", "-"),
+            ("372", "You are in a maze of twisty passages, all alike."),
+            ("376", ">"),
+        ];
+        for (number, text) in &welcome {
+            self.send(id, &format!(":{} {} {} :{}", HOST, number, nick, text));
+        }
+        if self.has_cap(id, "twitch.tv/commands") && !is_anonymous(nick) {
+            let user_id = self.user_id(nick);
+            self.send_tagged(
+                id,
+                &format!(
+                    "badge-info=;badges=;color=;display-name={};emote-sets=0;user-id={};user-type=",
+                    nick, user_id
+                ),
+                &format!(":{} GLOBALUSERSTATE", HOST),
+            );
+        }
+    }
+
+    fn join(&mut self, id: u64, channel: &str) {
+        let nick = match self.nick(id) {
+            Some(nick) => nick.to_string(),
+            None => return,
+        };
+        let room = self.channels.entry(channel.to_string()).or_default();
+        if !room.connections.insert(id) {
+            return;
+        }
+        let slow = room.slow;
+
+        self.send(id, &format!(":{} JOIN #{}", source(&nick), channel));
+        self.send_membership(channel, &nick, "JOIN", Some(id));
+        let server = format!("{}.tmi.twitch.tv", nick);
+        self.send(
+            id,
+            &format!(
+                ":{} 353 {} = #{} :{}",
+                server,
+                nick,
+                channel,
+                self.members(channel).join(" ")
+            ),
+        );
+        self.send(
+            id,
+            &format!(":{} 366 {} #{} :End of /NAMES list", server, nick, channel),
+        );
+
+        if self.has_cap(id, "twitch.tv/commands") {
+            let is_mod = self.is_moderator(channel, &nick) && channel != nick;
+            let user_state = format!(
+                "badge-info=;badges={};color=;display-name={};emote-sets=0;mod={};subscriber=0;\
+                 user-type={}",
+                self.badges(channel, &nick),
+                nick,
+                is_mod as u8,
+                if is_mod { "mod" } else { "" }
+            );
+            self.send_tagged(
+                id,
+                &user_state,
+                &format!(":{} USERSTATE #{}", HOST, channel),
+            );
+            let room_id = self.user_id(channel);
+            let room_state = format!(
+                "emote-only=0;followers-only=-1;r9k=0;room-id={};slow={};subs-only=0",
+                room_id, slow
+            );
+            self.send_tagged(
+                id,
+                &room_state,
+                &format!(":{} ROOMSTATE #{}", HOST, channel),
+            );
+        }
+    }
+
+    fn part(&mut self, id: u64, channel: &str) {
+        let nick = match self.nick(id) {
+            Some(nick) => nick.to_string(),
+            None => return,
+        };
+        let was_member = self
+            .channels
+            .get_mut(channel)
+            .is_some_and(|room| room.connections.remove(&id));
+        if was_member {
+            self.send(id, &format!(":{} PART #{}", source(&nick), channel));
+            self.send_membership(channel, &nick, "PART", Some(id));
+        }
+    }
+
+    /// Whether a chat message from `id` to `channel` is over the configured limit. Like Twitch,
+    /// the server answers those with a NOTICE instead of sending them on.
+    fn over_chat_limit(&mut self, id: u64, channel: &str, now: Instant) -> bool {
+        let nick = self.nick(id).unwrap_or_default().to_string();
+        if self.is_moderator(channel, &nick) {
+            return false;
+        }
+        let (count, period) = match self.config.chat_limit {
+            Some(limit) => limit,
+            None => return false,
+        };
+        let connection = match self.connections.get_mut(&id) {
+            Some(connection) => connection,
+            None => return false,
+        };
+        while let Some(&oldest) = connection.chat_sent.front() {
+            if oldest + period > now {
+                break;
+            }
+            connection.chat_sent.pop_front();
+        }
+        if connection.chat_sent.len() >= count {
+            return true;
+        }
+        connection.chat_sent.push_back(now);
+        false
+    }
+
+    fn privmsg(&mut self, id: u64, target: &str, text: &str, reply_to: Option<&str>) {
+        let nick = match self.nick(id) {
+            Some(nick) => nick.to_string(),
+            None => return,
+        };
+        // Twitch accepts messages from anonymous users and then drops them.
+        if is_anonymous(&nick) {
+            return;
+        }
+        let whisper = text
+            .strip_prefix("/w ")
+            .or_else(|| text.strip_prefix("/whisper "))
+            .and_then(|rest| rest.split_once(' '));
+        if let Some((to, text)) = whisper {
+            self.whisper(&nick, &to.to_lowercase(), text);
+            return;
+        }
+
+        let channel = channel_name(target);
+        if !self.channel_connections(&channel).contains(&id) {
+            return;
+        }
+        if self.over_chat_limit(id, &channel, Instant::now()) {
+            self.rate_limit_notice(id, &channel);
+            return;
+        }
+        if text.starts_with('/') || text.starts_with('.') {
+            self.chat_command(id, &channel, &text[1..]);
+            return;
+        }
+        self.chat(&channel, &nick, text, reply_to, Some(id));
+    }
+
+    /// Answers a chat command such as `/delete`. Twitch stopped running chat commands sent over
+    /// IRC in February 2023, so none of them do anything.
+    fn chat_command(&self, id: u64, channel: &str, command: &str) {
+        let name = match command.split_whitespace().next() {
+            Some(name) => name,
+            None => return,
+        };
+        self.send_tagged(
+            id,
+            "msg-id=unrecognized_cmd",
+            &format!(
+                ":{} NOTICE #{} :Unrecognized command: /{}",
+                HOST, channel, name
+            ),
+        );
+    }
+
+    fn rate_limit_notice(&self, id: u64, channel: &str) {
+        self.send_tagged(
+            id,
+            "msg-id=msg_ratelimit",
+            &format!(
+                ":{} NOTICE #{} :Your message was not sent because you are sending messages too \
+                 quickly.",
+                HOST, channel
+            ),
+        );
+    }
+
+    /// Sends a chat message to everyone in the channel except `except`. `reply_to` is the id of
+    /// the message it replies to.
+    fn chat(
+        &mut self,
+        channel: &str,
+        nick: &str,
+        text: &str,
+        reply_to: Option<&str>,
+        except: Option<u64>,
+    ) {
+        let msg_id = self.msg_id();
+        let user_id = self.user_id(nick);
+        let room_id = self.user_id(channel);
+        let sent_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let tags = format!(
+            "badge-info=;badges={};color=;display-name={};emotes=;id={};mod={};room-id={};\
+             tmi-sent-ts={};user-id={}",
+            self.badges(channel, nick),
+            nick,
+            msg_id,
+            (self.is_moderator(channel, nick) && channel != nick) as u8,
+            room_id,
+            sent_at,
+            user_id
+        );
+        let tags = match reply_to {
+            Some(parent) => format!("{};reply-parent-msg-id={}", tags, parent),
+            None => tags,
+        };
+        let line = format!(":{} PRIVMSG #{} :{}", source(nick), channel, text);
+        for id in self.channel_connections(channel) {
+            if Some(id) != except {
+                self.send_tagged(id, &tags, &line);
+            }
+        }
+    }
+
+    fn whisper(&mut self, from: &str, to: &str, text: &str) {
+        let msg_id = self.msg_id();
+        let from_id = self.user_id(from);
+        let to_id = self.user_id(to);
+        let tags = format!(
+            "badges=;color=;display-name={};emotes=;message-id={};thread-id={}_{};user-id={}",
+            from, msg_id, from_id, to_id, from_id
+        );
+        let line = format!(":{} WHISPER {} :{}", source(from), to, text);
+        for id in self.connections_for(to) {
+            self.send_tagged(id, &tags, &line);
+        }
+    }
+
+    fn remove(&mut self, id: u64) {
+        let nick = self
+            .connections
+            .remove(&id)
+            .and_then(|connection| connection.nick);
+        let mut left = Vec::new();
+        for (name, channel) in &mut self.channels {
+            if channel.connections.remove(&id) {
+                left.push(name.clone());
+            }
+        }
+        if let Some(nick) = nick {
+            for channel in left {
+                self.send_membership(&channel, &nick, "PART", None);
+            }
+        }
+    }
+}
+
+// What a connection has sent so far toward logging in.
+#[derive(Default)]
+struct Login {
+    pass: Option<String>,
+    nick: Option<String>,
+    negotiating_caps: bool,
+    done: bool,
+}
+
+impl Login {
+    fn try_finish(&mut self, id: u64, state: &mut State) {
+        if self.done || self.negotiating_caps {
+            return;
+        }
+        if let Some(nick) = &self.nick {
+            self.done = true;
+            state.login(id, nick, self.pass.as_deref());
+        }
+    }
+}
+
+fn handle_cap(id: u64, login: &mut Login, state: &mut State, params: &[String]) {
+    let param = |i: usize| params.get(i).map(String::as_str).unwrap_or("");
+    match param(0) {
+        "LS" => {
+            login.negotiating_caps = true;
+            state.send(id, &format!(":{} CAP * LS :{}", HOST, CAPS.join(" ")));
+        }
+        "REQ" => {
+            login.negotiating_caps = !login.done;
+            let requested = param(1);
+            let known = requested.split_whitespace().all(|cap| CAPS.contains(&cap));
+            if known {
+                if let Some(connection) = state.connections.get_mut(&id) {
+                    connection
+                        .caps
+                        .extend(requested.split_whitespace().map(str::to_string));
+                }
+            }
+            let reply = if known { "ACK" } else { "NAK" };
+            state.send(id, &format!(":{} CAP * {} :{}", HOST, reply, requested));
+        }
+        "END" => {
+            login.negotiating_caps = false;
+            login.try_finish(id, state);
+        }
+        _ => {}
+    }
+}
+
+fn handle_message(id: u64, login: &mut Login, state: &mut State, msg: &Message) {
+    let params = msg
+        .params()
+        .iter()
+        .map(|p| String::from_utf8_lossy(p.as_ref()).into_owned())
+        .collect::<Vec<_>>();
+    let param = |i: usize| params.get(i).map(String::as_str).unwrap_or("");
+
+    match msg.known_command() {
+        Some(KnownCommand::Cap) => handle_cap(id, login, state, &params),
+        Some(KnownCommand::Pass) => login.pass = Some(param(0).to_string()),
+        Some(KnownCommand::Nick) => {
+            login.nick = Some(param(0).to_lowercase());
+            login.try_finish(id, state);
+        }
+        Some(KnownCommand::Ping) => {
+            state.send(id, &format!(":{0} PONG {0} :{1}", HOST, param(0)));
+        }
+        Some(KnownCommand::Quit) => state.close(id),
+        // Twitch ignores everything else until the client has logged in.
+        _ if state.nick(id).is_none() => {}
+        Some(KnownCommand::Join) => {
+            for channel in param(0).split(',') {
+                state.join(id, &channel_name(channel));
+            }
+        }
+        Some(KnownCommand::Part) => {
+            for channel in param(0).split(',') {
+                state.part(id, &channel_name(channel));
+            }
+        }
+        Some(KnownCommand::Privmsg) => {
+            state.privmsg(id, param(0), param(1), msg.tag("reply-parent-msg-id"))
+        }
+        Some(KnownCommand::Pong) => {}
+        _ => {
+            let nick = state.nick(id).unwrap_or_default().to_string();
+            let command = format!("{:?}", msg.command());
+            state.send(
+                id,
+                &format!(":{} 421 {} {} :Unknown command", HOST, nick, command),
+            );
+        }
+    }
+}
+
+async fn run_connection(
+    id: u64,
+    stream: TcpStream,
+    state: Arc<Mutex<State>>,
+    mut outgoing: mpsc::UnboundedReceiver<Outgoing>,
+) {
+    let (mut irc_read, mut irc_write) = minibot_irc_raw::from_channel(stream.compat());
+    let mut login = Login::default();
+    loop {
+        match future::select(irc_read.next(), outgoing.next()).await {
+            Either::Left((Some(Ok(msg)), _)) => {
+                let mut state = state.lock().unwrap();
+                let _ = state.received.unbounded_send(Received {
+                    nick: state.nick(id).map(str::to_string),
+                    message: msg.clone(),
+                });
+                handle_message(id, &mut login, &mut state, &msg);
+            }
+            // Clients get no say in what counts as a valid line.
+            Either::Left((Some(Err(e)), _)) if e.is_recoverable() => {}
+            Either::Left(_) => break,
+            Either::Right((Some(Outgoing::Message(msg)), _)) => {
+                if irc_write.send(msg).await.is_err() {
+                    break;
+                }
+            }
+            Either::Right(_) => break,
+        }
+    }
+    state.lock().unwrap().remove(id);
+    let _ = irc_write.close().await;
+}
+
+async fn run_listener(listener: TcpListener, state: Arc<Mutex<State>>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let (sender, outgoing) = mpsc::unbounded();
+        let id = {
+            let mut state = state.lock().unwrap();
+            let id = state.next_connection;
+            state.next_connection += 1;
+            state.connections.insert(
+                id,
+                Connection {
+                    nick: None,
+                    caps: BTreeSet::new(),
+                    sender,
+                    chat_sent: VecDeque::new(),
+                },
+            );
+            id
+        };
+        tokio::spawn(run_connection(id, stream, state.clone(), outgoing));
+    }
+}
+
+pub struct TestServerBuilder {
+    config: Config,
+}
+
+impl TestServerBuilder {
+    /// Adds a user that can log in with `token`. If no users are added, any token is accepted.
+    /// Anonymous `justinfanNNNN` users can always log in, without a token.
+    pub fn user(mut self, nick: &str, token: &str) -> Self {
+        self.config
+            .users
+            .insert(nick.to_lowercase(), token.to_string());
+        self
+    }
+
+    /// Makes `nick` a moderator of `channel`. Broadcasters are always moderators of their own
+    /// channel.
+    pub fn moderator(mut self, channel: &str, nick: &str) -> Self {
+        self.config
+            .moderators
+            .insert((channel.to_lowercase(), nick.to_lowercase()));
+        self
+    }
+
+    /// Turns on slow mode in `channel`.
+    pub fn slow(mut self, channel: &str, seconds: u32) -> Self {
+        self.config.slow.insert(channel.to_lowercase(), seconds);
+        self
+    }
+
+    /// Answers chat messages past `count` per `period` from one connection with a
+    /// `msg_ratelimit` NOTICE, and drops them. Moderators are exempt. Off by default.
+    pub fn chat_limit(mut self, count: usize, period: Duration) -> Self {
+        self.config.chat_limit = Some((count, period));
+        self
+    }
+
+    /// Starts listening on a free local port.
+    pub async fn start(self) -> io::Result<TestServer> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+        let (received_sink, received) = mpsc::unbounded();
+        let channels = self
+            .config
+            .slow
+            .iter()
+            .map(|(name, slow)| {
+                let channel = Channel {
+                    slow: *slow,
+                    ..Channel::default()
+                };
+                (name.clone(), channel)
+            })
+            .collect();
+        let state = Arc::new(Mutex::new(State {
+            config: self.config,
+            next_connection: 0,
+            next_msg_id: 0,
+            connections: BTreeMap::new(),
+            channels,
+            user_ids: BTreeMap::new(),
+            received: received_sink,
+        }));
+        let handle = tokio::spawn(run_listener(listener, state.clone()));
+        Ok(TestServer {
+            addr,
+            state,
+            received,
+            handle,
+        })
+    }
+}
+
+/// A running mock server. Channel and user names are given without the leading `#`, and are
+/// lowercased the way Twitch does. Dropping the server closes every connection.
+pub struct TestServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    received: mpsc::UnboundedReceiver<Received>,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+impl TestServer {
+    pub fn builder() -> TestServerBuilder {
+        TestServerBuilder {
+            config: Config::default(),
+        }
+    }
+
+    /// Starts a server that accepts any login.
+    pub async fn start() -> io::Result<TestServer> {
+        TestServer::builder().start().await
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    /// The next message any client sent, in the order the server got them. Returns `None` once
+    /// the server is shut down.
+    pub async fn next_received(&mut self) -> Option<Received> {
+        self.received.next().await
+    }
+
+    /// Skips received messages until one with the given command, e.g. `"PRIVMSG"`.
+    pub async fn wait_for(&mut self, command: &str) -> Option<Received> {
+        loop {
+            let received = self.next_received().await?;
+            if format!("{:?}", received.message.command()).eq_ignore_ascii_case(command) {
+                return Some(received);
+            }
+        }
+    }
+
+    /// The nicks of the clients that are logged in.
+    pub fn connected(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .connections
+            .values()
+            .filter_map(|connection| connection.nick.clone())
+            .collect()
+    }
+
+    /// Everyone in a channel, connected or not.
+    pub fn members(&self, channel: &str) -> Vec<String> {
+        self.state.lock().unwrap().members(&channel.to_lowercase())
+    }
+
+    /// Sends a raw line to every connection logged in as `nick`.
+    pub fn send_to(&self, nick: &str, line: &str) {
+        let state = self.state.lock().unwrap();
+        for id in state.connections_for(&nick.to_lowercase()) {
+            state.send(id, line);
+        }
+    }
+
+    /// Sends chat from `nick` to everyone connected to `channel`. `nick` doesn't have to be
+    /// connected, or in the channel.
+    pub fn say(&self, channel: &str, nick: &str, text: &str) {
+        self.state.lock().unwrap().chat(
+            &channel.to_lowercase(),
+            &nick.to_lowercase(),
+            text,
+            None,
+            None,
+        );
+    }
+
+    /// Sends a whisper from `from` to every connection logged in as `to`.
+    pub fn whisper(&self, from: &str, to: &str, text: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .whisper(&from.to_lowercase(), &to.to_lowercase(), text);
+    }
+
+    /// Adds a user that isn't connected to a channel, as if they had joined it.
+    pub fn join_user(&self, channel: &str, nick: &str) {
+        let (channel, nick) = (channel.to_lowercase(), nick.to_lowercase());
+        let mut state = self.state.lock().unwrap();
+        let added = state
+            .channels
+            .entry(channel.clone())
+            .or_default()
+            .users
+            .insert(nick.clone());
+        if added {
+            state.send_membership(&channel, &nick, "JOIN", None);
+        }
+    }
+
+    /// Removes a user added by `join_user`.
+    pub fn part_user(&self, channel: &str, nick: &str) {
+        let (channel, nick) = (channel.to_lowercase(), nick.to_lowercase());
+        let mut state = self.state.lock().unwrap();
+        let removed = state
+            .channels
+            .get_mut(&channel)
+            .is_some_and(|room| room.users.remove(&nick));
+        if removed {
+            state.send_membership(&channel, &nick, "PART", None);
+        }
+    }
+
+    /// Changes slow mode in a channel, and tells everyone in it with a ROOMSTATE.
+    pub fn set_slow(&self, channel: &str, seconds: u32) {
+        let channel = channel.to_lowercase();
+        let mut state = self.state.lock().unwrap();
+        state.channels.entry(channel.clone()).or_default().slow = seconds;
+        let room_id = state.user_id(&channel);
+        for id in state.channel_connections(&channel) {
+            if state.has_cap(id, "twitch.tv/commands") {
+                state.send_tagged(
+                    id,
+                    &format!("room-id={};slow={}", room_id, seconds),
+                    &format!(":{} ROOMSTATE #{}", HOST, channel),
+                );
+            }
+        }
+    }
+
+    /// Sends the `msg_ratelimit` NOTICE that Twitch sends for a message that was dropped for
+    /// going over the rate limit.
+    pub fn rate_limit_notice(&self, nick: &str, channel: &str) {
+        let state = self.state.lock().unwrap();
+        for id in state.connections_for(&nick.to_lowercase()) {
+            state.rate_limit_notice(id, &channel.to_lowercase());
+        }
+    }
+
+    /// Asks every connection logged in as `nick` to reconnect, as Twitch does before a restart.
+    /// Unlike Twitch, the server doesn't close the connection afterwards.
+    pub fn reconnect(&self, nick: &str) {
+        self.send_to(nick, &format!(":{} RECONNECT", HOST));
+    }
+
+    /// Closes every connection logged in as `nick`, without warning.
+    pub fn disconnect(&self, nick: &str) {
+        let state = self.state.lock().unwrap();
+        for id in state.connections_for(&nick.to_lowercase()) {
+            state.close(id);
+        }
+    }
+
+    /// Closes the connections logged in as `nick` that are in `channel`, for clients that have
+    /// more than one connection.
+    pub fn disconnect_in(&self, channel: &str, nick: &str) {
+        let state = self.state.lock().unwrap();
+        let in_channel = state.channel_connections(&channel.to_lowercase());
+        for id in state.connections_for(&nick.to_lowercase()) {
+            if in_channel.contains(&id) {
+                state.close(id);
+            }
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+        if let Ok(state) = self.state.lock() {
+            for id in state.connections.keys() {
+                state.close(*id);
+            }
+        }
+    }
+}
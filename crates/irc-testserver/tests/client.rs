@@ -0,0 +1,315 @@
+use futures::channel::mpsc;
+use futures::prelude::*;
+use minibot_irc::client::{
+    ClientError, ClientFactory, ConnectionEvent, DisconnectReason, LoginError,
+};
+use minibot_irc::connection::Transport;
+use minibot_irc::event::Notice;
+use minibot_irc::event::{Event, SlowSubscriberPolicy};
+use minibot_irc::room_state::events::{PrimaryEvent, RoomEvent};
+use minibot_irc::room_state::MembersList;
+use minibot_irc::KnownCommand;
+use minibot_irc_testserver::TestServer;
+use std::time::{Duration, Instant};
+
+fn factory() -> ClientFactory {
+    ClientFactory::create()
+        .unwrap()
+        .with_transport(Transport::Tcp)
+}
+
+#[tokio::test]
+async fn chat_goes_both_ways() {
+    let mut server = TestServer::builder()
+        .user("bot", "token")
+        .start()
+        .await
+        .unwrap();
+    let mut client = factory()
+        .connect("127.0.0.1", server.port(), "bot", "token")
+        .await
+        .unwrap();
+    let mut events = client.subscribe(16, SlowSubscriberPolicy::Block).unwrap();
+
+    client.join("room").await.unwrap();
+    server.wait_for("JOIN").await.unwrap();
+    assert_eq!(server.members("room"), vec!["bot"]);
+
+    server.say("room", "someone", "hello");
+    let chat = loop {
+        if let Event::Chat(chat) = events.next().await.unwrap() {
+            break chat;
+        }
+    };
+    assert_eq!(chat.sender, "someone");
+    assert_eq!(chat.text, "hello");
+    assert!(chat.message.tag("id").is_some());
+
+    client.action("room", "waves").await.unwrap();
+    let sent = server.wait_for("PRIVMSG").await.unwrap();
+    assert_eq!(sent.nick.as_deref(), Some("bot"));
+
+    client.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn bad_token_fails_login() {
+    let server = TestServer::builder()
+        .user("bot", "token")
+        .start()
+        .await
+        .unwrap();
+    let result = factory()
+        .connect("127.0.0.1", server.port(), "bot", "wrong")
+        .await;
+    assert!(matches!(
+        result,
+        Err(ClientError::Login(LoginError::AuthenticationFailed(_)))
+    ));
+}
+
+#[tokio::test]
+async fn reconnect_drops_the_connection() {
+    let server = TestServer::start().await.unwrap();
+    let mut client = factory()
+        .connect("127.0.0.1", server.port(), "bot", "token")
+        .await
+        .unwrap();
+    let (listener, mut connection_events) = mpsc::channel(1);
+    client.add_connection_listener(listener).unwrap();
+
+    server.reconnect("bot");
+    assert!(matches!(
+        connection_events.next().await,
+        Some(ConnectionEvent::Disconnected(DisconnectReason::Reconnect))
+    ));
+
+    client.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn anonymous_clients_can_only_read() {
+    let mut server = TestServer::builder()
+        .user("bot", "token")
+        .start()
+        .await
+        .unwrap();
+    let mut client = factory()
+        .connect_anonymous("127.0.0.1", server.port())
+        .await
+        .unwrap();
+    assert!(client.user().unwrap().starts_with("justinfan"));
+    // The client sends PASS, if at all, before NICK.
+    loop {
+        let received = server.next_received().await.unwrap();
+        assert!(!received.message.has_command(KnownCommand::Pass));
+        if received.message.has_command(KnownCommand::Nick) {
+            break;
+        }
+    }
+
+    client.join("room").await.unwrap();
+    server.wait_for("JOIN").await.unwrap();
+    assert!(matches!(
+        client.action("room", "waves").await,
+        Err(ClientError::Anonymous)
+    ));
+    assert!(matches!(
+        client.whisper("someone", "hi").await,
+        Err(ClientError::Anonymous)
+    ));
+
+    client.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn replies_thread_but_deletes_and_whispers_are_unsupported() {
+    let mut server = TestServer::start().await.unwrap();
+    let mut client = factory()
+        .connect("127.0.0.1", server.port(), "bot", "token")
+        .await
+        .unwrap();
+    let mut events = client.subscribe(16, SlowSubscriberPolicy::Block).unwrap();
+    client.join("room").await.unwrap();
+    server.wait_for("JOIN").await.unwrap();
+
+    server.say("room", "someone", "!uptime");
+    let parent = loop {
+        if let Event::Chat(chat) = events.next().await.unwrap() {
+            break chat.id.unwrap();
+        }
+    };
+    client.reply("room", &parent, "a while").await.unwrap();
+    let reply = server.wait_for("PRIVMSG").await.unwrap();
+    assert_eq!(
+        reply.message.tag("reply-parent-msg-id"),
+        Some(parent.as_str())
+    );
+
+    assert!(matches!(
+        client.delete("room", &parent).await,
+        Err(ClientError::Unsupported(_))
+    ));
+    assert!(matches!(
+        client.whisper("someone", "hi").await,
+        Err(ClientError::Unsupported(_))
+    ));
+    // Deletions by moderators still come through.
+    server.send_to(
+        "bot",
+        &format!(
+            "@login=someone;target-msg-id={} :tmi.twitch.tv CLEARMSG #room :!uptime",
+            parent
+        ),
+    );
+    let cleared = loop {
+        if let Event::ClearMsg(cleared) = events.next().await.unwrap() {
+            break cleared;
+        }
+    };
+    assert_eq!(cleared.target_msg_id, Some(parent));
+
+    client.close().await.unwrap();
+}
+
+/// Waits for `count` chat messages to reach the server, and returns how long it took from the
+/// first to the last.
+async fn chat_spread(server: &mut TestServer, count: usize) -> Duration {
+    let first = server.wait_for("PRIVMSG").await.unwrap();
+    assert_eq!(first.nick.as_deref(), Some("bot"));
+    let start = Instant::now();
+    for _ in 1..count {
+        server.wait_for("PRIVMSG").await.unwrap();
+    }
+    start.elapsed()
+}
+
+#[tokio::test]
+async fn slow_mode_spaces_out_chat() {
+    let mut server = TestServer::builder().slow("room", 1).start().await.unwrap();
+    let mut client = factory()
+        .connect("127.0.0.1", server.port(), "bot", "token")
+        .await
+        .unwrap();
+    let mut events = client.subscribe(16, SlowSubscriberPolicy::Block).unwrap();
+    client.join("room").await.unwrap();
+    while !matches!(events.next().await.unwrap(), Event::RoomState(_)) {}
+
+    client.say("room", "one").await.unwrap();
+    client.say("room", "two").await.unwrap();
+    assert!(chat_spread(&mut server, 2).await >= Duration::from_millis(800));
+
+    server.set_slow("room", 0);
+    while !matches!(events.next().await.unwrap(), Event::RoomState(_)) {}
+    client.say("room", "three").await.unwrap();
+    client.say("room", "four").await.unwrap();
+    assert!(chat_spread(&mut server, 2).await < Duration::from_millis(800));
+
+    client.close().await.unwrap();
+}
+
+async fn next_notice(events: &mut mpsc::Receiver<Event>) -> Notice {
+    loop {
+        if let Event::Notice(notice) = events.next().await.unwrap() {
+            return notice;
+        }
+    }
+}
+
+#[tokio::test]
+async fn chat_over_the_server_limit_gets_a_notice() {
+    let mut server = TestServer::builder()
+        .chat_limit(1, Duration::from_secs(30))
+        .start()
+        .await
+        .unwrap();
+    let mut client = factory()
+        .connect("127.0.0.1", server.port(), "bot", "token")
+        .await
+        .unwrap();
+    let mut events = client.subscribe(16, SlowSubscriberPolicy::Block).unwrap();
+    client.join("room").await.unwrap();
+    server.wait_for("JOIN").await.unwrap();
+
+    client.say("room", "one").await.unwrap();
+    client.say("room", "two").await.unwrap();
+    let notice = next_notice(&mut events).await;
+    assert_eq!(notice.channel.as_deref(), Some("room"));
+    assert_eq!(notice.msg_id.as_deref(), Some("msg_ratelimit"));
+
+    server.rate_limit_notice("bot", "room");
+    let notice = next_notice(&mut events).await;
+    assert_eq!(notice.msg_id.as_deref(), Some("msg_ratelimit"));
+
+    client.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn whispers_reach_primary_listeners() {
+    let server = TestServer::start().await.unwrap();
+    let mut client = factory()
+        .connect("127.0.0.1", server.port(), "bot", "token")
+        .await
+        .unwrap();
+    let (listener, mut primary_events) = mpsc::channel(16);
+    client
+        .add_primary_listener(listener, SlowSubscriberPolicy::Block)
+        .await
+        .unwrap();
+
+    server.whisper("Someone", "bot", "psst");
+    let PrimaryEvent::Whisper(whisper) = primary_events.next().await.unwrap();
+    assert_eq!(whisper.from, "someone");
+    assert_eq!(whisper.message, "psst");
+
+    client.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn room_listeners_follow_membership() {
+    let server = TestServer::start().await.unwrap();
+    server.join_user("room", "early");
+    let mut client = factory()
+        .connect("127.0.0.1", server.port(), "bot", "token")
+        .await
+        .unwrap();
+    client.join("room").await.unwrap();
+    let (listener, mut room_events) = mpsc::channel(16);
+    client
+        .add_room_listener("room", listener, SlowSubscriberPolicy::Block)
+        .await
+        .unwrap();
+
+    // Our own JOIN may come before NAMES; wait for the list that has everyone.
+    loop {
+        if let RoomEvent::MembersListUpdate(update) = room_events.next().await.unwrap() {
+            if let MembersList::Users(users) = update.members_list {
+                if users.contains(&"early".to_string()) {
+                    assert!(users.contains(&"bot".to_string()));
+                    break;
+                }
+            }
+        }
+    }
+
+    server.join_user("room", "late");
+    loop {
+        if let RoomEvent::UserJoined(joined) = room_events.next().await.unwrap() {
+            assert_eq!(joined.user, "late");
+            break;
+        }
+    }
+    server.part_user("room", "early");
+    loop {
+        if let RoomEvent::UserLeft(left) = room_events.next().await.unwrap() {
+            assert_eq!(left.user, "early");
+            break;
+        }
+    }
+    match client.members("room").await.unwrap() {
+        Some(MembersList::Users(users)) => assert_eq!(users, vec!["bot", "late"]),
+        _ => panic!("Expected the members of #room"),
+    }
+
+    client.close().await.unwrap();
+}
@@ -0,0 +1,178 @@
+use futures::channel::mpsc;
+use futures::prelude::*;
+use minibot_irc::client::ClientFactory;
+use minibot_irc::connection::Transport;
+use minibot_irc::event::{Event, SlowSubscriberPolicy};
+use minibot_irc::pool::{ClientPool, PoolConfig};
+use minibot_irc::supervisor::{BackoffConfig, SupervisorConfig};
+use minibot_irc_testserver::TestServer;
+use std::time::Duration;
+
+/// Waits until `check` passes, or panics after a few seconds.
+async fn eventually(check: impl Fn() -> bool) {
+    for _ in 0..200 {
+        if check() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("Condition never became true");
+}
+
+fn factory() -> ClientFactory {
+    ClientFactory::create()
+        .unwrap()
+        .with_transport(Transport::Tcp)
+}
+
+/// Up to two connections with two channels each.
+fn pool_config() -> PoolConfig {
+    PoolConfig {
+        max_connections: 2,
+        channels_per_connection: 2,
+        supervisor: SupervisorConfig {
+            backoff: BackoffConfig {
+                initial: Duration::from_millis(10),
+                ..BackoffConfig::default()
+            },
+            ..SupervisorConfig::default()
+        },
+    }
+}
+
+fn start_pool(server: &TestServer) -> ClientPool {
+    ClientPool::start(
+        factory(),
+        "127.0.0.1",
+        server.port(),
+        "bot",
+        "token",
+        pool_config(),
+    )
+}
+
+/// Polls the pool until its connections hold as many channels as `sizes`.
+async fn wait_for_sizes(pool: &mut ClientPool, sizes: &[usize]) -> Vec<Vec<String>> {
+    for _ in 0..200 {
+        let connections = pool.connections().await.unwrap();
+        if connections.iter().map(Vec::len).eq(sizes.iter().copied()) {
+            return connections;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("Connections never held {:?} channels", sizes);
+}
+
+#[tokio::test]
+async fn channels_move_off_a_dropped_connection() {
+    let server = TestServer::start().await.unwrap();
+    let mut pool = start_pool(&server);
+    let (listener, mut events) = mpsc::channel(16);
+    pool.add_event_listener(listener, SlowSubscriberPolicy::DropNewest)
+        .unwrap();
+
+    for channel in &["a", "b", "c"] {
+        pool.join(channel).await.unwrap();
+    }
+    assert_eq!(
+        pool.connections().await.unwrap(),
+        vec![vec!["a", "b"], vec!["c"]]
+    );
+    eventually(|| server.members("c") == vec!["bot"]).await;
+    eventually(|| server.members("a") == vec!["bot"]).await;
+
+    // The connection holding a and b drops. The other one has room for one of them.
+    server.disconnect_in("a", "bot");
+    let connections = wait_for_sizes(&mut pool, &[1, 2]).await;
+    let moved = connections[1]
+        .iter()
+        .find(|channel| *channel != "c")
+        .cloned()
+        .expect("A channel was moved");
+    eventually(|| server.members(&moved) == vec!["bot"]).await;
+
+    server.say(&moved, "someone", "still here?");
+    loop {
+        if let Event::Chat(chat) = events.next().await.unwrap() {
+            assert_eq!(chat.channel, moved);
+            break;
+        }
+    }
+
+    pool.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn channel_names_ignore_case() {
+    let server = TestServer::start().await.unwrap();
+    let mut pool = start_pool(&server);
+
+    pool.join("Room").await.unwrap();
+    pool.join("room").await.unwrap();
+    assert_eq!(pool.connections().await.unwrap(), vec![vec!["room"]]);
+    pool.part("ROOM").await.unwrap();
+    assert_eq!(
+        pool.connections().await.unwrap(),
+        vec![Vec::<String>::new()]
+    );
+
+    pool.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn recovered_connections_take_channels_back() {
+    let server = TestServer::start().await.unwrap();
+    let mut pool = start_pool(&server);
+    let (listener, mut pool_events) = mpsc::channel(16);
+    pool.add_pool_listener(listener).unwrap();
+
+    // Both connections fill up, so the fifth channel overloads the first.
+    for channel in &["a", "b", "c", "d", "e"] {
+        pool.join(channel).await.unwrap();
+    }
+    assert_eq!(
+        pool.connections().await.unwrap(),
+        vec![vec!["a", "b", "e"], vec!["c", "d"]]
+    );
+    pool.part("d").await.unwrap();
+    eventually(|| server.members("c") == vec!["bot"]).await;
+
+    // Nothing has room for c while its connection is down, and once it is back, that connection
+    // takes a channel from the overloaded one.
+    server.disconnect_in("c", "bot");
+    let connections = wait_for_sizes(&mut pool, &[2, 2]).await;
+    assert!(connections[1].contains(&"c".to_string()));
+    let moved = connections[1]
+        .iter()
+        .find(|channel| *channel != "c")
+        .cloned()
+        .unwrap();
+    eventually(|| server.members(&moved) == vec!["bot"]).await;
+
+    // None of that was a problem worth reporting.
+    assert!(pool_events.try_next().is_err());
+
+    pool.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn failing_connections_are_not_multiplied() {
+    // Nothing listens on the port once the listener is dropped.
+    let port = {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    };
+    let config = PoolConfig {
+        max_connections: 4,
+        ..pool_config()
+    };
+    let mut pool = ClientPool::start(factory(), "127.0.0.1", port, "bot", "token", config);
+
+    pool.join("a").await.unwrap();
+    pool.join("b").await.unwrap();
+    // Plenty of time for several failed attempts.
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(pool.connections().await.unwrap(), vec![vec!["a", "b"]]);
+
+    pool.close().await.unwrap();
+}
@@ -0,0 +1,119 @@
+use futures::channel::mpsc;
+use futures::prelude::*;
+use minibot_irc::client::{ClientFactory, DisconnectReason};
+use minibot_irc::connection::Transport;
+use minibot_irc::event::{Event, SlowSubscriberPolicy};
+use minibot_irc::supervisor::{BackoffConfig, ConnectionState, SupervisedClient, SupervisorConfig};
+use minibot_irc_testserver::TestServer;
+use std::time::Duration;
+
+fn factory() -> ClientFactory {
+    ClientFactory::create()
+        .unwrap()
+        .with_transport(Transport::Tcp)
+}
+
+fn config() -> SupervisorConfig {
+    SupervisorConfig {
+        backoff: BackoffConfig {
+            initial: Duration::from_millis(10),
+            ..BackoffConfig::default()
+        },
+        ..SupervisorConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn channels_are_rejoined_after_a_drop() {
+    let mut server = TestServer::start().await.unwrap();
+    let mut client = SupervisedClient::start(
+        factory(),
+        "127.0.0.1",
+        server.port(),
+        "bot",
+        "token",
+        config(),
+    );
+
+    client.join("room").await.unwrap();
+    server.wait_for("JOIN").await.unwrap();
+    assert_eq!(server.members("room"), vec!["bot"]);
+
+    server.disconnect("bot");
+    let rejoined = server.wait_for("JOIN").await.unwrap();
+    assert_eq!(rejoined.nick.as_deref(), Some("bot"));
+    assert_eq!(server.members("room"), vec!["bot"]);
+
+    client.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn requests_are_served_while_listeners_are_full() {
+    let mut server = TestServer::start().await.unwrap();
+    let mut client = SupervisedClient::start(
+        factory(),
+        "127.0.0.1",
+        server.port(),
+        "bot",
+        "token",
+        config(),
+    );
+    // Never read from.
+    let (listener, events) = mpsc::channel::<Event>(1);
+    client
+        .add_event_listener(listener, SlowSubscriberPolicy::Block)
+        .unwrap();
+
+    client.join("room").await.unwrap();
+    server.wait_for("JOIN").await.unwrap();
+    for i in 0..64 {
+        server.say("room", "someone", &format!("message {}", i));
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    tokio::time::timeout(Duration::from_secs(5), client.part("room"))
+        .await
+        .expect("the request was not served")
+        .unwrap();
+    server.wait_for("PART").await.unwrap();
+
+    // Closing waits for the events already read to be delivered.
+    drop(events);
+    client.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn stalled_logins_time_out() {
+    // Accepts connections, and then never says anything.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let accept = tokio::spawn(async move {
+        let mut accepted = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            accepted.push(stream);
+        }
+    });
+
+    let config = SupervisorConfig {
+        connect_timeout: Duration::from_millis(50),
+        ..config()
+    };
+    let mut client = SupervisedClient::start(factory(), "127.0.0.1", port, "bot", "token", config);
+    let (listener, mut states) = mpsc::channel(16);
+    client.add_state_listener(listener).unwrap();
+
+    loop {
+        if let ConnectionState::Disconnected(DisconnectReason::Error(_)) =
+            states.next().await.unwrap()
+        {
+            break;
+        }
+    }
+    assert!(matches!(
+        states.next().await,
+        Some(ConnectionState::Waiting(_))
+    ));
+
+    client.close().await.unwrap();
+    accept.abort();
+}
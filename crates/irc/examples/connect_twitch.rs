@@ -30,7 +30,10 @@ async fn main() -> anyhow::Result<()> {
                 Command::from_name("PRIVMSG"),
                 &["#ludofex", "Hello, World!"],
             ),
-            Message::from_command_params(Command::from_name("PRIVMSG"), &["#ludofex", "/me waves"]),
+            Message::from_command_params(
+                Command::from_name("PRIVMSG"),
+                &[&b"#ludofex"[..], minibot_irc_raw::ctcp::encode_action("waves").as_ref()],
+            ),
         ];
 
         let connector = minibot_irc::connection::IrcConnector::new()?;
@@ -0,0 +1,376 @@
+//! IRCv3 capability negotiation.
+//!
+//! `CapNegotiator` is fed the server's `CAP` messages and returns the messages to send back. It
+//! does no I/O itself. The same negotiator is kept for the life of the connection, so that caps
+//! announced later with `CAP NEW` are requested, and ones withdrawn with `CAP DEL` are forgotten.
+
+use minibot_byte_string::ByteString;
+use minibot_irc_raw::{BuildError, KnownCommand, Message, MessageBuilder};
+use std::collections::{BTreeMap, BTreeSet};
+
+pub const TWITCH_TAGS: &str = "twitch.tv/tags";
+pub const TWITCH_COMMANDS: &str = "twitch.tv/commands";
+pub const TWITCH_MEMBERSHIP: &str = "twitch.tv/membership";
+pub const SASL: &str = "sasl";
+
+#[derive(thiserror::Error, Debug)]
+pub enum CapError {
+    #[error("Required capability {0:?} is not offered by the server")]
+    Unavailable(String),
+
+    #[error("Server rejected required capabilities {0:?}")]
+    Rejected(Vec<String>),
+
+    #[error("Malformed CAP message: {0}")]
+    Malformed(String),
+
+    #[error(transparent)]
+    InvalidMessage(#[from] BuildError),
+}
+
+type Result<T> = std::result::Result<T, CapError>;
+
+/// The caps a client asks for. Required caps fail the negotiation if the server doesn't offer or
+/// rejects them. Optional caps are requested if offered, and silently skipped otherwise.
+#[derive(Clone, Debug, Default)]
+pub struct CapConfig {
+    // Cap name, and whether it is required.
+    caps: Vec<(String, bool)>,
+}
+
+impl CapConfig {
+    pub fn new() -> Self {
+        CapConfig::default()
+    }
+
+    /// The caps the bot needs on Twitch. Membership is optional, as Twitch only sends JOIN and
+    /// PART for it in small channels anyway.
+    pub fn twitch() -> Self {
+        CapConfig::new()
+            .require(TWITCH_TAGS)
+            .require(TWITCH_COMMANDS)
+            .request(TWITCH_MEMBERSHIP)
+    }
+
+    /// Common IRCv3 caps for other networks, all optional. SASL is requested when logging in
+    /// with it.
+    pub fn ircv3() -> Self {
+        CapConfig::new()
+            .request("message-tags")
+            .request("server-time")
+            .request("multi-prefix")
+    }
+
+    pub fn require(mut self, cap: impl Into<String>) -> Self {
+        self.caps.push((cap.into(), true));
+        self
+    }
+
+    pub fn request(mut self, cap: impl Into<String>) -> Self {
+        self.caps.push((cap.into(), false));
+        self
+    }
+
+    fn is_required(&self, cap: &str) -> bool {
+        self.caps
+            .iter()
+            .any(|(name, required)| name == cap && *required)
+    }
+
+    fn is_wanted(&self, cap: &str) -> bool {
+        self.caps.iter().any(|(name, _)| name == cap)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum State {
+    Listing,
+    Requesting,
+    Done,
+}
+
+pub struct CapNegotiator {
+    config: CapConfig,
+    state: State,
+    // Caps offered by the server, with their values (e.g. `sasl=PLAIN,EXTERNAL`).
+    available: BTreeMap<String, Option<String>>,
+    // Caps that have been requested but not yet acknowledged or rejected.
+    pending: BTreeSet<String>,
+    enabled: BTreeSet<String>,
+}
+
+fn param_str(param: &ByteString) -> Result<&str> {
+    std::str::from_utf8(param.as_ref())
+        .map_err(|_| CapError::Malformed("Parameter is not UTF-8".to_string()))
+}
+
+/// Strips the value from a cap listed as `name=value`.
+fn cap_name(cap: &str) -> &str {
+    match cap.find('=') {
+        Some(p) => &cap[..p],
+        None => cap,
+    }
+}
+
+pub(crate) fn cap_request<T: IntoIterator<Item = S>, S: AsRef<str>>(caps: T) -> Result<Message> {
+    let caps = caps
+        .into_iter()
+        .map(|cap| cap.as_ref().to_string())
+        .collect::<Vec<_>>();
+    Ok(MessageBuilder::named("CAP")
+        .param("REQ")
+        .param(caps.join(" "))
+        .build()?)
+}
+
+impl CapNegotiator {
+    pub fn new(config: CapConfig) -> Self {
+        CapNegotiator {
+            config,
+            state: State::Listing,
+            available: BTreeMap::new(),
+            pending: BTreeSet::new(),
+            enabled: BTreeSet::new(),
+        }
+    }
+
+    /// The message that starts negotiation.
+    pub fn start(&self) -> Message {
+        Message::from_named_command_params("CAP", ["LS", "302"])
+    }
+
+    /// True once every requested cap has been acknowledged or rejected, and `CAP END` can be
+    /// sent.
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    pub fn is_enabled(&self, cap: &str) -> bool {
+        self.enabled.contains(cap)
+    }
+
+    pub fn enabled(&self) -> impl Iterator<Item = &str> {
+        self.enabled.iter().map(String::as_str)
+    }
+
+    /// The value the server listed for a cap, if it is offered and has one.
+    pub fn value(&self, cap: &str) -> Option<&str> {
+        self.available.get(cap)?.as_deref()
+    }
+
+    /// Handles a `CAP` message from the server, returning the messages to send in reply. Other
+    /// messages are ignored.
+    pub fn handle(&mut self, msg: &Message) -> Result<Vec<Message>> {
+        if !msg.has_command(KnownCommand::Cap) {
+            return Ok(Vec::new());
+        }
+
+        // The first parameter is our nick, or `*` before we have one.
+        let (subcommand, caps, is_continued) = match msg.params() {
+            [_, sub, caps] => (param_str(sub)?, param_str(caps)?, false),
+            [_, sub, more, caps] if more.eq_bytes(b"*") => {
+                (param_str(sub)?, param_str(caps)?, true)
+            }
+            _ => return Err(CapError::Malformed(format!("{:?}", msg))),
+        };
+        let caps = caps.split_whitespace();
+
+        match subcommand {
+            "LS" => {
+                self.add_available(caps);
+                if is_continued || self.state != State::Listing {
+                    return Ok(Vec::new());
+                }
+                self.request_initial()
+            }
+            "NEW" => {
+                let new_caps = caps.collect::<Vec<_>>();
+                self.add_available(new_caps.iter().copied());
+                self.request_new(new_caps.into_iter().map(cap_name))
+            }
+            "DEL" => {
+                for cap in caps {
+                    self.available.remove(cap);
+                    self.enabled.remove(cap);
+                }
+                Ok(Vec::new())
+            }
+            "ACK" => {
+                for cap in caps {
+                    match cap.strip_prefix('-') {
+                        Some(cap) => {
+                            self.enabled.remove(cap);
+                            self.pending.remove(cap);
+                        }
+                        None => {
+                            self.enabled.insert(cap.to_string());
+                            self.pending.remove(cap);
+                        }
+                    }
+                }
+                self.check_done();
+                Ok(Vec::new())
+            }
+            "NAK" => {
+                let mut rejected = Vec::new();
+                for cap in caps {
+                    self.pending.remove(cap);
+                    if self.config.is_required(cap) {
+                        rejected.push(cap.to_string());
+                    }
+                }
+                // Once negotiation is over, losing a cap that came from `CAP NEW` isn't fatal.
+                if !rejected.is_empty() && self.state != State::Done {
+                    return Err(CapError::Rejected(rejected));
+                }
+                self.check_done();
+                Ok(Vec::new())
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    fn add_available<'a>(&mut self, caps: impl Iterator<Item = &'a str>) {
+        for cap in caps {
+            let value = cap.find('=').map(|p| cap[p + 1..].to_string());
+            self.available.insert(cap_name(cap).to_string(), value);
+        }
+    }
+
+    fn request_initial(&mut self) -> Result<Vec<Message>> {
+        let mut required = Vec::new();
+        let mut optional = Vec::new();
+        for (cap, is_required) in &self.config.caps {
+            if self.available.contains_key(cap) {
+                if *is_required {
+                    required.push(cap.clone());
+                } else {
+                    optional.push(cap.clone());
+                }
+            } else if *is_required {
+                return Err(CapError::Unavailable(cap.clone()));
+            }
+        }
+
+        // A NAK rejects a whole request, so optional caps are requested one at a time to keep one
+        // unsupported cap from taking the others down with it.
+        let mut requests = Vec::new();
+        if !required.is_empty() {
+            requests.push(cap_request(&required)?);
+        }
+        for cap in &optional {
+            requests.push(cap_request([cap])?);
+        }
+
+        self.pending.extend(required);
+        self.pending.extend(optional);
+        self.state = State::Requesting;
+        self.check_done();
+        Ok(requests)
+    }
+
+    fn request_new<'a>(&mut self, caps: impl Iterator<Item = &'a str>) -> Result<Vec<Message>> {
+        let mut requests = Vec::new();
+        for cap in caps {
+            if self.config.is_wanted(cap)
+                && !self.enabled.contains(cap)
+                && !self.pending.contains(cap)
+            {
+                requests.push(cap_request([cap])?);
+                self.pending.insert(cap.to_string());
+            }
+        }
+        Ok(requests)
+    }
+
+    fn check_done(&mut self) {
+        if self.state == State::Requesting && self.pending.is_empty() {
+            self.state = State::Done;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use minibot_irc_raw::MessageRef;
+
+    fn parse(line: &str) -> Message {
+        MessageRef::parse(line.as_bytes())
+            .unwrap()
+            .to_owned()
+            .unwrap()
+    }
+
+    fn params(msg: &Message) -> Vec<String> {
+        msg.params()
+            .iter()
+            .map(|p| String::from_utf8(p.as_ref().to_vec()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn negotiates_across_multiple_lines() {
+        let mut caps = CapNegotiator::new(CapConfig::twitch().request("other"));
+        assert!(caps
+            .handle(&parse("CAP * LS * :twitch.tv/tags twitch.tv/commands"))
+            .unwrap()
+            .is_empty());
+
+        let requests = caps
+            .handle(&parse("CAP * LS :twitch.tv/membership sasl=PLAIN"))
+            .unwrap();
+        assert_eq!(
+            requests.iter().map(params).collect::<Vec<_>>(),
+            vec![
+                vec!["REQ", "twitch.tv/tags twitch.tv/commands"],
+                vec!["REQ", "twitch.tv/membership"],
+            ]
+        );
+        assert_eq!(caps.value("sasl"), Some("PLAIN"));
+
+        caps.handle(&parse("CAP * ACK :twitch.tv/tags twitch.tv/commands"))
+            .unwrap();
+        assert!(!caps.is_done());
+        caps.handle(&parse("CAP * NAK :twitch.tv/membership"))
+            .unwrap();
+        assert!(caps.is_done());
+        assert_eq!(
+            caps.enabled().collect::<Vec<_>>(),
+            vec!["twitch.tv/commands", "twitch.tv/tags"]
+        );
+    }
+
+    #[test]
+    fn fails_on_missing_or_rejected_required_caps() {
+        let mut caps = CapNegotiator::new(CapConfig::twitch());
+        assert!(matches!(
+            caps.handle(&parse("CAP * LS :twitch.tv/tags")),
+            Err(CapError::Unavailable(cap)) if cap == TWITCH_COMMANDS
+        ));
+
+        let mut caps = CapNegotiator::new(CapConfig::new().require("a"));
+        caps.handle(&parse("CAP * LS :a")).unwrap();
+        assert!(matches!(
+            caps.handle(&parse("CAP * NAK :a")),
+            Err(CapError::Rejected(_))
+        ));
+    }
+
+    #[test]
+    fn follows_new_and_del() {
+        let mut caps = CapNegotiator::new(CapConfig::new().request("a").request("b"));
+        caps.handle(&parse("CAP * LS :a")).unwrap();
+        caps.handle(&parse("CAP nick ACK :a")).unwrap();
+        assert!(caps.is_done());
+
+        let requests = caps.handle(&parse("CAP nick NEW :b=1 c")).unwrap();
+        assert_eq!(
+            requests.iter().map(params).collect::<Vec<_>>(),
+            vec![vec!["REQ", "b"]]
+        );
+        caps.handle(&parse("CAP nick ACK :b")).unwrap();
+        caps.handle(&parse("CAP nick DEL :a")).unwrap();
+        assert_eq!(caps.enabled().collect::<Vec<_>>(), vec!["b"]);
+    }
+}
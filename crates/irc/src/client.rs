@@ -1,38 +1,21 @@
-use crate::connection::{IrcConnector, IrcSink, IrcStream};
+use crate::cap::{CapConfig, CapError, CapNegotiator};
+use crate::connection::{IrcConnector, IrcSink, IrcStream, Transport};
+use crate::event::{Event, SlowSubscriberPolicy};
+use crate::futures_util::event_sink::EventSink;
+use crate::keepalive::{run_keepalive_loop, KeepaliveConfig, Liveness};
+use crate::network::{ISupport, NetworkProfile, Registered, Registration};
+use crate::outbound::{ChatLimit, Priority, RepeatGuard};
+use crate::rate_limit::{self, RateLimitConfig, RateLimiter};
+use crate::room_state::events::{PrimaryEvent, RoomEvent};
+use crate::room_state::{ConnectionState, MembersList, UserState};
 use futures::channel::mpsc;
 use futures::prelude::*;
 use futures::{join, select};
-use minibot_byte_string::{ByteStr, ByteString};
-use minibot_irc_raw::Message;
-
-struct Sender<'a>(&'a mut IrcSink);
-
-impl Sender<'_> {
-    pub async fn send_n<T: IntoIterator<Item = S>, S: AsRef<[u8]>>(
-        &mut self,
-        cmd: &str,
-        params: T,
-    ) -> ClientResult<()> {
-        self.0
-            .send(Message::from_named_command_params(cmd, params))
-            .await?;
-        Ok(())
-    }
-}
-
-fn join_bytes<T: IntoIterator<Item = S>, S: AsRef<[u8]>>(iter: T, connector: &[u8]) -> Vec<u8> {
-    let mut result = Vec::new();
-    let mut first = true;
-    for item in iter.into_iter() {
-        if first {
-            first = false;
-        } else {
-            result.extend(connector);
-        }
-        result.extend(item.as_ref());
-    }
-    result
-}
+use minibot_byte_string::ByteString;
+use minibot_irc_raw::{ctcp, BuildError, KnownCommand, Message, MessageBuilder};
+use rand::Rng;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 #[derive(thiserror::Error, Debug)]
 pub enum ClientError {
@@ -48,103 +31,150 @@ pub enum ClientError {
     #[error("Client has already been closed")]
     AlreadyClosed,
 
+    #[error("Not connected to the server")]
+    NotConnected,
+
+    #[error("Timed out connecting and logging in")]
+    ConnectTimeout,
+
+    #[error("Not in channel {0}")]
+    NotInChannel(String),
+
+    #[error("Can't send chat or whispers while logged in anonymously")]
+    Anonymous,
+
+    #[error("Not supported: {0}")]
+    Unsupported(&'static str),
+
     #[error(transparent)]
     Irc(#[from] minibot_irc_raw::Error),
-}
 
-pub struct ClientFactory {
-    connector: IrcConnector,
+    #[error(transparent)]
+    InvalidMessage(#[from] BuildError),
+
+    #[error(transparent)]
+    Login(#[from] LoginError),
 }
 
-async fn initialize_irc_channel(
-    user: &str,
-    token: &str,
-    irc_read: &mut IrcStream,
-    irc_write: &mut IrcSink,
-) -> ClientResult<()> {
-    let mut irc_sender = Sender(irc_write);
-    irc_sender.send_n("CAP", &["LS", "302"]).await?;
+#[derive(thiserror::Error, Debug)]
+pub enum LoginError {
+    #[error("Authentication failed: {0}")]
+    AuthenticationFailed(String),
 
-    let mut caps = Vec::new();
-    loop {
-        let message = irc_read.next().await.ok_or(ClientError::UnexpectedEnd)??;
-        assert!(
-            message.has_named_command("CAP"),
-            "Unexpected message: {:?}",
-            message
-        );
-        let params = message.params();
-        assert!(params.len() >= 2);
-        if params.len() == 2 {
-            assert!(params[0].eq_bytes(b"LS"));
-            let caps_list = &params[1];
-            caps.extend(caps_list.split_spaces().map(ByteStr::to_byte_string));
-        } else if params.len() == 3 {
-            assert!(params[0].eq_bytes(b"*"));
-            assert!(params[1].eq_bytes(b"LS"));
-            let caps_list = &params[2];
-            caps.extend(caps_list.split_spaces().map(ByteStr::to_byte_string));
-            break;
-        } else {
-            panic!("Unexpected message: {:?}", message);
-        }
-    }
+    #[error("Server closed the connection: {0}")]
+    Closed(String),
+
+    #[error("The server doesn't support SASL {0}")]
+    SaslUnavailable(String),
 
-    eprintln!("Got caps: {:?}", caps);
+    #[error("Every nick we tried was taken, the last being {0}")]
+    NickUnavailable(String),
 
-    // Check that the caps are the expected set.
+    #[error(transparent)]
+    Cap(#[from] CapError),
+}
 
-    let ack_args = join_bytes(caps, b" ");
+// The NOTICE texts Twitch sends when it rejects a PASS.
+const TWITCH_AUTH_FAILURES: &[&str] = &["Login authentication failed", "Improperly formatted auth"];
 
-    irc_sender.send_n("CAP", &[b"REQ", &ack_args[..]]).await?;
+fn last_param_text(message: &Message) -> String {
+    message
+        .params()
+        .last()
+        .map(|p| String::from_utf8_lossy(p.as_ref()).into_owned())
+        .unwrap_or_default()
+}
 
-    let mut caps = Vec::new();
-    loop {
-        let message = irc_read.next().await.ok_or(ClientError::UnexpectedEnd)??;
-        assert!(
-            message.has_named_command("CAP"),
-            "Unexpected message: {:?}",
-            message
-        );
-        let params = message.params();
-        assert!(params.len() >= 2);
-        if params.len() == 2 {
-            assert!(params[0].eq_bytes(b"ACK"));
-            let caps_list = &params[1];
-            caps.extend(caps_list.split_spaces().map(ByteStr::to_byte_string));
-        } else if params.len() == 3 {
-            assert!(params[0].eq_bytes(b"*"));
-            assert!(params[1].eq_bytes(b"ACK"));
-            let caps_list = &params[2];
-            caps.extend(caps_list.split_spaces().map(ByteStr::to_byte_string));
-            break;
-        } else {
-            panic!("Unexpected message: {:?}", message);
+fn login_failure(message: &Message) -> Option<LoginError> {
+    match message.known_command()? {
+        KnownCommand::Notice => {
+            let text = last_param_text(message);
+            if TWITCH_AUTH_FAILURES.iter().any(|f| text.contains(f)) {
+                Some(LoginError::AuthenticationFailed(text))
+            } else {
+                None
+            }
         }
+        KnownCommand::ErrPasswdMismatch => {
+            Some(LoginError::AuthenticationFailed(last_param_text(message)))
+        }
+        KnownCommand::Error => Some(LoginError::Closed(last_param_text(message))),
+        _ => None,
     }
+}
 
-    irc_sender
-        .send_n("PASS", &[&format!("oauth:{}", token)])
-        .await?;
-    irc_sender.send_n("NICK", &[user]).await?;
-    irc_sender.send_n("CAP", &[b"END"]).await?;
+/// Reads the next message during login, turning login failures into errors.
+async fn next_login_message(irc_read: &mut IrcStream) -> ClientResult<Message> {
     loop {
-        let message = irc_read.next().await.ok_or(ClientError::UnexpectedEnd)??;
-        if message.has_num_command(376) {
-            break;
+        match irc_read.next().await.ok_or(ClientError::UnexpectedEnd)? {
+            Ok(message) => match login_failure(&message) {
+                Some(e) => return Err(e.into()),
+                None => return Ok(message),
+            },
+            // There is nowhere to report a bad line before the client exists, so it is skipped.
+            Err(e) if e.is_recoverable() => {}
+            Err(e) => return Err(e.into()),
         }
     }
+}
+
+#[derive(Clone)]
+pub struct ClientFactory {
+    connector: IrcConnector,
+    network: NetworkProfile,
+    caps: CapConfig,
+    keepalive: KeepaliveConfig,
+    rate_limits: RateLimitConfig,
+    // Set if every client from this factory shares one limiter.
+    shared_limiter: Option<Arc<Mutex<RateLimiter>>>,
+}
 
-    Ok(())
+#[derive(Clone, Debug)]
+pub enum DisconnectReason {
+    /// The server closed the connection.
+    Closed,
+    /// The server didn't answer our PING in time.
+    PingTimeout,
+    /// The server asked us to reconnect, usually because it is about to restart.
+    Reconnect,
+    Error(String),
+}
+
+#[derive(Clone, Debug)]
+pub enum ConnectionEvent {
+    Disconnected(DisconnectReason),
+    /// A message from the server was skipped because it couldn't be parsed or made no sense. The
+    /// connection carries on.
+    IgnoredMessage(String),
+}
+
+/// Logs in, answering the server until registration is done.
+async fn register(
+    mut registration: Registration,
+    irc_read: &mut IrcStream,
+    irc_write: &mut IrcSink,
+) -> ClientResult<Registered> {
+    for message in registration.start()? {
+        irc_write.send(message).await?;
+    }
+    while !registration.is_done() {
+        let message = next_login_message(irc_read).await?;
+        for reply in registration.handle(&message)? {
+            irc_write.send(reply).await?;
+        }
+    }
+    Ok(registration.finish())
 }
 
 async fn run_input_loop(
     mut input_stream: impl Stream<Item = Message> + Unpin,
     mut ping_stream: mpsc::Receiver<ByteString>,
+    mut control_stream: mpsc::Receiver<Message>,
     mut irc_write: IrcSink,
 ) {
     let mut read_op = input_stream.next().fuse();
     let mut ping_read_op = ping_stream.next().fuse();
+    let mut control_read_op = control_stream.next().fuse();
     'outer: loop {
         select! {
             new_msg = read_op => {
@@ -164,49 +194,169 @@ async fn run_input_loop(
             new_ping = ping_read_op => {
                 match new_ping {
                     Some(new_ping) => {
-                        Message::from_named_command_params("PONG", &[&new_ping]);
+                        let pong = Message::from_named_command_params("PONG", [&new_ping]);
+                        if irc_write.send(pong).await.is_err() {
+                            break 'outer;
+                        }
+                        ping_read_op = ping_stream.next().fuse();
+                    }
+                    None => break 'outer,
+                }
+            }
+            control_msg = control_read_op => {
+                match control_msg {
+                    Some(control_msg) => {
+                        if irc_write.send(control_msg).await.is_err() {
+                            break 'outer;
+                        }
+                        control_read_op = control_stream.next().fuse();
                     }
                     None => break 'outer,
                 }
             }
         };
     }
+    // Closing our side tells the server we're done, so that it closes the connection and the
+    // read side ends too.
+    let _ = irc_write.close().await;
+}
+
+/// State that is kept up to date from the messages the server sends.
+#[derive(Clone)]
+struct TrackedState {
+    caps: Arc<Mutex<CapNegotiator>>,
+    limiter: Arc<Mutex<RateLimiter>>,
+    rooms: Arc<futures::lock::Mutex<ConnectionState>>,
 }
 
 async fn run_output_loop(
     mut irc_read: IrcStream,
     mut ping_sink: mpsc::Sender<ByteString>,
-    mut output_sink: mpsc::Sender<Message>,
-) {
+    mut control_sink: mpsc::Sender<Message>,
+    mut liveness_sink: mpsc::Sender<Liveness>,
+    mut events_sink: mpsc::Sender<ConnectionEvent>,
+    tracked: TrackedState,
+    mut output_sink: mpsc::Sender<Event>,
+) -> DisconnectReason {
+    // Reports are dropped rather than holding up the read loop when no one keeps up with them.
+    let mut report = move |error: String| {
+        let _ = events_sink.try_send(ConnectionEvent::IgnoredMessage(error));
+    };
+    let mut reason = DisconnectReason::Closed;
     while let Some(msg_or_err) = irc_read.next().await {
         match msg_or_err {
             Ok(msg) => {
-                if msg.has_named_command("PING") {
-                    if let Err(_) = ping_sink.send(msg.params()[0].to_byte_string()).await {
+                // Any traffic shows the connection is alive, so a dropped notification is fine.
+                let _ = liveness_sink.try_send(Liveness::Activity);
+
+                let token = msg.params().last().map(|p| p.to_byte_string());
+                if msg.has_command(KnownCommand::Ping) {
+                    let token = token.unwrap_or_else(|| ByteString::from_slice(b""));
+                    if ping_sink.send(token).await.is_err() {
                         break;
                     }
+                } else if msg.has_command(KnownCommand::Reconnect) {
+                    reason = DisconnectReason::Reconnect;
+                    break;
+                } else if msg.has_command(KnownCommand::Pong) {
+                    if let Some(token) = token {
+                        if liveness_sink.send(Liveness::Pong(token)).await.is_err() {
+                            break;
+                        }
+                    }
+                } else if msg.has_command(KnownCommand::Cap) {
+                    // The lock is released before any replies are sent.
+                    let replies = tracked.caps.lock().unwrap().handle(&msg);
+                    match replies {
+                        Ok(replies) => {
+                            let mut replies = stream::iter(replies).map(Ok);
+                            if control_sink.send_all(&mut replies).await.is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => report(e.to_string()),
+                    }
                 } else {
-                    if let Err(_) = output_sink.send(msg).await {
+                    tracked.limiter.lock().unwrap().observe(&msg);
+                    let event = Event::from_message(msg);
+                    tracked.rooms.lock().await.handle_event(&event);
+                    if output_sink.send(event).await.is_err() {
                         break;
                     }
                 }
             }
+            Err(e) if e.is_recoverable() => report(e.to_string()),
             Err(e) => {
-                println!("{}", e);
+                reason = DisconnectReason::Error(e.to_string());
                 break;
             }
         }
     }
-    let _ = join!(ping_sink.close(), output_sink.close());
+    let _ = join!(
+        ping_sink.close(),
+        control_sink.close(),
+        liveness_sink.close(),
+        output_sink.close()
+    );
+    reason
 }
 
 impl ClientFactory {
     pub fn create() -> ClientResult<Self> {
         Ok(ClientFactory {
             connector: IrcConnector::new()?,
+            network: NetworkProfile::Twitch,
+            caps: CapConfig::twitch(),
+            keepalive: KeepaliveConfig::default(),
+            rate_limits: RateLimitConfig::default(),
+            shared_limiter: None,
         })
     }
 
+    /// Sets how to connect to the server. Defaults to TLS over TCP.
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.connector = IrcConnector::with_transport(transport);
+        self
+    }
+
+    pub fn with_keepalive(mut self, keepalive: KeepaliveConfig) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Sets the limits outgoing messages are held to. Defaults to the network's
+    /// `default_rate_limits()`, which on Twitch are the limits for a regular account.
+    pub fn with_rate_limits(mut self, rate_limits: RateLimitConfig) -> Self {
+        self.rate_limits = rate_limits;
+        if self.shared_limiter.is_some() {
+            self = self.with_shared_rate_limits();
+        }
+        self
+    }
+
+    /// Makes every client from this factory, and from its clones, count against the same rate
+    /// limits. Twitch's limits are per account, so clients that log in as the same user at the
+    /// same time should share them.
+    pub fn with_shared_rate_limits(mut self) -> Self {
+        self.shared_limiter = Some(Arc::new(Mutex::new(RateLimiter::new(self.rate_limits))));
+        self
+    }
+
+    /// Sets the network to log in to, and the caps to negotiate and the rate limits to the
+    /// network's defaults. Defaults to Twitch.
+    pub fn with_network(mut self, network: NetworkProfile) -> Self {
+        self.caps = network.default_caps();
+        let rate_limits = network.default_rate_limits();
+        self.network = network;
+        self.with_rate_limits(rate_limits)
+    }
+
+    /// Sets the caps to negotiate. Defaults to the network's `default_caps()`.
+    pub fn with_caps(mut self, caps: CapConfig) -> Self {
+        self.caps = caps;
+        self
+    }
+
     pub async fn connect(
         &self,
         host: &str,
@@ -214,38 +364,341 @@ impl ClientFactory {
         user: &str,
         token: &str,
     ) -> ClientResult<Client> {
-        let (mut irc_read, mut irc_write) = self.connector.connect(host, port).await?;
-        initialize_irc_channel(user, token, &mut irc_read, &mut irc_write).await?;
-        Ok(Client::new(irc_read, irc_write))
+        let (irc_read, irc_write) = self.connector.connect(host, port).await?;
+        self.connect_with(irc_read, irc_write, user, token).await
+    }
+
+    /// Logs in over an existing stream and sink, such as ones wrapped by
+    /// `transcript::record()` or produced by `Transcript::replay()`.
+    pub async fn connect_with(
+        &self,
+        irc_read: IrcStream,
+        irc_write: IrcSink,
+        user: &str,
+        token: &str,
+    ) -> ClientResult<Client> {
+        self.login(irc_read, irc_write, user, Some(token)).await
+    }
+
+    /// Logs in without an account, as a random `justinfanNNNNN` user. The connection can join
+    /// channels and read them, but sending chat or whispers fails with `ClientError::Anonymous`.
+    pub async fn connect_anonymous(&self, host: &str, port: u16) -> ClientResult<Client> {
+        let (irc_read, irc_write) = self.connector.connect(host, port).await?;
+        self.connect_anonymous_with(irc_read, irc_write).await
+    }
+
+    /// Logs in anonymously over an existing stream and sink.
+    pub async fn connect_anonymous_with(
+        &self,
+        irc_read: IrcStream,
+        irc_write: IrcSink,
+    ) -> ClientResult<Client> {
+        let user = format!("justinfan{}", rand::thread_rng().gen_range(10000, 100000));
+        self.login(irc_read, irc_write, &user, None).await
+    }
+
+    async fn login(
+        &self,
+        mut irc_read: IrcStream,
+        mut irc_write: IrcSink,
+        user: &str,
+        token: Option<&str>,
+    ) -> ClientResult<Client> {
+        let registration = Registration::new(self.network.clone(), self.caps.clone(), user, token);
+        let registered = register(registration, &mut irc_read, &mut irc_write).await?;
+        Ok(Client::new(
+            registered,
+            self.network.clone(),
+            token.is_none(),
+            irc_read,
+            irc_write,
+            self.keepalive,
+            match &self.shared_limiter {
+                Some(limiter) => limiter.clone(),
+                None => Arc::new(Mutex::new(RateLimiter::new(self.rate_limits))),
+            },
+        ))
     }
 }
 
 pub type ClientResult<T> = Result<T, ClientError>;
 
 struct ClientInner {
-    input: mpsc::Sender<Message>,
+    user: String,
+    network: NetworkProfile,
+    anonymous: bool,
+    isupport: ISupport,
+    input: mpsc::Sender<(Priority, Message)>,
+    caps: Arc<Mutex<CapNegotiator>>,
+    latency: Arc<Mutex<Option<Duration>>>,
+    rooms: Arc<futures::lock::Mutex<ConnectionState>>,
+    // Set once the connection is lost, for listeners that are added after the event was sent.
+    disconnected: Arc<Mutex<Option<DisconnectReason>>>,
+    events_channel: EventSink<ConnectionEvent>,
+    inbound_channel: EventSink<Event>,
     handle: tokio::task::JoinHandle<()>,
 }
 
 pub struct Client(Option<ClientInner>);
 
 impl Client {
-    fn new(irc_read: IrcStream, irc_write: IrcSink) -> Self {
+    fn new(
+        registered: Registered,
+        network: NetworkProfile,
+        anonymous: bool,
+        irc_read: IrcStream,
+        irc_write: IrcSink,
+        keepalive: KeepaliveConfig,
+        limiter: Arc<Mutex<RateLimiter>>,
+    ) -> Self {
         let (input, input_stream) = mpsc::channel(3);
-        let (output_sink, _) = mpsc::channel(3);
+        let (output_sink, output_stream) = mpsc::channel(3);
+        let (mut events_sink, events_stream) = mpsc::channel(3);
+        let Registered {
+            nick: user,
+            caps,
+            isupport,
+        } = registered;
+        let caps = Arc::new(Mutex::new(caps));
+        let rooms = Arc::new(futures::lock::Mutex::new(
+            ConnectionState::new(&user).with_isupport(isupport.clone()),
+        ));
+        let tracked = TrackedState {
+            caps: caps.clone(),
+            limiter,
+            rooms: rooms.clone(),
+        };
+        let latency = Arc::new(Mutex::new(None));
+        let loop_latency = latency.clone();
+        let disconnected = Arc::new(Mutex::new(None));
+        let loop_disconnected = disconnected.clone();
+        let network_is_twitch = network.is_twitch();
 
         let handle = tokio::spawn(async move {
+            // Only Twitch drops repeated messages.
+            let mut repeats = network_is_twitch.then(RepeatGuard::default);
             let input_stream =
-                tokio::time::throttle(std::time::Duration::from_secs_f32(5.0 / 30.0), input_stream);
+                rate_limit::limit(input_stream, tracked.limiter.clone()).map(move |msg| {
+                    match &mut repeats {
+                        Some(repeats) => repeats.apply(msg, Instant::now()),
+                        None => msg,
+                    }
+                });
+            futures::pin_mut!(input_stream);
             let (ping_sink, ping_stream) = mpsc::channel(1);
+            let (control_sink, control_stream) = mpsc::channel(3);
+            let (liveness_sink, liveness_stream) = mpsc::channel(1);
+
+            let read_side = async {
+                let output_loop = run_output_loop(
+                    irc_read,
+                    ping_sink,
+                    control_sink.clone(),
+                    liveness_sink,
+                    events_sink.clone(),
+                    tracked,
+                    output_sink,
+                )
+                .fuse();
+                let keepalive_loop =
+                    run_keepalive_loop(keepalive, liveness_stream, control_sink, loop_latency)
+                        .fuse();
+                futures::pin_mut!(output_loop, keepalive_loop);
+
+                // If the keepalive gives up, dropping the output loop closes the channels to the
+                // input loop, which then shuts down the write side.
+                let reason = select! {
+                    reason = output_loop => reason,
+                    timed_out = keepalive_loop => {
+                        if timed_out {
+                            DisconnectReason::PingTimeout
+                        } else {
+                            output_loop.await
+                        }
+                    }
+                };
+                *loop_disconnected.lock().unwrap() = Some(reason.clone());
+                let _ = events_sink
+                    .send(ConnectionEvent::Disconnected(reason))
+                    .await;
+            };
 
             join! {
-                run_input_loop(input_stream, ping_stream, irc_write),
-                run_output_loop(irc_read, ping_sink, output_sink),
+                run_input_loop(input_stream, ping_stream, control_stream, irc_write),
+                read_side,
             };
         });
 
-        Client(Some(ClientInner { input, handle }))
+        Client(Some(ClientInner {
+            user,
+            network,
+            anonymous,
+            isupport,
+            input,
+            caps,
+            latency,
+            rooms,
+            disconnected,
+            events_channel: EventSink::new(events_stream),
+            inbound_channel: EventSink::new(output_stream),
+            handle,
+        }))
+    }
+
+    /// The user we are logged in as. On a generic network, this is the nick the server gave us,
+    /// which may not be the one we asked for.
+    pub fn user(&self) -> ClientResult<&str> {
+        Ok(&self.get_inner()?.user)
+    }
+
+    /// The parameters the server sent in RPL_ISUPPORT while we logged in.
+    pub fn isupport(&self) -> ClientResult<&ISupport> {
+        Ok(&self.get_inner()?.isupport)
+    }
+
+    /// True if we logged in with `ClientFactory::connect_anonymous`.
+    pub fn is_anonymous(&self) -> ClientResult<bool> {
+        Ok(self.get_inner()?.anonymous)
+    }
+
+    /// The round trip time of the last PING the client sent, if it has been answered.
+    pub fn latency(&self) -> ClientResult<Option<Duration>> {
+        Ok(*self.get_inner()?.latency.lock().unwrap())
+    }
+
+    /// Adds a listener for connection events, such as the connection being lost. If the
+    /// connection has already been lost, the listener is told right away.
+    pub fn add_connection_listener(
+        &mut self,
+        mut listener: mpsc::Sender<ConnectionEvent>,
+    ) -> ClientResult<()> {
+        let inner = self.get_inner_mut()?;
+        // Holding the lock keeps the disconnect from landing between the check and adding the
+        // sink.
+        let disconnected = inner.disconnected.lock().unwrap();
+        match &*disconnected {
+            Some(reason) => {
+                let _ = listener.try_send(ConnectionEvent::Disconnected(reason.clone()));
+            }
+            None => inner.events_channel.add_sink(listener),
+        }
+        Ok(())
+    }
+
+    /// Adds a listener for events from the server. Events that arrive while there are no
+    /// listeners are dropped.
+    pub fn add_event_listener(
+        &mut self,
+        listener: mpsc::Sender<Event>,
+        policy: SlowSubscriberPolicy,
+    ) -> ClientResult<()> {
+        self.get_inner_mut()?
+            .inbound_channel
+            .add_sink_with_policy(listener, policy);
+        Ok(())
+    }
+
+    /// Returns a new stream of events from the server, buffering up to `capacity` events.
+    pub fn subscribe(
+        &mut self,
+        capacity: usize,
+        policy: SlowSubscriberPolicy,
+    ) -> ClientResult<mpsc::Receiver<Event>> {
+        let (listener, events) = mpsc::channel(capacity);
+        self.add_event_listener(listener, policy)?;
+        Ok(events)
+    }
+
+    /// Adds a listener for membership changes and messages in a channel. The listener first gets
+    /// the current members, if they are known. Its stream ends when we leave the channel. With
+    /// `SlowSubscriberPolicy::Block`, the events for a slow listener pile up in memory rather than
+    /// holding up the connection.
+    pub async fn add_room_listener(
+        &mut self,
+        channel: &str,
+        listener: mpsc::Sender<RoomEvent>,
+        policy: SlowSubscriberPolicy,
+    ) -> ClientResult<()> {
+        let mut rooms = self.get_inner()?.rooms.lock().await;
+        let room = rooms
+            .get_room_mut(channel)
+            .ok_or_else(|| ClientError::NotInChannel(channel.to_string()))?;
+        room.add_listener(listener, policy);
+        Ok(())
+    }
+
+    /// The members of a channel, or `None` if the server hasn't sent them yet.
+    pub async fn members(&self, channel: &str) -> ClientResult<Option<MembersList>> {
+        let rooms = self.get_inner()?.rooms.lock().await;
+        let room = rooms
+            .get_room(channel)
+            .ok_or_else(|| ClientError::NotInChannel(channel.to_string()))?;
+        Ok(room.members())
+    }
+
+    /// Adds a listener for events that aren't tied to a channel, such as whispers. As with room
+    /// listeners, a slow listener never holds up the connection.
+    pub async fn add_primary_listener(
+        &mut self,
+        listener: mpsc::Sender<PrimaryEvent>,
+        policy: SlowSubscriberPolicy,
+    ) -> ClientResult<()> {
+        self.get_inner()?
+            .rooms
+            .lock()
+            .await
+            .add_listener(listener, policy);
+        Ok(())
+    }
+
+    /// What we know about a user's roles and badges in a channel. Returns `None` if they haven't
+    /// said anything since we joined. For ourselves, this comes from USERSTATE.
+    pub async fn user_state(&self, channel: &str, user: &str) -> ClientResult<Option<UserState>> {
+        let rooms = self.get_inner()?.rooms.lock().await;
+        let room = rooms
+            .get_room(channel)
+            .ok_or_else(|| ClientError::NotInChannel(channel.to_string()))?;
+        Ok(room.user_state(user).cloned())
+    }
+
+    /// Our own state outside of any channel, from GLOBALUSERSTATE.
+    pub async fn global_user_state(&self) -> ClientResult<Option<UserState>> {
+        let rooms = self.get_inner()?.rooms.lock().await;
+        Ok(rooms.global_user_state().cloned())
+    }
+
+    fn get_inner(&self) -> ClientResult<&ClientInner> {
+        self.0.as_ref().ok_or(ClientError::AlreadyClosed)
+    }
+
+    /// The name to send for a channel. Channels are named without the `#`, as Twitch shows them,
+    /// so one is added, unless the name starts with another of the server's channel prefixes
+    /// (CHANTYPES), such as `&`.
+    fn channel_target(&self, channel: &str) -> ClientResult<String> {
+        let isupport = &self.get_inner()?.isupport;
+        if isupport.is_channel(channel) && !channel.starts_with('#') {
+            Ok(channel.to_string())
+        } else {
+            Ok(format!("#{}", channel))
+        }
+    }
+
+    /// How much text fits in a chat message to `target`.
+    fn chat_limit(&self, target: &str) -> ClientResult<ChatLimit> {
+        let inner = self.get_inner()?;
+        Ok(inner
+            .network
+            .chat_limit(&inner.user, target, &inner.isupport))
+    }
+
+    /// The caps the server has enabled for this connection.
+    pub fn enabled_caps(&self) -> ClientResult<Vec<String>> {
+        let caps = self.get_inner()?.caps.lock().unwrap();
+        Ok(caps.enabled().map(str::to_string).collect())
+    }
+
+    pub fn has_cap(&self, cap: &str) -> ClientResult<bool> {
+        Ok(self.get_inner()?.caps.lock().unwrap().is_enabled(cap))
     }
 
     fn get_inner_mut(&mut self) -> ClientResult<&mut ClientInner> {
@@ -263,16 +716,145 @@ impl Client {
         T: IntoIterator<Item = S>,
         S: AsRef<[u8]>,
     {
+        let msg = MessageBuilder::named(command).params(params).build()?;
+        self.send(msg).await
+    }
+
+    /// Sends a message as is. A message that fails `Message::validate` is rejected here rather
+    /// than taking down the connection when it is encoded. On an anonymous connection, PRIVMSGs
+    /// fail with `ClientError::Anonymous`, since Twitch would drop them without saying so.
+    pub async fn send(&mut self, msg: Message) -> ClientResult<()> {
+        self.send_with_priority(msg, Priority::Normal).await
+    }
+
+    /// Sends a message ahead of waiting messages with a lower priority.
+    pub async fn send_with_priority(
+        &mut self,
+        msg: Message,
+        priority: Priority,
+    ) -> ClientResult<()> {
+        msg.validate()?;
+        if msg.has_command(KnownCommand::Privmsg) && self.is_anonymous()? {
+            return Err(ClientError::Anonymous);
+        }
         self.get_inner_mut()?
             .input
-            .send(Message::from_named_command_params(command, params))
+            .send((priority, msg))
             .await
             .map_err(|_| ClientError::AlreadyClosed)?;
         Ok(())
     }
 
+    /// Sends chat to a channel, split into as many messages as the network's length limit needs.
+    pub async fn send_chat(
+        &mut self,
+        channel: &str,
+        text: &str,
+        priority: Priority,
+    ) -> ClientResult<()> {
+        self.send_chat_with_parent(channel, None, text, priority)
+            .await
+    }
+
+    async fn send_chat_with_parent(
+        &mut self,
+        channel: &str,
+        parent_msg_id: Option<&str>,
+        text: &str,
+        priority: Priority,
+    ) -> ClientResult<()> {
+        let channel = self.channel_target(channel)?;
+        for chunk in self.chat_limit(&channel)?.split(text) {
+            let mut builder = MessageBuilder::named("PRIVMSG");
+            if let Some(parent_msg_id) = parent_msg_id {
+                builder = builder.tag("reply-parent-msg-id", parent_msg_id);
+            }
+            let msg = builder.param(&channel).param(&chunk).build()?;
+            self.send_with_priority(msg, priority).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends chat to a channel.
+    pub async fn say(&mut self, channel: &str, text: &str) -> ClientResult<()> {
+        self.send_chat(channel, text, Priority::Normal).await
+    }
+
+    /// Sends chat to a channel as a reply to the message with the given id, such as `Chat::id`.
+    /// If the text has to be split, every part is a reply.
+    pub async fn reply(
+        &mut self,
+        channel: &str,
+        parent_msg_id: &str,
+        text: &str,
+    ) -> ClientResult<()> {
+        self.send_chat_with_parent(channel, Some(parent_msg_id), text, Priority::Normal)
+            .await
+    }
+
+    /// Always fails with `ClientError::Unsupported`. Twitch stopped taking chat commands such as
+    /// `/delete` over IRC in February 2023, so a message can only be deleted through the Helix API
+    /// (`DELETE /helix/moderation/chat`), which this crate doesn't speak. CLEARMSG events still
+    /// arrive when a moderator deletes a message.
+    pub async fn delete(&mut self, _channel: &str, _msg_id: &str) -> ClientResult<()> {
+        self.get_inner()?;
+        Err(ClientError::Unsupported(
+            "Twitch only deletes messages through the Helix API; call \
+             DELETE /helix/moderation/chat with the broadcaster, moderator and message ids",
+        ))
+    }
+
+    /// Joins a channel. Room listeners can be added for it as soon as this returns.
     pub async fn join(&mut self, channel: &str) -> ClientResult<()> {
-        self.send_msg("JOIN", &[format!("#{}", channel)]).await
+        let target = self.channel_target(channel)?;
+        self.send_msg("JOIN", &[target]).await?;
+        self.get_inner()?
+            .rooms
+            .lock()
+            .await
+            .notify_join_room(channel.to_string());
+        Ok(())
+    }
+
+    pub async fn part(&mut self, channel: &str) -> ClientResult<()> {
+        let target = self.channel_target(channel)?;
+        self.send_msg("PART", &[target]).await
+    }
+
+    /// Sends a private message to a user, split like `send_chat` does. Always fails with
+    /// `ClientError::Unsupported` on Twitch, which stopped taking `/w` over IRC in February 2023
+    /// and only sends whispers through the Helix API (`POST /helix/whispers`). Whispers to us
+    /// still arrive as events.
+    pub async fn whisper(&mut self, user: &str, text: &str) -> ClientResult<()> {
+        let inner = self.get_inner()?;
+        if inner.anonymous {
+            return Err(ClientError::Anonymous);
+        }
+        if inner.network.is_twitch() {
+            return Err(ClientError::Unsupported(
+                "Twitch only sends whispers through the Helix API (POST /helix/whispers)",
+            ));
+        }
+        for chunk in self.chat_limit(user)?.split(text) {
+            self.send_msg("PRIVMSG", [user, &chunk]).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends an action to the channel, as with `/me` in a chat client. Long text is split like
+    /// `send_chat` does.
+    pub async fn action(&mut self, channel: &str, text: &str) -> ClientResult<()> {
+        let channel = self.channel_target(channel)?;
+        // The framing is two delimiters, `ACTION` and a space.
+        let limit = self
+            .chat_limit(&channel)?
+            .less_bytes(ctcp::ACTION.len() + 3);
+        for chunk in limit.split(text) {
+            let text = ctcp::encode_action(&chunk);
+            self.send_msg("PRIVMSG", &[channel.as_bytes(), text.as_ref()])
+                .await?;
+        }
+        Ok(())
     }
 }
 
@@ -284,3 +866,81 @@ impl Drop for Client {
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::network::{Auth, GenericNetwork};
+    use minibot_irc_raw::MessageRef;
+
+    fn parse(line: &str) -> Message {
+        MessageRef::parse(line.as_bytes())
+            .unwrap()
+            .to_owned()
+            .unwrap()
+    }
+
+    fn wire(msg: &Message) -> String {
+        String::from_utf8(msg.to_wire().as_ref().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn generic_networks_get_plain_irc() {
+        let (server_send, stream) = mpsc::unbounded::<Message>();
+        let (sink, mut client_sent) = mpsc::unbounded::<Message>();
+        // The server's side of registration, queued before the client starts it.
+        for line in &[
+            "CAP * LS :server-time",
+            "CAP * ACK :server-time",
+            ":irc 001 bot :Welcome",
+            ":irc 005 bot CHANTYPES=#& :are supported by this server",
+            ":irc 376 bot :End of MOTD",
+        ] {
+            server_send.unbounded_send(parse(line)).unwrap();
+        }
+        let mut client = ClientFactory::create()
+            .unwrap()
+            .with_network(NetworkProfile::Generic(GenericNetwork::new(Auth::None)))
+            .connect_with(
+                IrcStream::from_stream(stream.map(Ok)),
+                IrcSink::from_sink(sink.sink_map_err(|_| {
+                    minibot_irc_raw::Error::Io(std::io::ErrorKind::BrokenPipe.into())
+                })),
+                "bot",
+                "token",
+            )
+            .await
+            .unwrap();
+
+        client.join("&local").await.unwrap();
+        client.join("room").await.unwrap();
+        client.say("room", "hi").await.unwrap();
+        client.say("room", "hi").await.unwrap();
+        client.whisper("someone", "/w other hi").await.unwrap();
+        // 512 bytes, less `:bot!~<user>@<host> PRIVMSG #room :` and the CRLF.
+        client.say("room", &"a".repeat(450)).await.unwrap();
+
+        let mut sent = Vec::new();
+        while sent.len() < 7 {
+            let msg = wire(&client_sent.next().await.unwrap());
+            if msg.starts_with("JOIN") || msg.starts_with("PRIVMSG") {
+                sent.push(msg);
+            }
+        }
+        assert_eq!(
+            sent[..5],
+            [
+                "JOIN :&local",
+                "JOIN :#room",
+                "PRIVMSG #room :hi",
+                "PRIVMSG #room :hi",
+                "PRIVMSG someone :/w other hi",
+            ]
+        );
+        assert_eq!(sent[5], format!("PRIVMSG #room :{}", "a".repeat(414)));
+        assert_eq!(sent[6], format!("PRIVMSG #room :{}", "a".repeat(36)));
+
+        drop(server_send);
+        client.close().await.unwrap();
+    }
+}
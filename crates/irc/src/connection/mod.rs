@@ -1,30 +1,208 @@
-mod net_stream;
+mod tls;
+mod websocket;
 
-pub use minibot_irc_raw::{Error as IrcError, IrcSink, IrcStream};
+pub use minibot_irc_raw::{Error as IrcError, IrcCodec, IrcSink, IrcStream};
+pub use tls::TlsConfig;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::InvalidDnsNameError;
+use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error(transparent)]
     MessageParseError(#[from] IrcError),
 
+    #[error("Invalid server name for TLS: {0}")]
+    InvalidServerName(#[from] InvalidDnsNameError),
+
+    #[error("Invalid root certificate: {0}")]
+    InvalidCertificate(String),
+
+    // Boxed, since the error is much larger than the others.
     #[error(transparent)]
-    NativeTlsError(#[from] native_tls::Error),
+    WebSocketError(Box<tokio_tungstenite::tungstenite::Error>),
 
     #[error(transparent)]
     IoError(#[from] std::io::Error),
 }
 
+impl From<tokio_tungstenite::tungstenite::Error> for Error {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        Error::WebSocketError(Box::new(e))
+    }
+}
+
 type Result<T> = std::result::Result<T, Error>;
 
-pub struct IrcConnector(async_native_tls::TlsConnector);
+/// How the connection to the server is made.
+#[derive(Clone)]
+pub enum Transport {
+    /// Plain TCP, such as for a local test server.
+    Tcp,
+    /// TLS over TCP, as on `irc.chat.twitch.tv:6697`.
+    Tls(TlsConfig),
+    /// IRC over WebSocket, with one message per text frame, as on `irc-ws.chat.twitch.tv`. `tls`
+    /// selects between `wss://` and `ws://`.
+    WebSocket {
+        tls: Option<TlsConfig>,
+        path: String,
+    },
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Transport::Tls(TlsConfig::new())
+    }
+}
+
+trait NetStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> NetStream for T {}
+
+/// Frames IRC messages over a byte stream, one per line.
+fn from_byte_stream<S: NetStream>(stream: S) -> (IrcStream, IrcSink) {
+    let (read_stream, write_stream) = tokio::io::split(stream);
+    // A single bad line from the server shouldn't take down the whole connection.
+    (
+        IrcStream::with_codec(read_stream.compat(), IrcCodec::lenient()),
+        IrcSink::new(write_stream.compat_write()),
+    )
+}
+
+#[derive(Clone)]
+pub struct IrcConnector(Transport);
 
 impl IrcConnector {
+    /// Creates a connector that uses TLS, checking certificates against the usual root CAs.
     pub fn new() -> Result<Self> {
-        Ok(IrcConnector(async_native_tls::TlsConnector::new()))
+        Ok(IrcConnector::with_transport(Transport::default()))
+    }
+
+    pub fn with_transport(transport: Transport) -> Self {
+        IrcConnector(transport)
     }
 
     pub async fn connect(&self, host: &str, port: u16) -> Result<(IrcStream, IrcSink)> {
-        let (read_stream, write_stream) = net_stream::connect_ssl(&self.0, host, port).await?;
-        Ok((IrcStream::new(read_stream), IrcSink::new(write_stream)))
+        let tcp_stream = TcpStream::connect((host, port)).await?;
+        match &self.0 {
+            Transport::Tcp => Ok(from_byte_stream(tcp_stream)),
+            Transport::Tls(tls) => Ok(from_byte_stream(tls.connect(host, tcp_stream).await?)),
+            Transport::WebSocket { tls, path } => {
+                let (scheme, stream): (_, Box<dyn NetStream>) = match tls {
+                    Some(tls) => ("wss", Box::new(tls.connect(host, tcp_stream).await?)),
+                    None => ("ws", Box::new(tcp_stream)),
+                };
+                let url = format!("{}://{}:{}{}", scheme, host, port, path);
+                websocket::connect(&url, stream).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::prelude::*;
+    use minibot_irc_raw::Message;
+    use std::sync::Arc;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite;
+
+    async fn ping_pong(transport: Transport, port: u16) {
+        let (mut irc_read, mut irc_write) = IrcConnector::with_transport(transport)
+            .connect("127.0.0.1", port)
+            .await
+            .unwrap();
+        irc_write
+            .send(Message::from_named_command_params("PING", ["abc"]))
+            .await
+            .unwrap();
+        let reply = irc_read.next().await.unwrap().unwrap();
+        assert!(reply.has_named_command("PONG"));
+        assert!(reply.params()[0].eq_bytes(b"abc"));
+    }
+
+    #[tokio::test]
+    async fn connects_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "PING :abc\r\n");
+            stream.get_mut().write_all(b"PONG :abc\r\n").await.unwrap();
+        };
+        future::join(ping_pong(Transport::Tcp, port), server).await;
+    }
+
+    #[tokio::test]
+    async fn connects_over_tls_with_a_custom_ca() {
+        use tokio_rustls::rustls::crypto::ring;
+        use tokio_rustls::rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+        use tokio_rustls::rustls::ServerConfig;
+
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+        let server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.der().clone()],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der())),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = async {
+            // The first client doesn't trust the certificate, and gives up on the handshake.
+            let (stream, _) = listener.accept().await.unwrap();
+            assert!(acceptor.accept(stream).await.is_err());
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(acceptor.accept(stream).await.unwrap());
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "PING :abc\r\n");
+            stream.get_mut().write_all(b"PONG :abc\r\n").await.unwrap();
+            stream.get_mut().flush().await.unwrap();
+        };
+        let client = async {
+            let untrusted = IrcConnector::with_transport(Transport::Tls(TlsConfig::new()))
+                .connect("127.0.0.1", port)
+                .await;
+            assert!(matches!(untrusted, Err(Error::IoError(_))));
+
+            let tls = TlsConfig::with_root_ca_pem(cert.pem().as_bytes()).unwrap();
+            ping_pong(Transport::Tls(tls), port).await;
+        };
+        future::join(client, server).await;
+    }
+
+    #[tokio::test]
+    async fn connects_over_websocket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let frame = ws.next().await.unwrap().unwrap();
+            assert_eq!(frame, tungstenite::Message::text("PING :abc"));
+            ws.send(tungstenite::Message::text("PONG :abc"))
+                .await
+                .unwrap();
+        };
+        let transport = Transport::WebSocket {
+            tls: None,
+            path: "/".to_string(),
+        };
+        future::join(ping_pong(transport, port), server).await;
     }
 }
@@ -0,0 +1,114 @@
+use super::{Error, Result};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::client::WantsClientCert;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, ConfigBuilder, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+/// TLS settings for a connection. By default, the server's certificate is checked against the
+/// Mozilla root CAs that are built in, not the ones the OS trusts. Networks that add their own
+/// CAs, such as corporate proxies that intercept TLS, need `with_native_roots()`.
+#[derive(Clone)]
+pub struct TlsConfig {
+    roots: RootCertStore,
+    config: Arc<ClientConfig>,
+}
+
+fn config_builder(roots: RootCertStore) -> ConfigBuilder<ClientConfig, WantsClientCert> {
+    ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("The ring provider supports the default protocol versions")
+        .with_root_certificates(roots)
+}
+
+impl TlsConfig {
+    pub fn new() -> Self {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        TlsConfig::with_roots(roots)
+    }
+
+    /// Trusts the CA certificates the OS trusts. Certificates that the OS store has but that can't
+    /// be parsed are skipped; this only fails if none could be loaded.
+    pub fn with_native_roots() -> Result<Self> {
+        let native = rustls_native_certs::load_native_certs();
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(native.certs);
+        if roots.is_empty() {
+            let reason = match native.errors.first() {
+                Some(e) => e.to_string(),
+                None => "The OS has no trusted certificates".to_string(),
+            };
+            return Err(Error::InvalidCertificate(reason));
+        }
+        Ok(TlsConfig::with_roots(roots))
+    }
+
+    /// Trusts only the CA certificates in a PEM file, such as a self-signed certificate for a
+    /// test server.
+    pub fn with_root_ca_pem(pem: &[u8]) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_slice_iter(pem) {
+            let cert = cert.map_err(|e| Error::InvalidCertificate(e.to_string()))?;
+            roots
+                .add(cert)
+                .map_err(|e| Error::InvalidCertificate(e.to_string()))?;
+        }
+        if roots.is_empty() {
+            return Err(Error::InvalidCertificate(
+                "No certificates found in PEM".to_string(),
+            ));
+        }
+        Ok(TlsConfig::with_roots(roots))
+    }
+
+    pub fn with_roots(roots: RootCertStore) -> Self {
+        let config = config_builder(roots.clone()).with_no_client_auth();
+        TlsConfig {
+            roots,
+            config: Arc::new(config),
+        }
+    }
+
+    /// Presents a client certificate to the server, as SASL EXTERNAL logins need. The first PEM
+    /// holds the certificate chain, leaf first, and the second its private key.
+    pub fn with_client_cert_pem(self, cert_chain_pem: &[u8], key_pem: &[u8]) -> Result<Self> {
+        let certs = CertificateDer::pem_slice_iter(cert_chain_pem)
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| Error::InvalidCertificate(e.to_string()))?;
+        if certs.is_empty() {
+            return Err(Error::InvalidCertificate(
+                "No certificates found in PEM".to_string(),
+            ));
+        }
+        let key = PrivateKeyDer::from_pem_slice(key_pem)
+            .map_err(|e| Error::InvalidCertificate(e.to_string()))?;
+        let config = config_builder(self.roots.clone())
+            .with_client_auth_cert(certs, key)
+            .map_err(|e| Error::InvalidCertificate(e.to_string()))?;
+        Ok(TlsConfig {
+            roots: self.roots,
+            config: Arc::new(config),
+        })
+    }
+
+    pub(super) async fn connect<S>(&self, host: &str, stream: S) -> Result<TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let server_name = ServerName::try_from(host.to_string())?;
+        let connector = TlsConnector::from(self.config.clone());
+        Ok(connector.connect(server_name, stream).await?)
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig::new()
+    }
+}
@@ -0,0 +1,66 @@
+//! IRC over WebSocket, as described in the IRCv3 WebSocket spec. Each text frame carries one
+//! message without the trailing CRLF.
+
+use super::{IrcCodec, IrcError, IrcSink, IrcStream, NetStream, Result};
+use futures::prelude::*;
+use minibot_irc_raw::Message;
+use tokio_tungstenite::tungstenite::{self, Bytes};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+use tokio_util::io::StreamReader;
+
+/// Turns a frame into lines for the codec. Servers may put several lines in one frame, and may
+/// leave off the CRLF after the last one.
+fn frame_lines(frame: tungstenite::Message) -> Option<Bytes> {
+    let data = match frame {
+        tungstenite::Message::Text(text) => Bytes::from(text),
+        tungstenite::Message::Binary(data) => data,
+        _ => return None,
+    };
+    if data.ends_with(b"\n") {
+        Some(data)
+    } else {
+        let mut line = data.to_vec();
+        line.extend_from_slice(b"\r\n");
+        Some(Bytes::from(line))
+    }
+}
+
+/// Text frames have to be UTF-8, so other messages are refused rather than changed.
+fn to_frame(msg: Message) -> std::result::Result<tungstenite::Message, IrcError> {
+    msg.validate()?;
+    let line = String::from_utf8(msg.to_wire().as_ref().to_vec())
+        .map_err(|e| IrcError::Message(e.utf8_error().into()))?;
+    Ok(tungstenite::Message::text(line))
+}
+
+pub(super) async fn connect(url: &str, stream: Box<dyn NetStream>) -> Result<(IrcStream, IrcSink)> {
+    let (ws_stream, _) = tokio_tungstenite::client_async(url, stream).await?;
+    let (ws_sink, ws_stream) = ws_stream.split();
+
+    let lines = ws_stream
+        .try_filter_map(|frame| future::ready(Ok(frame_lines(frame))))
+        .map_err(std::io::Error::other);
+    let irc_stream = IrcStream::with_codec(StreamReader::new(lines).compat(), IrcCodec::lenient());
+
+    let irc_sink = ws_sink
+        .sink_map_err(|e| IrcError::Io(std::io::Error::other(e)))
+        .with(|msg| future::ready(to_frame(msg)));
+    Ok((irc_stream, IrcSink::from_sink(irc_sink)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_utf8_goes_in_text_frames() {
+        let msg = Message::from_named_command_params("PRIVMSG", ["#a", "caf\u{e9}"]);
+        assert_eq!(
+            to_frame(msg).unwrap(),
+            tungstenite::Message::text("PRIVMSG #a :caf\u{e9}")
+        );
+
+        let msg = Message::from_named_command_params("PRIVMSG", [&b"#a"[..], &b"caf\xe9"[..]]);
+        assert!(matches!(to_frame(msg), Err(IrcError::Message(_))));
+    }
+}
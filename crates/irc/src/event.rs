@@ -0,0 +1,352 @@
+//! Typed events for messages from the server.
+//!
+//! Each event keeps the message it came from, so that tags that aren't broken out into fields are
+//! still available, e.g. through `minibot_irc_raw::twitch_tags::TwitchTags`. Channel names are
+//! given without the leading `#`, the same way `Client::join` takes them.
+
+use minibot_byte_string::ByteString;
+use minibot_irc_raw::{KnownCommand, Message};
+
+pub use crate::futures_util::simple_sender::SlowSubscriberPolicy;
+
+fn text(param: &ByteString) -> String {
+    String::from_utf8_lossy(param.as_ref()).into_owned()
+}
+
+fn channel_name(param: &ByteString) -> String {
+    let channel = text(param);
+    match channel.strip_prefix('#') {
+        Some(name) => name.to_string(),
+        None => channel,
+    }
+}
+
+// Nicknames can't start with any of the channel prefixes in RFC 2811, so this holds on any network.
+fn is_channel(param: &ByteString) -> bool {
+    param
+        .as_ref()
+        .first()
+        .is_some_and(|prefix| b"#&+!".contains(prefix))
+}
+
+fn sender_nick(message: &Message) -> Option<String> {
+    Some(message.source()?.nick()?.to_string())
+}
+
+#[derive(Clone, Debug)]
+pub struct Chat {
+    /// Where to reply: the channel, or for a private message, the sender.
+    pub channel: String,
+    pub sender: String,
+    pub text: String,
+    /// True if this was sent as a CTCP ACTION (`/me`). The CTCP framing is not included in
+    /// `text`.
+    pub is_action: bool,
+    /// True if this was sent to us rather than to a channel. Replies go through
+    /// `Client::whisper`.
+    pub is_private: bool,
+    /// The `id` tag, which `Client::reply` takes, and which Twitch's API takes for deleting the
+    /// message.
+    pub id: Option<String>,
+    pub message: Message,
+}
+
+/// A CTCP request other than ACTION, such as `VERSION`. Replies go to `sender` as a NOTICE.
+#[derive(Clone, Debug)]
+pub struct Ctcp {
+    /// `None` if it was sent to us rather than to a channel.
+    pub channel: Option<String>,
+    pub sender: String,
+    pub command: String,
+    pub params: String,
+    pub message: Message,
+}
+
+/// A user joining or leaving a channel.
+#[derive(Clone, Debug)]
+pub struct Membership {
+    pub channel: String,
+    pub user: String,
+    pub message: Message,
+}
+
+#[derive(Clone, Debug)]
+pub struct Notice {
+    /// `None` for notices that aren't about a channel, such as login failures.
+    pub channel: Option<String>,
+    /// Twitch's `msg-id` tag, which identifies the kind of notice.
+    pub msg_id: Option<String>,
+    pub text: String,
+    pub message: Message,
+}
+
+/// Subscriptions, raids, and other announcements.
+#[derive(Clone, Debug)]
+pub struct UserNotice {
+    pub channel: String,
+    pub msg_id: Option<String>,
+    /// The message the user attached, if any.
+    pub text: Option<String>,
+    pub message: Message,
+}
+
+#[derive(Clone, Debug)]
+pub struct ClearChat {
+    pub channel: String,
+    /// The user whose messages were cleared, or `None` if the whole chat was cleared.
+    pub user: Option<String>,
+    pub message: Message,
+}
+
+#[derive(Clone, Debug)]
+pub struct ClearMsg {
+    pub channel: String,
+    /// The `target-msg-id` tag: the id of the deleted message.
+    pub target_msg_id: Option<String>,
+    pub message: Message,
+}
+
+/// A ROOMSTATE or USERSTATE update for a channel. The state itself is in the tags.
+#[derive(Clone, Debug)]
+pub struct ChannelState {
+    pub channel: String,
+    pub message: Message,
+}
+
+#[derive(Clone, Debug)]
+pub struct Whisper {
+    pub from: String,
+    pub to: String,
+    pub text: String,
+    pub message: Message,
+}
+
+#[derive(Clone, Debug)]
+pub enum Event {
+    Chat(Chat),
+    Ctcp(Ctcp),
+    Join(Membership),
+    Part(Membership),
+    Notice(Notice),
+    UserNotice(UserNotice),
+    ClearChat(ClearChat),
+    ClearMsg(ClearMsg),
+    RoomState(ChannelState),
+    UserState(ChannelState),
+    /// Our own state across all channels, sent once after login.
+    GlobalUserState(Message),
+    Whisper(Whisper),
+    /// Anything that doesn't have its own event, including malformed versions of the above.
+    Other(Message),
+}
+
+impl Event {
+    pub fn from_message(message: Message) -> Event {
+        let known = match message.known_command() {
+            Some(known) => known,
+            None => return Event::Other(message),
+        };
+
+        let params = message.params();
+        match (known, params) {
+            (KnownCommand::Privmsg, [target, body]) => {
+                let sender = match sender_nick(&message) {
+                    Some(sender) => sender,
+                    None => return Event::Other(message),
+                };
+                let is_private = !is_channel(target);
+                let ctcp = message.ctcp().map(|ctcp| {
+                    let params = String::from_utf8_lossy(ctcp.params().as_ref()).into_owned();
+                    (ctcp.is_action(), ctcp.command().to_string(), params)
+                });
+                let (text, is_action) = match ctcp {
+                    Some((true, _, params)) => (params, true),
+                    Some((false, command, params)) => {
+                        return Event::Ctcp(Ctcp {
+                            channel: (!is_private).then(|| channel_name(target)),
+                            sender,
+                            command,
+                            params,
+                            message,
+                        })
+                    }
+                    None => (text(body), false),
+                };
+                Event::Chat(Chat {
+                    channel: if is_private {
+                        sender.clone()
+                    } else {
+                        channel_name(target)
+                    },
+                    sender,
+                    text,
+                    is_action,
+                    is_private,
+                    id: message.tag("id").map(str::to_string),
+                    message,
+                })
+            }
+            (KnownCommand::Join, [channel, ..]) | (KnownCommand::Part, [channel, ..]) => {
+                match sender_nick(&message) {
+                    Some(user) => {
+                        let membership = Membership {
+                            channel: channel_name(channel),
+                            user,
+                            message,
+                        };
+                        if known == KnownCommand::Join {
+                            Event::Join(membership)
+                        } else {
+                            Event::Part(membership)
+                        }
+                    }
+                    None => Event::Other(message),
+                }
+            }
+            (KnownCommand::Notice, [target, body]) => Event::Notice(Notice {
+                channel: if target.as_ref().starts_with(b"#") {
+                    Some(channel_name(target))
+                } else {
+                    None
+                },
+                msg_id: message.tag("msg-id").map(str::to_string),
+                text: text(body),
+                message,
+            }),
+            (KnownCommand::UserNotice, [channel, rest @ ..]) => Event::UserNotice(UserNotice {
+                channel: channel_name(channel),
+                msg_id: message.tag("msg-id").map(str::to_string),
+                text: rest.first().map(text),
+                message,
+            }),
+            (KnownCommand::ClearChat, [channel, rest @ ..]) => Event::ClearChat(ClearChat {
+                channel: channel_name(channel),
+                user: rest.first().map(text),
+                message,
+            }),
+            (KnownCommand::ClearMsg, [channel, ..]) => Event::ClearMsg(ClearMsg {
+                channel: channel_name(channel),
+                target_msg_id: message.tag("target-msg-id").map(str::to_string),
+                message,
+            }),
+            (KnownCommand::RoomState, [channel]) => Event::RoomState(ChannelState {
+                channel: channel_name(channel),
+                message,
+            }),
+            (KnownCommand::UserState, [channel]) => Event::UserState(ChannelState {
+                channel: channel_name(channel),
+                message,
+            }),
+            (KnownCommand::GlobalUserState, _) => Event::GlobalUserState(message),
+            (KnownCommand::Whisper, [to, body]) => match sender_nick(&message) {
+                Some(from) => Event::Whisper(Whisper {
+                    from,
+                    to: text(to),
+                    text: text(body),
+                    message,
+                }),
+                None => Event::Other(message),
+            },
+            _ => Event::Other(message),
+        }
+    }
+
+    /// The message the event was parsed from.
+    pub fn message(&self) -> &Message {
+        match self {
+            Event::Chat(e) => &e.message,
+            Event::Ctcp(e) => &e.message,
+            Event::Join(e) | Event::Part(e) => &e.message,
+            Event::Notice(e) => &e.message,
+            Event::UserNotice(e) => &e.message,
+            Event::ClearChat(e) => &e.message,
+            Event::ClearMsg(e) => &e.message,
+            Event::RoomState(e) | Event::UserState(e) => &e.message,
+            Event::Whisper(e) => &e.message,
+            Event::GlobalUserState(message) | Event::Other(message) => message,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use minibot_irc_raw::MessageRef;
+
+    fn event(line: &str) -> Event {
+        Event::from_message(
+            MessageRef::parse(line.as_bytes())
+                .unwrap()
+                .to_owned()
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn parses_chat() {
+        match event("@id=abc :foo!foo@foo.tmi.twitch.tv PRIVMSG #bar :\x01ACTION waves\x01") {
+            Event::Chat(chat) => {
+                assert_eq!(chat.channel, "bar");
+                assert_eq!(chat.sender, "foo");
+                assert_eq!(chat.text, "waves");
+                assert!(chat.is_action);
+                assert_eq!(chat.id.as_deref(), Some("abc"));
+            }
+            e => panic!("Unexpected event: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn parses_moderation_and_whispers() {
+        assert!(matches!(
+            event(":tmi.twitch.tv CLEARCHAT #bar"),
+            Event::ClearChat(ClearChat { user: None, .. })
+        ));
+        assert!(matches!(
+            event(":tmi.twitch.tv CLEARCHAT #bar :baduser"),
+            Event::ClearChat(ClearChat { user: Some(_), .. })
+        ));
+        match event(":foo!foo@foo.tmi.twitch.tv WHISPER me :psst") {
+            Event::Whisper(whisper) => {
+                assert_eq!(whisper.from, "foo");
+                assert_eq!(whisper.text, "psst");
+            }
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        assert!(matches!(event("PRIVMSG #bar :no source"), Event::Other(_)));
+    }
+
+    #[test]
+    fn parses_private_chat_and_ctcp() {
+        match event(":foo!foo@example.net PRIVMSG me :hi") {
+            Event::Chat(chat) => {
+                assert_eq!(chat.channel, "foo");
+                assert!(chat.is_private);
+            }
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        match event(":foo!foo@example.net PRIVMSG &local :hi") {
+            Event::Chat(chat) => {
+                assert_eq!(chat.channel, "&local");
+                assert!(!chat.is_private);
+            }
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        match event(":foo!foo@example.net PRIVMSG me :\x01VERSION\x01") {
+            Event::Ctcp(ctcp) => {
+                assert_eq!(ctcp.channel, None);
+                assert_eq!(ctcp.sender, "foo");
+                assert_eq!(ctcp.command, "VERSION");
+                assert_eq!(ctcp.params, "");
+            }
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        assert!(matches!(
+            event(":foo!foo@example.net PRIVMSG #bar :\x01PING 123\x01"),
+            Event::Ctcp(Ctcp {
+                channel: Some(_),
+                ..
+            })
+        ));
+    }
+}
@@ -1,4 +1,4 @@
-use super::simple_sender::SimpleSender;
+use super::simple_sender::{SimpleSender, SlowSubscriberPolicy};
 use futures::channel::mpsc;
 use futures::prelude::*;
 use std::mem;
@@ -26,9 +26,9 @@ impl<T: Clone> Inner<T> {
         *guard = sinks;
     }
 
-    pub fn add_sink(&self, sender: mpsc::Sender<T>) {
+    pub fn add_sink(&self, sender: SimpleSender<T>) {
         let mut guard = self.sinks.lock().unwrap();
-        guard.push(SimpleSender::new(sender))
+        guard.push(sender)
     }
 }
 
@@ -57,6 +57,11 @@ impl<T: Clone + Send + Sync + 'static> EventSink<T> {
     }
 
     pub fn add_sink(&mut self, sender: mpsc::Sender<T>) {
-        self.inner.add_sink(sender);
+        self.inner.add_sink(SimpleSender::new(sender));
+    }
+
+    pub fn add_sink_with_policy(&mut self, sender: mpsc::Sender<T>, policy: SlowSubscriberPolicy) {
+        self.inner
+            .add_sink(SimpleSender::with_policy(sender, policy));
     }
 }
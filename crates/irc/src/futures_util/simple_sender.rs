@@ -1,25 +1,49 @@
 use futures::channel::mpsc;
 use futures::prelude::*;
 
+/// What to do when a subscriber's channel is full.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SlowSubscriberPolicy {
+    /// Wait for the subscriber to catch up. This holds up every other subscriber, and eventually
+    /// the connection itself.
+    Block,
+    /// Drop the events that don't fit.
+    DropNewest,
+    /// Drop the subscriber. Its stream ends.
+    Disconnect,
+}
+
 pub enum SimpleSender<T> {
-    Connected(mpsc::Sender<T>),
+    Connected(mpsc::Sender<T>, SlowSubscriberPolicy),
     Disconnected,
 }
 
 impl<T> SimpleSender<T> {
     pub fn new(sender: mpsc::Sender<T>) -> Self {
-        SimpleSender::Connected(sender)
+        SimpleSender::with_policy(sender, SlowSubscriberPolicy::Block)
+    }
+
+    pub fn with_policy(sender: mpsc::Sender<T>, policy: SlowSubscriberPolicy) -> Self {
+        SimpleSender::Connected(sender, policy)
     }
 
     pub async fn send(&mut self, msg: T) {
-        if let SimpleSender::Connected(sender) = self {
-            if let Err(_) = sender.send(msg).await {
+        if let SimpleSender::Connected(sender, policy) = self {
+            let still_connected = match policy {
+                SlowSubscriberPolicy::Block => sender.send(msg).await.is_ok(),
+                SlowSubscriberPolicy::DropNewest => match sender.try_send(msg) {
+                    Ok(()) => true,
+                    Err(e) => e.is_full(),
+                },
+                SlowSubscriberPolicy::Disconnect => sender.try_send(msg).is_ok(),
+            };
+            if !still_connected {
                 *self = SimpleSender::Disconnected;
             }
         }
     }
 
     pub fn is_connected(&self) -> bool {
-        matches!(self, SimpleSender::Connected(_))
+        matches!(self, SimpleSender::Connected(..))
     }
 }
@@ -0,0 +1,92 @@
+//! Client-side keepalive.
+//!
+//! The server PINGs us, but only every few minutes, so a dead connection can go unnoticed for a
+//! long time. The client sends its own PING whenever the connection has been idle for a while,
+//! and gives up on the connection if the PONG doesn't come back in time. The PING/PONG round trip
+//! doubles as a latency measurement.
+
+use futures::channel::mpsc;
+use futures::prelude::*;
+use futures::select;
+use minibot_byte_string::ByteString;
+use minibot_irc_raw::Message;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Copy, Clone, Debug)]
+pub struct KeepaliveConfig {
+    /// How long the connection may be quiet before we send a PING.
+    pub idle_interval: Duration,
+    /// How long to wait for the PONG before the connection is considered dead.
+    pub timeout: Duration,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig {
+            idle_interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(15),
+        }
+    }
+}
+
+/// What the read side of the connection tells the keepalive loop.
+pub(crate) enum Liveness {
+    /// Some message arrived.
+    Activity,
+    /// A PONG arrived, with its token.
+    Pong(ByteString),
+}
+
+/// Runs until the liveness stream ends, or until a PING goes unanswered. Returns true in the
+/// latter case.
+pub(crate) async fn run_keepalive_loop(
+    config: KeepaliveConfig,
+    mut liveness_stream: mpsc::Receiver<Liveness>,
+    mut control_sink: mpsc::Sender<Message>,
+    latency: Arc<Mutex<Option<Duration>>>,
+) -> bool {
+    let mut last_received = Instant::now();
+    // The send time and token of the PING we're waiting on.
+    let mut outstanding: Option<(Instant, String)> = None;
+    let mut next_token = 0u64;
+
+    loop {
+        let deadline = match &outstanding {
+            Some((sent, _)) => *sent + config.timeout,
+            None => last_received + config.idle_interval,
+        };
+        let sleep = tokio::time::sleep_until(deadline).fuse();
+        futures::pin_mut!(sleep);
+
+        select! {
+            liveness = liveness_stream.next() => match liveness {
+                Some(Liveness::Activity) => last_received = Instant::now(),
+                Some(Liveness::Pong(token)) => {
+                    last_received = Instant::now();
+                    if let Some((sent, expected)) = &outstanding {
+                        if token.eq_bytes(expected.as_bytes()) {
+                            *latency.lock().unwrap() = Some(last_received - *sent);
+                            outstanding = None;
+                        }
+                    }
+                }
+                None => return false,
+            },
+            () = sleep => {
+                if outstanding.is_some() {
+                    return true;
+                }
+
+                let token = format!("minibot-{}", next_token);
+                next_token += 1;
+                let ping = Message::from_named_command_params("PING", [&token]);
+                if control_sink.send(ping).await.is_err() {
+                    return false;
+                }
+                outstanding = Some((Instant::now(), token));
+            }
+        }
+    }
+}
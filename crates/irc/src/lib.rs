@@ -1,7 +1,16 @@
+pub mod cap;
 pub mod client;
 pub mod connection;
+pub mod event;
 mod futures_util;
+pub mod keepalive;
+pub mod network;
+pub mod outbound;
+pub mod pool;
+pub mod rate_limit;
 pub mod room_state;
 pub mod rpc;
+pub mod supervisor;
+pub mod transcript;
 
-pub use minibot_irc_raw::{Command, Message};
+pub use minibot_irc_raw::{Command, KnownCommand, Message};
@@ -0,0 +1,755 @@
+//! What differs between IRC networks.
+//!
+//! Twitch takes an OAuth token as the PASS and never refuses a nick. Other networks log in to an
+//! account with SASL, or by identifying to NickServ once registered, and may already have someone
+//! using our nick. `NetworkProfile` says which kind of network we are on, and `Registration`
+//! logs in to it. Like `CapNegotiator`, it is fed the server's messages and returns the messages
+//! to send back, and does no I/O itself.
+//!
+//! Once logged in, the profile also decides how we talk: Twitch has its own rate limits, length
+//! limit and whispers, where other networks have plain IRC's.
+//!
+//! During registration, servers also describe themselves in RPL_ISUPPORT (005). `ISupport`
+//! keeps those parameters, and interprets the ones that change how names are handled: which
+//! prefixes mark a channel, how names are compared, and which prefixes mark a member's modes in
+//! NAMES replies.
+
+use crate::cap::{CapConfig, CapNegotiator, SASL};
+use crate::client::{ClientResult, LoginError};
+use crate::outbound::ChatLimit;
+use crate::rate_limit::RateLimitConfig;
+use minibot_irc_raw::{KnownCommand, Message, MessageBuilder};
+use std::collections::BTreeMap;
+
+// Servers take AUTHENTICATE payloads in pieces of at most this many bytes.
+const SASL_CHUNK_LEN: usize = 400;
+
+// How many underscores we add to our nick when it is taken and there are no alternates left.
+const MAX_NICK_SUFFIX: usize = 3;
+
+const DEFAULT_CHANTYPES: &str = "#&";
+
+// The longest line a server takes, with the CRLF.
+const MAX_LINE_BYTES: usize = 512;
+
+// How long the user and host in our source may be, for servers that don't say in USERLEN and
+// HOSTLEN.
+const DEFAULT_USERLEN: usize = 10;
+const DEFAULT_HOSTLEN: usize = 63;
+
+/// How to log in to an account on a generic network. The account name is the user given to
+/// `ClientFactory::connect`, and the password is the token. Logging in anonymously skips this.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Auth {
+    None,
+    /// Sends the token with PASS, as a server or bouncer password.
+    ServerPassword,
+    /// SASL PLAIN, with the account name and password.
+    SaslPlain,
+    /// SASL EXTERNAL, which logs in with the TLS client certificate from
+    /// `TlsConfig::with_client_cert_pem`. The token is only used for a NickServ fallback.
+    SaslExternal,
+    /// `PRIVMSG NickServ :IDENTIFY <account> <password>` once registered. We don't wait for
+    /// NickServ to answer, since how it does differs between services.
+    NickServ,
+}
+
+impl Auth {
+    fn sasl_mechanism(&self) -> Option<&'static str> {
+        match self {
+            Auth::SaslPlain => Some("PLAIN"),
+            Auth::SaslExternal => Some("EXTERNAL"),
+            _ => None,
+        }
+    }
+}
+
+/// Settings for a network that follows the IRC RFCs and IRCv3.
+#[derive(Clone, Debug)]
+pub struct GenericNetwork {
+    auth: Auth,
+    nickserv_fallback: bool,
+    alt_nicks: Vec<String>,
+    realname: String,
+}
+
+impl GenericNetwork {
+    pub fn new(auth: Auth) -> Self {
+        GenericNetwork {
+            auth,
+            nickserv_fallback: false,
+            alt_nicks: Vec::new(),
+            realname: "minibot".to_string(),
+        }
+    }
+
+    /// If the server doesn't offer SASL, or SASL fails, identifies to NickServ instead of failing
+    /// the login.
+    pub fn with_nickserv_fallback(mut self) -> Self {
+        self.nickserv_fallback = true;
+        self
+    }
+
+    /// Adds a nick to try if ours is taken. Once these are used up, underscores are added to the
+    /// nick we asked for.
+    pub fn with_alt_nick(mut self, nick: impl Into<String>) -> Self {
+        self.alt_nicks.push(nick.into());
+        self
+    }
+
+    /// Sets the real name sent with USER. Defaults to `minibot`.
+    pub fn with_realname(mut self, realname: impl Into<String>) -> Self {
+        self.realname = realname.into();
+        self
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub enum NetworkProfile {
+    #[default]
+    Twitch,
+    Generic(GenericNetwork),
+}
+
+impl NetworkProfile {
+    pub fn is_twitch(&self) -> bool {
+        matches!(self, NetworkProfile::Twitch)
+    }
+
+    /// The caps that a `ClientFactory` negotiates on this network unless it is given others.
+    pub fn default_caps(&self) -> CapConfig {
+        match self {
+            NetworkProfile::Twitch => CapConfig::twitch(),
+            NetworkProfile::Generic(_) => CapConfig::ircv3(),
+        }
+    }
+
+    /// The limits that a `ClientFactory` holds messages to on this network unless it is given
+    /// others.
+    pub fn default_rate_limits(&self) -> RateLimitConfig {
+        match self {
+            NetworkProfile::Twitch => RateLimitConfig::default(),
+            NetworkProfile::Generic(_) => RateLimitConfig::generic(),
+        }
+    }
+
+    /// How much text fits in a PRIVMSG from `nick` to `target`. Elsewhere than Twitch, the limit
+    /// is on the line that the server passes on, which starts with our `nick!user@host`. We don't
+    /// know our user and host, so this leaves room for the longest ones the server allows.
+    pub fn chat_limit(&self, nick: &str, target: &str, isupport: &ISupport) -> ChatLimit {
+        match self {
+            NetworkProfile::Twitch => ChatLimit::TWITCH,
+            NetworkProfile::Generic(_) => {
+                let length = |token: &str, default: usize| {
+                    isupport
+                        .value(token)
+                        .and_then(|value| value.parse().ok())
+                        .unwrap_or(default)
+                };
+                // The `~` is for servers that mark users without ident that way.
+                let framing = ":!~@ PRIVMSG  :\r\n".len();
+                let used = framing
+                    + nick.len()
+                    + length("USERLEN", DEFAULT_USERLEN)
+                    + length("HOSTLEN", DEFAULT_HOSTLEN)
+                    + target.len();
+                ChatLimit::Bytes(MAX_LINE_BYTES.saturating_sub(used))
+            }
+        }
+    }
+}
+
+/// How a server compares nicks and channel names, from the CASEMAPPING parameter.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CaseMapping {
+    /// Only `A-Z` and `a-z` are the same. Also used for mappings we don't know, such as
+    /// `rfc7613`, since ASCII letters fold the same way in those too.
+    Ascii,
+    /// `[]\~` are also the same as `{}|^`.
+    Rfc1459,
+    /// `[]\` are also the same as `{}|`.
+    StrictRfc1459,
+}
+
+impl CaseMapping {
+    fn from_value(value: &str) -> Self {
+        match value {
+            "rfc1459" => CaseMapping::Rfc1459,
+            "strict-rfc1459" => CaseMapping::StrictRfc1459,
+            _ => CaseMapping::Ascii,
+        }
+    }
+
+    pub fn fold_char(self, c: char) -> char {
+        match (self, c) {
+            (_, 'A'..='Z') => c.to_ascii_lowercase(),
+            (CaseMapping::Rfc1459, '~') => '^',
+            (CaseMapping::Rfc1459, '[') | (CaseMapping::StrictRfc1459, '[') => '{',
+            (CaseMapping::Rfc1459, ']') | (CaseMapping::StrictRfc1459, ']') => '}',
+            (CaseMapping::Rfc1459, '\\') | (CaseMapping::StrictRfc1459, '\\') => '|',
+            _ => c,
+        }
+    }
+}
+
+/// The parameters a server sent in RPL_ISUPPORT. Until it sends them, the RFC 1459 defaults
+/// apply.
+#[derive(Clone, Debug)]
+pub struct ISupport {
+    // Every parameter the server sent, with its value if it had one.
+    tokens: BTreeMap<String, Option<String>>,
+    chantypes: String,
+    casemapping: CaseMapping,
+    // Membership modes and their prefixes, highest first, e.g. `('o', '@')`.
+    prefixes: Vec<(char, char)>,
+}
+
+fn default_prefixes() -> Vec<(char, char)> {
+    vec![('o', '@'), ('v', '+')]
+}
+
+/// Parses a PREFIX value such as `(ov)@+`.
+fn parse_prefix(value: &str) -> Option<Vec<(char, char)>> {
+    if value.is_empty() {
+        return Some(Vec::new());
+    }
+    let (modes, symbols) = value.strip_prefix('(')?.split_once(')')?;
+    if modes.chars().count() != symbols.chars().count() {
+        return None;
+    }
+    Some(modes.chars().zip(symbols.chars()).collect())
+}
+
+impl ISupport {
+    pub fn new() -> Self {
+        ISupport {
+            tokens: BTreeMap::new(),
+            chantypes: DEFAULT_CHANTYPES.to_string(),
+            casemapping: CaseMapping::Rfc1459,
+            prefixes: default_prefixes(),
+        }
+    }
+
+    /// Updates the parameters from an RPL_ISUPPORT message. Other messages are ignored.
+    pub fn handle(&mut self, msg: &Message) {
+        if !msg.has_command(KnownCommand::RplISupport) {
+            return;
+        }
+        // The first parameter is our nick, and the last is "are supported by this server".
+        let params = msg.params();
+        if params.len() < 2 {
+            return;
+        }
+        for token in &params[1..params.len() - 1] {
+            let token = String::from_utf8_lossy(token.as_ref());
+            match token.strip_prefix('-') {
+                Some(name) => {
+                    self.tokens.remove(name);
+                }
+                None => {
+                    let (name, value) = match token.split_once('=') {
+                        Some((name, value)) => (name, Some(value.to_string())),
+                        None => (&*token, None),
+                    };
+                    self.tokens.insert(name.to_string(), value);
+                }
+            }
+        }
+
+        self.chantypes = match self.tokens.get("CHANTYPES") {
+            Some(value) => value.clone().unwrap_or_default(),
+            None => DEFAULT_CHANTYPES.to_string(),
+        };
+        self.casemapping = self
+            .value("CASEMAPPING")
+            .map_or(CaseMapping::Rfc1459, CaseMapping::from_value);
+        self.prefixes = match self.tokens.get("PREFIX") {
+            Some(value) => {
+                parse_prefix(value.as_deref().unwrap_or("")).unwrap_or_else(default_prefixes)
+            }
+            None => default_prefixes(),
+        };
+    }
+
+    pub fn is_supported(&self, token: &str) -> bool {
+        self.tokens.contains_key(token)
+    }
+
+    /// The value the server sent for a parameter, if it sent one.
+    pub fn value(&self, token: &str) -> Option<&str> {
+        self.tokens.get(token)?.as_deref()
+    }
+
+    /// The characters that start a channel name.
+    pub fn chantypes(&self) -> &str {
+        &self.chantypes
+    }
+
+    pub fn is_channel(&self, name: &str) -> bool {
+        name.chars()
+            .next()
+            .is_some_and(|c| self.chantypes.contains(c))
+    }
+
+    pub fn casemapping(&self) -> CaseMapping {
+        self.casemapping
+    }
+
+    /// Folds a nick or channel name, so that names the server thinks are the same are equal.
+    pub fn casefold(&self, name: &str) -> String {
+        name.chars()
+            .map(|c| self.casemapping.fold_char(c))
+            .collect()
+    }
+
+    pub fn same_name(&self, a: &str, b: &str) -> bool {
+        a.chars()
+            .map(|c| self.casemapping.fold_char(c))
+            .eq(b.chars().map(|c| self.casemapping.fold_char(c)))
+    }
+
+    /// Membership modes and the prefixes that mark them, highest first.
+    pub fn prefixes(&self) -> &[(char, char)] {
+        &self.prefixes
+    }
+
+    /// The membership mode that a prefix marks, e.g. `o` for `@`.
+    pub fn prefix_mode(&self, prefix: char) -> Option<char> {
+        self.prefixes
+            .iter()
+            .find(|(_, symbol)| *symbol == prefix)
+            .map(|(mode, _)| *mode)
+    }
+
+    /// Splits a name from a NAMES reply into its prefixes and the nick. There may be several
+    /// prefixes if the `multi-prefix` cap is on.
+    pub fn split_prefixes<'a>(&self, name: &'a str) -> (&'a str, &'a str) {
+        let nick = name.trim_start_matches(|c| self.prefix_mode(c).is_some());
+        name.split_at(name.len() - nick.len())
+    }
+}
+
+impl Default for ISupport {
+    fn default() -> Self {
+        ISupport::new()
+    }
+}
+
+/// Splits an AUTHENTICATE payload into the pieces to send. A piece shorter than the limit, or
+/// `+` if there is nothing left, tells the server the payload is done.
+fn authenticate_chunks(payload: &str) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = payload;
+    while rest.len() >= SASL_CHUNK_LEN {
+        let (chunk, tail) = rest.split_at(SASL_CHUNK_LEN);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks.push(if rest.is_empty() { "+" } else { rest });
+    chunks
+}
+
+fn param_text(msg: &Message, index: usize) -> String {
+    msg.params()
+        .get(index)
+        .map(|p| String::from_utf8_lossy(p.as_ref()).into_owned())
+        .unwrap_or_default()
+}
+
+fn build<T: IntoIterator<Item = S>, S: AsRef<[u8]>>(cmd: &str, params: T) -> ClientResult<Message> {
+    Ok(MessageBuilder::named(cmd).params(params).build()?)
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Step {
+    /// Waiting for cap negotiation to finish.
+    Negotiating,
+    /// Waiting for the server to accept or refuse our SASL login.
+    Authenticating,
+    /// Waiting for the end of the MOTD.
+    Registering,
+    Done,
+}
+
+/// What we learned while logging in.
+pub(crate) struct Registered {
+    /// The nick the server gave us, which on a generic network may not be the one we asked for.
+    pub nick: String,
+    pub caps: CapNegotiator,
+    pub isupport: ISupport,
+}
+
+/// Logs in to a network, from the first `CAP LS` until the end of the MOTD.
+pub(crate) struct Registration {
+    network: NetworkProfile,
+    caps: CapNegotiator,
+    nick: String,
+    // The account we log in to, which is the nick we asked for first.
+    account: String,
+    token: Option<String>,
+    sasl_mechanism: Option<&'static str>,
+    step: Step,
+    welcomed: bool,
+    // How many other nicks we have tried.
+    nick_attempts: usize,
+    identify_with_nickserv: bool,
+    isupport: ISupport,
+}
+
+impl Registration {
+    /// Logs in as `nick`, with `token` as the password. Without a token, we log in anonymously.
+    pub fn new(network: NetworkProfile, caps: CapConfig, nick: &str, token: Option<&str>) -> Self {
+        let auth = match (&network, token) {
+            (NetworkProfile::Generic(generic), Some(_)) => generic.auth.clone(),
+            _ => Auth::None,
+        };
+        let sasl_mechanism = auth.sasl_mechanism();
+        let caps = match sasl_mechanism {
+            Some(_) => caps.request(SASL),
+            None => caps,
+        };
+        Registration {
+            network,
+            caps: CapNegotiator::new(caps),
+            nick: nick.to_string(),
+            account: nick.to_string(),
+            token: token.map(str::to_string),
+            sasl_mechanism,
+            step: Step::Negotiating,
+            welcomed: false,
+            nick_attempts: 0,
+            identify_with_nickserv: auth == Auth::NickServ,
+            isupport: ISupport::new(),
+        }
+    }
+
+    fn generic(&self) -> Option<&GenericNetwork> {
+        match &self.network {
+            NetworkProfile::Twitch => None,
+            NetworkProfile::Generic(generic) => Some(generic),
+        }
+    }
+
+    /// The messages that start the login. On Twitch, we only say who we are once caps are
+    /// negotiated. Elsewhere, we do it right away, and the server waits for `CAP END` before
+    /// finishing registration.
+    pub fn start(&self) -> ClientResult<Vec<Message>> {
+        let mut messages = vec![self.caps.start()];
+        if let Some(generic) = self.generic() {
+            if let (Auth::ServerPassword, Some(token)) = (&generic.auth, &self.token) {
+                messages.push(build("PASS", [token])?);
+            }
+            messages.push(build("NICK", [&self.nick])?);
+            messages.push(build("USER", [&self.nick, "0", "*", &generic.realname])?);
+        }
+        Ok(messages)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.step == Step::Done
+    }
+
+    /// Handles a message from the server, returning the messages to send in reply.
+    pub fn handle(&mut self, msg: &Message) -> ClientResult<Vec<Message>> {
+        let command = match msg.known_command() {
+            Some(command) => command,
+            None => return Ok(Vec::new()),
+        };
+        let mut replies = Vec::new();
+        match command {
+            KnownCommand::Cap => {
+                replies.extend(self.caps.handle(msg).map_err(LoginError::from)?);
+                if self.step == Step::Negotiating && self.caps.is_done() {
+                    replies.extend(self.end_negotiation()?);
+                }
+            }
+            // The server asks for our credentials with an empty challenge.
+            KnownCommand::Authenticate
+                if self.step == Step::Authenticating && param_text(msg, 0) == "+" =>
+            {
+                replies.extend(self.sasl_response()?);
+            }
+            KnownCommand::RplSaslSuccess | KnownCommand::ErrSaslAlready
+                if self.step == Step::Authenticating =>
+            {
+                self.step = Step::Registering;
+                replies.push(build("CAP", ["END"])?);
+            }
+            KnownCommand::ErrSaslFail
+            | KnownCommand::ErrSaslTooLong
+            | KnownCommand::ErrSaslAborted
+                if self.step == Step::Authenticating =>
+            {
+                let text = param_text(msg, msg.params().len().saturating_sub(1));
+                self.fall_back(LoginError::AuthenticationFailed(text))?;
+                self.step = Step::Registering;
+                replies.push(build("CAP", ["END"])?);
+            }
+            KnownCommand::ErrNicknameInUse
+            | KnownCommand::ErrNickCollision
+            | KnownCommand::ErrErroneousNickname
+                if !self.welcomed && self.generic().is_some() =>
+            {
+                self.next_nick()?;
+                replies.push(build("NICK", [&self.nick])?);
+            }
+            KnownCommand::RplWelcome => {
+                self.welcomed = true;
+                if let Some(nick) = msg.params().first() {
+                    self.nick = String::from_utf8_lossy(nick.as_ref()).into_owned();
+                }
+                // A server that registers us without answering CAP LS doesn't support caps, and
+                // so doesn't support SASL either.
+                if let (Step::Negotiating, Some(mechanism)) = (self.step, self.sasl_mechanism) {
+                    self.fall_back(LoginError::SaslUnavailable(mechanism.to_string()))?;
+                }
+                self.step = Step::Registering;
+            }
+            KnownCommand::RplISupport => self.isupport.handle(msg),
+            KnownCommand::RplEndOfMotd | KnownCommand::ErrNoMotd => {
+                if self.identify_with_nickserv {
+                    let text = format!(
+                        "IDENTIFY {} {}",
+                        self.account,
+                        self.token.as_deref().unwrap_or_default()
+                    );
+                    replies.push(build("PRIVMSG", ["NickServ", &text])?);
+                }
+                self.step = Step::Done;
+            }
+            _ => {}
+        }
+        Ok(replies)
+    }
+
+    pub fn finish(self) -> Registered {
+        Registered {
+            nick: self.nick,
+            caps: self.caps,
+            isupport: self.isupport,
+        }
+    }
+
+    fn end_negotiation(&mut self) -> ClientResult<Vec<Message>> {
+        if self.generic().is_none() {
+            let mut messages = Vec::new();
+            if let Some(token) = &self.token {
+                messages.push(build("PASS", [format!("oauth:{}", token)])?);
+            }
+            messages.push(build("NICK", [&self.nick])?);
+            messages.push(build("CAP", ["END"])?);
+            self.step = Step::Registering;
+            return Ok(messages);
+        }
+
+        if let Some(mechanism) = self.sasl_mechanism {
+            // The cap's value, if there is one, lists the mechanisms the server supports.
+            let offered = self.caps.is_enabled(SASL)
+                && self
+                    .caps
+                    .value(SASL)
+                    .is_none_or(|mechs| mechs.split(',').any(|m| m == mechanism));
+            if offered {
+                self.step = Step::Authenticating;
+                return Ok(vec![build("AUTHENTICATE", [mechanism])?]);
+            }
+            self.fall_back(LoginError::SaslUnavailable(mechanism.to_string()))?;
+        }
+        self.step = Step::Registering;
+        Ok(vec![build("CAP", ["END"])?])
+    }
+
+    fn sasl_response(&self) -> ClientResult<Vec<Message>> {
+        let payload = match self.sasl_mechanism {
+            Some("PLAIN") => base64::encode(format!(
+                "{0}\0{0}\0{1}",
+                self.account,
+                self.token.as_deref().unwrap_or_default()
+            )),
+            _ => String::new(),
+        };
+        authenticate_chunks(&payload)
+            .into_iter()
+            .map(|chunk| build("AUTHENTICATE", [chunk]))
+            .collect()
+    }
+
+    /// Identifies to NickServ later if SASL didn't work out and that is allowed, or fails with
+    /// `error`.
+    fn fall_back(&mut self, error: LoginError) -> Result<(), LoginError> {
+        if self
+            .generic()
+            .is_some_and(|generic| generic.nickserv_fallback)
+        {
+            self.identify_with_nickserv = true;
+            Ok(())
+        } else {
+            Err(error)
+        }
+    }
+
+    fn next_nick(&mut self) -> Result<(), LoginError> {
+        let alt_nicks = self.generic().map_or(&[][..], |generic| &generic.alt_nicks);
+        let attempt = self.nick_attempts;
+        self.nick = if attempt < alt_nicks.len() {
+            alt_nicks[attempt].clone()
+        } else if attempt < alt_nicks.len() + MAX_NICK_SUFFIX {
+            let suffix = "_".repeat(attempt - alt_nicks.len() + 1);
+            format!("{}{}", self.account, suffix)
+        } else {
+            return Err(LoginError::NickUnavailable(self.nick.clone()));
+        };
+        self.nick_attempts += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use minibot_irc_raw::MessageRef;
+
+    fn parse(line: &str) -> Message {
+        MessageRef::parse(line.as_bytes())
+            .unwrap()
+            .to_owned()
+            .unwrap()
+    }
+
+    fn lines(msgs: &[Message]) -> Vec<String> {
+        msgs.iter()
+            .map(|msg| String::from_utf8(msg.to_wire().as_ref().to_vec()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn reads_isupport() {
+        let mut isupport = ISupport::new();
+        assert!(isupport.is_channel("&local"));
+        assert!(isupport.same_name("Nick[away]", "nick{AWAY}"));
+
+        isupport.handle(&parse(
+            ":irc.example.net 005 me CHANTYPES=# CASEMAPPING=ascii PREFIX=(qaohv)~&@%+ \
+             EXCEPTS :are supported by this server",
+        ));
+        assert!(isupport.is_channel("#a"));
+        assert!(!isupport.is_channel("&a"));
+        assert!(!isupport.same_name("Nick[away]", "nick{away}"));
+        assert_eq!(isupport.casefold("Nick[away]"), "nick[away]");
+        assert_eq!(isupport.prefix_mode('%'), Some('h'));
+        assert_eq!(isupport.split_prefixes("~@someone"), ("~@", "someone"));
+        assert!(isupport.is_supported("EXCEPTS"));
+
+        isupport.handle(&parse(
+            ":irc.example.net 005 me -CHANTYPES -PREFIX :are supported",
+        ));
+        assert_eq!(isupport.chantypes(), DEFAULT_CHANTYPES);
+        assert_eq!(isupport.prefixes(), &default_prefixes()[..]);
+    }
+
+    #[test]
+    fn chat_limits_depend_on_the_network() {
+        let isupport = ISupport::new();
+        assert_eq!(
+            NetworkProfile::Twitch.chat_limit("bot", "#room", &isupport),
+            ChatLimit::TWITCH
+        );
+
+        let generic = NetworkProfile::Generic(GenericNetwork::new(Auth::None));
+        // 512 - len(":bot!~<10>@<63> PRIVMSG #room :\r\n")
+        assert_eq!(
+            generic.chat_limit("bot", "#room", &isupport),
+            ChatLimit::Bytes(414)
+        );
+        let mut isupport = ISupport::new();
+        isupport.handle(&parse(
+            ":irc.example.net 005 bot USERLEN=12 HOSTLEN=64 :are supported by this server",
+        ));
+        assert_eq!(
+            generic.chat_limit("bot", "#room", &isupport),
+            ChatLimit::Bytes(411)
+        );
+    }
+
+    #[test]
+    fn logs_in_with_sasl_plain() {
+        let network = NetworkProfile::Generic(GenericNetwork::new(Auth::SaslPlain));
+        let mut registration = Registration::new(network, CapConfig::new(), "bot", Some("hunter2"));
+        assert_eq!(
+            lines(&registration.start().unwrap()),
+            vec!["CAP LS :302", "NICK :bot", "USER bot 0 * :minibot"]
+        );
+
+        let mut exchange = |line: &str| lines(&registration.handle(&parse(line)).unwrap());
+        assert_eq!(
+            exchange("CAP * LS :sasl=PLAIN,EXTERNAL server-time"),
+            vec!["CAP REQ :sasl"]
+        );
+        assert_eq!(exchange("CAP * ACK :sasl"), vec!["AUTHENTICATE :PLAIN"]);
+        assert_eq!(
+            exchange("AUTHENTICATE +"),
+            vec![format!(
+                "AUTHENTICATE :{}",
+                base64::encode("bot\0bot\0hunter2")
+            )]
+        );
+        assert!(exchange(":irc 900 bot bot!b@h bot :You are now logged in").is_empty());
+        assert_eq!(
+            exchange(":irc 903 bot :SASL authentication successful"),
+            vec!["CAP :END"]
+        );
+        assert!(exchange(":irc 001 bot :Welcome").is_empty());
+        assert!(exchange(":irc 376 bot :End of MOTD").is_empty());
+        assert!(registration.is_done());
+    }
+
+    #[test]
+    fn falls_back_to_nickserv_and_other_nicks() {
+        let network = NetworkProfile::Generic(
+            GenericNetwork::new(Auth::SaslPlain)
+                .with_nickserv_fallback()
+                .with_alt_nick("bot2"),
+        );
+        let mut registration = Registration::new(network, CapConfig::new(), "bot", Some("pw"));
+        registration.start().unwrap();
+
+        let mut exchange = |line: &str| lines(&registration.handle(&parse(line)).unwrap());
+        // The server doesn't offer SASL.
+        assert_eq!(exchange("CAP * LS :server-time"), vec!["CAP :END"]);
+        assert_eq!(
+            exchange(":irc 433 * bot :Nickname is already in use"),
+            vec!["NICK :bot2"]
+        );
+        assert_eq!(
+            exchange(":irc 433 * bot2 :Nickname is already in use"),
+            vec!["NICK :bot_"]
+        );
+        exchange(":irc 001 bot_ :Welcome");
+        assert_eq!(
+            exchange(":irc 422 bot_ :MOTD File is missing"),
+            vec!["PRIVMSG NickServ :IDENTIFY bot pw"]
+        );
+        assert_eq!(registration.finish().nick, "bot_");
+
+        let network = NetworkProfile::Generic(GenericNetwork::new(Auth::SaslPlain));
+        let mut registration = Registration::new(network, CapConfig::new(), "bot", Some("pw"));
+        registration.handle(&parse("CAP * LS :sasl")).unwrap();
+        registration.handle(&parse("CAP * ACK :sasl")).unwrap();
+        assert!(matches!(
+            registration.handle(&parse(":irc 904 bot :SASL authentication failed")),
+            Err(crate::client::ClientError::Login(
+                LoginError::AuthenticationFailed(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn long_sasl_payloads_are_split() {
+        let payload = "a".repeat(SASL_CHUNK_LEN * 2);
+        let chunks = authenticate_chunks(&payload);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[2], "+");
+        assert_eq!(authenticate_chunks(""), vec!["+"]);
+        assert_eq!(authenticate_chunks("abc"), vec!["abc"]);
+    }
+}
@@ -0,0 +1,354 @@
+//! Shaping chat on its way out.
+//!
+//! Twitch rejects chat messages over 500 characters, and silently drops a message that is the
+//! same as the previous one we sent to the channel, if that was less than 30 seconds ago. Other
+//! IRC servers cut off lines over 512 bytes instead, and let repeats through. `ChatLimit` breaks
+//! long text into messages that fit, and on Twitch, `RepeatGuard` changes repeats by adding an
+//! invisible character. `Priority` decides which waiting messages the rate limiter lets through
+//! first.
+
+use crate::rate_limit::whisper_recipient;
+use minibot_byte_string::ByteString;
+use minibot_irc_raw::{KnownCommand, Message};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// The longest chat message Twitch accepts, in characters.
+pub const MAX_MESSAGE_CHARS: usize = 500;
+
+/// How long Twitch remembers a message when checking for repeats.
+pub const REPEAT_WINDOW: Duration = Duration::from_secs(30);
+
+// A space and an unassigned character from the Tags block. Twitch keeps it, and chat clients
+// don't show it.
+const DISAMBIGUATOR: &str = " \u{E0000}";
+
+/// The room `split_text` leaves in each message for the disambiguator.
+const DISAMBIGUATOR_CHARS: usize = 2;
+
+/// Messages that are waiting on the rate limiter go out highest priority first. Messages with the
+/// same priority keep their order.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    /// For messages that shouldn't wait behind chat that is already queued, such as an answer
+    /// to a moderator.
+    High,
+}
+
+/// How much text fits in one chat message.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ChatLimit {
+    /// Twitch counts the characters of the text.
+    Chars(usize),
+    /// IRC servers count the bytes of the whole line, so this is what is left of it for the text.
+    Bytes(usize),
+}
+
+impl ChatLimit {
+    /// Twitch's limit, with room left for `RepeatGuard`.
+    pub const TWITCH: ChatLimit = ChatLimit::Chars(MAX_MESSAGE_CHARS - DISAMBIGUATOR_CHARS);
+
+    /// Leaves room for `bytes` more on the line, such as CTCP framing. Twitch only counts the
+    /// text, so a `Chars` limit stays as it is.
+    pub fn less_bytes(self, bytes: usize) -> ChatLimit {
+        match self {
+            ChatLimit::Chars(chars) => ChatLimit::Chars(chars),
+            ChatLimit::Bytes(max) => ChatLimit::Bytes(max.saturating_sub(bytes)),
+        }
+    }
+
+    fn len(self, text: &str) -> usize {
+        match self {
+            ChatLimit::Chars(_) => text.chars().count(),
+            ChatLimit::Bytes(_) => text.len(),
+        }
+    }
+
+    fn max(self) -> usize {
+        match self {
+            ChatLimit::Chars(max) | ChatLimit::Bytes(max) => max,
+        }
+    }
+
+    /// Splits text into messages that fit, between words where possible. Words that are too long
+    /// on their own are split between graphemes, so that emoji and accented letters stay whole.
+    /// Runs of whitespace, including newlines, become single spaces.
+    pub fn split(self, text: &str) -> Vec<String> {
+        // A single character over a limit this small goes out as it is.
+        let max = self.max().max(1);
+        let mut chunks = Chunks {
+            limit: self,
+            max,
+            chunks: Vec::new(),
+            current: String::new(),
+            current_len: 0,
+        };
+        for word in text.split_whitespace() {
+            if self.len(word) <= max {
+                chunks.push(word, true);
+                continue;
+            }
+            for (i, grapheme) in word.graphemes(true).enumerate() {
+                if self.len(grapheme) <= max {
+                    chunks.push(grapheme, i == 0);
+                } else {
+                    // Only absurdly long graphemes get here. Split them between characters
+                    // instead.
+                    for (j, ch) in grapheme.chars().enumerate() {
+                        chunks.push(ch.encode_utf8(&mut [0; 4]), i == 0 && j == 0);
+                    }
+                }
+            }
+        }
+        chunks.flush();
+        chunks.chunks
+    }
+}
+
+struct Chunks {
+    limit: ChatLimit,
+    max: usize,
+    chunks: Vec<String>,
+    current: String,
+    // The length of `current`, as `limit` counts it.
+    current_len: usize,
+}
+
+impl Chunks {
+    fn flush(&mut self) {
+        if !self.current.is_empty() {
+            self.chunks.push(std::mem::take(&mut self.current));
+        }
+        self.current_len = 0;
+    }
+
+    /// Adds a piece that fits in a chunk on its own, starting a new chunk if it doesn't fit in
+    /// this one.
+    fn push(&mut self, piece: &str, after_space: bool) {
+        let len = self.limit.len(piece);
+        let space = (after_space && self.current_len > 0) as usize;
+        if self.current_len + space + len > self.max {
+            self.flush();
+        } else if space > 0 {
+            self.current.push(' ');
+            self.current_len += 1;
+        }
+        self.current.push_str(piece);
+        self.current_len += len;
+    }
+}
+
+/// Splits text into pieces of at most `max_chars` characters, as `ChatLimit::split` does.
+pub fn split_text(text: &str, max_chars: usize) -> Vec<String> {
+    assert!(max_chars > 0, "max_chars must be positive");
+    ChatLimit::Chars(max_chars).split(text)
+}
+
+/// Keeps Twitch from dropping repeated chat messages, by adding an invisible character to a
+/// message that is the same as the previous one sent to its channel.
+pub struct RepeatGuard {
+    window: Duration,
+    // The text of the last message sent to each channel, and when.
+    last_sent: HashMap<ByteString, (ByteString, Instant)>,
+}
+
+impl RepeatGuard {
+    pub fn new(window: Duration) -> Self {
+        RepeatGuard {
+            window,
+            last_sent: HashMap::new(),
+        }
+    }
+
+    /// Changes the message if needed, and records it as sent at `now`. Only chat to channels is
+    /// changed. Commands such as `/ban` and whispers go out as they are.
+    pub fn apply(&mut self, mut msg: Message, now: Instant) -> Message {
+        if !msg.has_command(KnownCommand::Privmsg) || whisper_recipient(&msg).is_some() {
+            return msg;
+        }
+        let (channel, text) = match msg.params() {
+            [channel, text] if channel.as_ref().starts_with(b"#") => (channel.clone(), text),
+            _ => return msg,
+        };
+        if text.as_ref().starts_with(b"/") || text.as_ref().starts_with(b".") {
+            return msg;
+        }
+
+        // Anything older than the window can't make a repeat, and would pile up over channels.
+        let window = self.window;
+        self.last_sent
+            .retain(|_, (_, sent_at)| *sent_at + window > now);
+        let is_repeat = self
+            .last_sent
+            .get(&channel)
+            .is_some_and(|(last, _)| last == text);
+        let text = if is_repeat {
+            disambiguate(text)
+        } else {
+            text.clone()
+        };
+        // Only fails for a message that was invalid to begin with, which the codec rejects.
+        if msg.set_param(1, &text).is_ok() {
+            self.last_sent.insert(channel, (text, now));
+        }
+        msg
+    }
+}
+
+impl Default for RepeatGuard {
+    fn default() -> Self {
+        RepeatGuard::new(REPEAT_WINDOW)
+    }
+}
+
+/// Adds the disambiguator to chat text, cutting graphemes off the end if the text would no longer
+/// fit in `MAX_MESSAGE_CHARS`.
+fn disambiguate(text: &ByteString) -> ByteString {
+    let text = text.as_ref();
+    // For a CTCP ACTION, the character goes inside the closing delimiter, and Twitch doesn't count
+    // the framing (`\x01ACTION `) against the limit.
+    let (body, framing, end): (&[u8], usize, &[u8]) = match text.strip_suffix(b"\x01") {
+        Some(inner) if text.starts_with(b"\x01") => {
+            let framing = inner
+                .iter()
+                .position(|&b| b == b' ')
+                .map_or(inner.len(), |p| p + 1);
+            (inner, framing, b"\x01")
+        }
+        _ => (text, 0, b""),
+    };
+    let body = match std::str::from_utf8(body) {
+        Ok(body) => {
+            trim_to_chars(body, MAX_MESSAGE_CHARS - DISAMBIGUATOR_CHARS + framing).as_bytes()
+        }
+        // Twitch only takes UTF-8, so this won't be delivered anyway.
+        Err(_) => body,
+    };
+
+    let mut changed = Vec::with_capacity(body.len() + DISAMBIGUATOR.len() + end.len());
+    changed.extend_from_slice(body);
+    changed.extend_from_slice(DISAMBIGUATOR.as_bytes());
+    changed.extend_from_slice(end);
+    ByteString::from(changed)
+}
+
+/// Cuts whole graphemes off the end of `text` until it has at most `max_chars` characters.
+fn trim_to_chars(text: &str, max_chars: usize) -> &str {
+    let mut chars = text.chars().count();
+    let mut end = text.len();
+    for (i, grapheme) in text.grapheme_indices(true).rev() {
+        if chars <= max_chars {
+            break;
+        }
+        chars -= grapheme.chars().count();
+        end = i;
+    }
+    text[..end].trim_end()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn splits_between_words_then_graphemes() {
+        assert_eq!(
+            split_text("the quick  brown\nfox", 10),
+            vec!["the quick", "brown fox"]
+        );
+        assert_eq!(
+            split_text("hi abcdefghijkl", 5),
+            vec!["hi ab", "cdefg", "hijkl"]
+        );
+        // Each family emoji is several characters joined into one grapheme.
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        let word = family.repeat(3);
+        assert_eq!(split_text(&word, 6), vec![family; 3]);
+        assert_eq!(split_text("héllo", 2), vec!["hé", "ll", "o"]);
+        assert!(split_text(" \n ", 10).is_empty());
+    }
+
+    #[test]
+    fn byte_limits_count_bytes() {
+        assert_eq!(ChatLimit::Bytes(5).split("héllo"), vec!["héll", "o"]);
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        assert_eq!(
+            ChatLimit::Bytes(19).split(&format!("a {}", family)),
+            vec!["a", family]
+        );
+        assert_eq!(ChatLimit::Bytes(10).less_bytes(4), ChatLimit::Bytes(6));
+        assert_eq!(ChatLimit::TWITCH.less_bytes(9), ChatLimit::TWITCH);
+    }
+
+    #[test]
+    fn repeats_are_changed() {
+        let mut guard = RepeatGuard::default();
+        let now = Instant::now();
+        let msg = Message::from_named_command_params("PRIVMSG", ["#a", "hello"]);
+        let text = |msg: &Message| msg.params()[1].clone();
+
+        let first = guard.apply(msg.clone(), now);
+        assert_eq!(text(&first), text(&msg));
+        let second = guard.apply(msg.clone(), now);
+        assert_ne!(text(&second), text(&msg));
+        // The third one is different from the second, so it goes out as is.
+        let third = guard.apply(msg.clone(), now);
+        assert_eq!(text(&third), text(&msg));
+
+        let later = guard.apply(msg.clone(), now + REPEAT_WINDOW);
+        assert_eq!(text(&later), text(&msg));
+        let elsewhere = Message::from_named_command_params("PRIVMSG", ["#b", "hello"]);
+        assert_eq!(text(&guard.apply(elsewhere.clone(), now)), text(&elsewhere));
+    }
+
+    #[test]
+    fn old_messages_are_forgotten() {
+        let mut guard = RepeatGuard::default();
+        let now = Instant::now();
+        guard.apply(
+            Message::from_named_command_params("PRIVMSG", ["#a", "hello"]),
+            now,
+        );
+        guard.apply(
+            Message::from_named_command_params("PRIVMSG", ["#b", "hello"]),
+            now + REPEAT_WINDOW,
+        );
+        assert_eq!(guard.last_sent.len(), 1);
+    }
+
+    #[test]
+    fn changed_repeats_stay_within_the_limit() {
+        let mut guard = RepeatGuard::default();
+        let now = Instant::now();
+        let mut repeat = |text: &str| {
+            let msg = Message::from_named_command_params("PRIVMSG", ["#a", text]);
+            guard.apply(msg.clone(), now);
+            let changed = guard.apply(msg, now).params()[1].clone();
+            String::from_utf8(changed.as_ref().to_vec()).unwrap()
+        };
+
+        let full = "a".repeat(MAX_MESSAGE_CHARS);
+        let changed = repeat(&full);
+        assert_eq!(changed.chars().count(), MAX_MESSAGE_CHARS);
+        assert!(changed.ends_with(DISAMBIGUATOR));
+
+        // The framing of an action doesn't count, and emoji aren't cut in half.
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        let action = format!(
+            "\x01ACTION {}{}\x01",
+            "a".repeat(MAX_MESSAGE_CHARS - 6),
+            family
+        );
+        let changed = repeat(&action);
+        let expected = format!(
+            "\x01ACTION {}{}\x01",
+            "a".repeat(MAX_MESSAGE_CHARS - 6),
+            DISAMBIGUATOR
+        );
+        assert_eq!(changed, expected);
+    }
+}
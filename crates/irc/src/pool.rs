@@ -0,0 +1,501 @@
+//! Channels spread over several connections.
+//!
+//! `ClientPool` logs in as one user over up to `max_connections` supervised connections, and puts
+//! at most `channels_per_connection` channels on each, opening connections as channels are
+//! joined. When a connection drops, its channels move to connections that are still up, so that
+//! they aren't missed while it reconnects. Events from every connection come out of one stream,
+//! and messages are sent on whichever connection holds their channel.
+//!
+//! Every connection counts against the same rate limits, since Twitch limits JOINs and chat per
+//! account rather than per connection.
+//!
+//! Channel names are compared without regard to case, as Twitch does.
+
+use crate::client::{ClientError, ClientFactory, ClientResult};
+use crate::event::{Event, SlowSubscriberPolicy};
+use crate::futures_util::event_sink::EventSink;
+use crate::supervisor::{ConnectionState, SupervisedClient, SupervisorConfig};
+use futures::channel::{mpsc, oneshot};
+use futures::prelude::*;
+use futures::select;
+use futures::stream::SelectAll;
+use minibot_irc_raw::Message;
+use std::collections::{BTreeMap, BTreeSet};
+use std::pin::Pin;
+
+#[derive(Copy, Clone, Debug)]
+pub struct PoolConfig {
+    pub max_connections: usize,
+    /// How many channels to put on a connection before opening another one. Once
+    /// `max_connections` are open, channels go on the least loaded connection regardless.
+    pub channels_per_connection: usize,
+    /// How each connection reconnects, and what it does with messages while it is down.
+    pub supervisor: SupervisorConfig,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_connections: 20,
+            channels_per_connection: 50,
+            supervisor: SupervisorConfig::default(),
+        }
+    }
+}
+
+/// Problems the pool runs into without a caller to report them to.
+#[derive(Clone, Debug)]
+pub enum PoolEvent {
+    /// A connection gave up for good, or a new one couldn't be opened.
+    ConnectionFailed(String),
+    /// A channel couldn't be moved off a connection that went down. It stays there, and is
+    /// rejoined once that connection is back.
+    MoveFailed { channel: String, error: String },
+    /// A channel was left because no connection could take it. Joining it again retries.
+    ChannelDropped { channel: String, error: String },
+}
+
+enum Request {
+    Join(String, oneshot::Sender<ClientResult<()>>),
+    Part(String, oneshot::Sender<ClientResult<()>>),
+    Send(String, Message, oneshot::Sender<ClientResult<()>>),
+    Connections(oneshot::Sender<Vec<Vec<String>>>),
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Status {
+    /// Opened, but not connected yet.
+    Starting,
+    Up,
+    Down,
+}
+
+struct Shard {
+    client: SupervisedClient,
+    channels: BTreeSet<String>,
+    status: Status,
+}
+
+struct Endpoint {
+    factory: ClientFactory,
+    host: String,
+    port: u16,
+    user: String,
+    token: String,
+}
+
+type StateStream = Pin<Box<dyn Stream<Item = (u64, ConnectionState)> + Send>>;
+
+struct Pool {
+    endpoint: Endpoint,
+    config: PoolConfig,
+    next_id: u64,
+    shards: BTreeMap<u64, Shard>,
+    events: mpsc::Sender<Event>,
+    pool_events: mpsc::Sender<PoolEvent>,
+}
+
+impl Pool {
+    async fn report(&mut self, event: PoolEvent) {
+        let _ = self.pool_events.send(event).await;
+    }
+
+    fn shard_of(&self, channel: &str) -> Option<u64> {
+        self.shards
+            .iter()
+            .find(|(_, shard)| shard.channels.contains(channel))
+            .map(|(id, _)| *id)
+    }
+
+    fn open_shard(&mut self, states: &mut SelectAll<StateStream>) -> ClientResult<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let mut client = SupervisedClient::start(
+            self.endpoint.factory.clone(),
+            &self.endpoint.host,
+            self.endpoint.port,
+            &self.endpoint.user,
+            &self.endpoint.token,
+            self.config.supervisor,
+        );
+        // Roomy, since the pool stops reading states while it waits on a connection.
+        let (states_sink, shard_states) = mpsc::channel(16);
+        client.add_state_listener(states_sink)?;
+        client.add_event_listener(self.events.clone(), SlowSubscriberPolicy::Block)?;
+        states.push(Box::pin(shard_states.map(move |state| (id, state))));
+        self.shards.insert(
+            id,
+            Shard {
+                client,
+                channels: BTreeSet::new(),
+                status: Status::Starting,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Picks a connection other than `exclude` for a channel: the least loaded one with room that
+    /// is up, or failing that, still starting. Otherwise opens a new connection, if there can be
+    /// more. Returns `None` if none of that worked.
+    fn place(
+        &mut self,
+        exclude: Option<u64>,
+        states: &mut SelectAll<StateStream>,
+    ) -> ClientResult<Option<u64>> {
+        let per_connection = self.config.channels_per_connection;
+        for status in &[Status::Up, Status::Starting] {
+            let found = self
+                .shards
+                .iter()
+                .filter(|(id, shard)| {
+                    Some(**id) != exclude
+                        && shard.status == *status
+                        && shard.channels.len() < per_connection
+                })
+                .min_by_key(|(_, shard)| shard.channels.len())
+                .map(|(id, _)| *id);
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+        if self.shards.len() < self.config.max_connections {
+            return Ok(Some(self.open_shard(states)?));
+        }
+        Ok(None)
+    }
+
+    async fn join(
+        &mut self,
+        channel: String,
+        states: &mut SelectAll<StateStream>,
+    ) -> ClientResult<()> {
+        if self.shard_of(&channel).is_some() {
+            return Ok(());
+        }
+        let id = match self.place(None, states)? {
+            Some(id) => id,
+            // Everything is full or down, so overload whatever is least loaded. The supervisor
+            // joins the channel once it is back.
+            None => self
+                .shards
+                .iter()
+                .min_by_key(|(_, shard)| shard.channels.len())
+                .map(|(id, _)| *id)
+                .ok_or(ClientError::NotConnected)?,
+        };
+        let shard = self.shards.get_mut(&id).unwrap();
+        shard.client.join(&channel).await?;
+        shard.channels.insert(channel);
+        Ok(())
+    }
+
+    async fn part(&mut self, channel: &str) -> ClientResult<()> {
+        let id = self
+            .shard_of(channel)
+            .ok_or_else(|| ClientError::NotInChannel(channel.to_string()))?;
+        let shard = self.shards.get_mut(&id).unwrap();
+        shard.channels.remove(channel);
+        shard.client.part(channel).await
+    }
+
+    async fn send(&mut self, channel: &str, msg: Message) -> ClientResult<()> {
+        let id = self
+            .shard_of(channel)
+            .ok_or_else(|| ClientError::NotInChannel(channel.to_string()))?;
+        self.shards.get_mut(&id).unwrap().client.send(msg).await
+    }
+
+    /// Moves a channel to another connection. If it can't be joined there, it stays where it
+    /// was. Returns whether it moved.
+    async fn move_channel(&mut self, channel: String, from: u64, to: u64) -> bool {
+        let shard = self.shards.get_mut(&to).unwrap();
+        if let Err(e) = shard.client.join(&channel).await {
+            let error = e.to_string();
+            self.report(PoolEvent::MoveFailed { channel, error }).await;
+            return false;
+        }
+        shard.channels.insert(channel.clone());
+
+        let shard = self.shards.get_mut(&from).unwrap();
+        shard.channels.remove(&channel);
+        // If the connection is down, this only keeps it from rejoining the channel later.
+        let _ = shard.client.part(&channel).await;
+        true
+    }
+
+    /// Moves the channels of a connection that went down to connections that are up, as far as
+    /// there is room. If none are up, the channels stay where they are: the network is likely
+    /// down, and new connections would only go down as well.
+    async fn rebalance(&mut self, from: u64, states: &mut SelectAll<StateStream>) {
+        if !self.shards.values().any(|shard| shard.status == Status::Up) {
+            return;
+        }
+        let channels = match self.shards.get(&from) {
+            Some(shard) => shard.channels.clone(),
+            None => return,
+        };
+        for channel in channels {
+            let to = match self.place(Some(from), states) {
+                Ok(Some(to)) => to,
+                Ok(None) => break,
+                Err(e) => {
+                    self.report(PoolEvent::ConnectionFailed(e.to_string()))
+                        .await;
+                    break;
+                }
+            };
+            self.move_channel(channel, from, to).await;
+        }
+    }
+
+    /// Fills a connection that is up with channels from connections that are down, and then with
+    /// channels that didn't fit anywhere when they were joined, as far as there is room.
+    async fn refill(&mut self, to: u64) {
+        let per_connection = self.config.channels_per_connection;
+        loop {
+            let has_room = self
+                .shards
+                .get(&to)
+                .is_some_and(|shard| shard.channels.len() < per_connection);
+            if !has_room {
+                break;
+            }
+            let mut others = self.shards.iter().filter(|(id, _)| **id != to);
+            let from = others
+                .clone()
+                .find(|(_, shard)| shard.status == Status::Down && !shard.channels.is_empty())
+                .or_else(|| others.find(|(_, shard)| shard.channels.len() > per_connection));
+            let (from, channel) = match from {
+                Some((id, shard)) => (*id, shard.channels.iter().next().unwrap().clone()),
+                None => break,
+            };
+            if !self.move_channel(channel, from, to).await {
+                break;
+            }
+        }
+    }
+
+    async fn handle_state(
+        &mut self,
+        id: u64,
+        state: ConnectionState,
+        states: &mut SelectAll<StateStream>,
+    ) {
+        let shard = match self.shards.get_mut(&id) {
+            Some(shard) => shard,
+            None => return,
+        };
+        match state {
+            ConnectionState::Connected => {
+                shard.status = Status::Up;
+                self.refill(id).await;
+            }
+            ConnectionState::Disconnected(_) | ConnectionState::Waiting(_) => {
+                shard.status = Status::Down;
+                self.rebalance(id, states).await;
+            }
+            // Retrying won't help, and the other connections log in the same way, so their
+            // channels can't be moved to a new one. They go to the others, if there are any.
+            ConnectionState::Failed(reason) => {
+                self.report(PoolEvent::ConnectionFailed(reason)).await;
+                let shard = self.shards.remove(&id).unwrap();
+                let _ = shard.client.close().await;
+                for channel in shard.channels {
+                    let to = self
+                        .shards
+                        .iter()
+                        .filter(|(_, shard)| shard.status != Status::Down)
+                        .min_by_key(|(_, shard)| shard.channels.len())
+                        .map(|(id, _)| *id);
+                    let error = match to {
+                        Some(to) => {
+                            let shard = self.shards.get_mut(&to).unwrap();
+                            match shard.client.join(&channel).await {
+                                Ok(()) => {
+                                    shard.channels.insert(channel);
+                                    continue;
+                                }
+                                Err(e) => e.to_string(),
+                            }
+                        }
+                        None => "No connections left".to_string(),
+                    };
+                    self.report(PoolEvent::ChannelDropped { channel, error })
+                        .await;
+                }
+            }
+            ConnectionState::Connecting { .. } | ConnectionState::Closed => {}
+        }
+    }
+
+    async fn handle_request(&mut self, request: Request, states: &mut SelectAll<StateStream>) {
+        match request {
+            Request::Join(channel, reply) => {
+                let _ = reply.send(self.join(channel, states).await);
+            }
+            Request::Part(channel, reply) => {
+                let _ = reply.send(self.part(&channel).await);
+            }
+            Request::Send(channel, msg, reply) => {
+                let _ = reply.send(self.send(&channel, msg).await);
+            }
+            Request::Connections(reply) => {
+                let connections = self
+                    .shards
+                    .values()
+                    .map(|shard| shard.channels.iter().cloned().collect())
+                    .collect();
+                let _ = reply.send(connections);
+            }
+        }
+    }
+
+    async fn close(self) {
+        for (_, shard) in self.shards {
+            let _ = shard.client.close().await;
+        }
+    }
+}
+
+async fn run_pool(mut pool: Pool, mut requests: mpsc::Receiver<Request>) {
+    let mut states = SelectAll::<StateStream>::new();
+    loop {
+        select! {
+            request = requests.next() => match request {
+                Some(request) => pool.handle_request(request, &mut states).await,
+                None => break,
+            },
+            state = states.next() => {
+                if let Some((id, state)) = state {
+                    pool.handle_state(id, state, &mut states).await;
+                }
+            }
+        }
+    }
+    pool.close().await;
+}
+
+struct PoolInner {
+    requests: mpsc::Sender<Request>,
+    inbound_channel: EventSink<Event>,
+    pool_events_channel: EventSink<PoolEvent>,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+pub struct ClientPool(Option<PoolInner>);
+
+impl ClientPool {
+    /// Starts a pool with no connections. The first is opened by the first join.
+    pub fn start(
+        factory: ClientFactory,
+        host: &str,
+        port: u16,
+        user: &str,
+        token: &str,
+        config: PoolConfig,
+    ) -> Self {
+        let (requests, requests_stream) = mpsc::channel(3);
+        let (events_sink, events_stream) = mpsc::channel(3);
+        let (pool_events_sink, pool_events_stream) = mpsc::channel(3);
+
+        let pool = Pool {
+            endpoint: Endpoint {
+                factory: factory.with_shared_rate_limits(),
+                host: host.to_string(),
+                port,
+                user: user.to_string(),
+                token: token.to_string(),
+            },
+            config,
+            next_id: 0,
+            shards: BTreeMap::new(),
+            events: events_sink,
+            pool_events: pool_events_sink,
+        };
+        let handle = tokio::spawn(run_pool(pool, requests_stream));
+
+        ClientPool(Some(PoolInner {
+            requests,
+            inbound_channel: EventSink::new(events_stream),
+            pool_events_channel: EventSink::new(pool_events_stream),
+            handle,
+        }))
+    }
+
+    fn get_inner_mut(&mut self) -> ClientResult<&mut PoolInner> {
+        self.0.as_mut().ok_or(ClientError::AlreadyClosed)
+    }
+
+    /// Adds a listener for events from every connection.
+    pub fn add_event_listener(
+        &mut self,
+        listener: mpsc::Sender<Event>,
+        policy: SlowSubscriberPolicy,
+    ) -> ClientResult<()> {
+        self.get_inner_mut()?
+            .inbound_channel
+            .add_sink_with_policy(listener, policy);
+        Ok(())
+    }
+
+    /// Adds a listener for problems with connections and channels, such as a channel that had to
+    /// be left because no connection could take it.
+    pub fn add_pool_listener(&mut self, listener: mpsc::Sender<PoolEvent>) -> ClientResult<()> {
+        self.get_inner_mut()?.pool_events_channel.add_sink(listener);
+        Ok(())
+    }
+
+    async fn request<T>(
+        &mut self,
+        make_request: impl FnOnce(oneshot::Sender<T>) -> Request,
+    ) -> ClientResult<T> {
+        let (reply, reply_recv) = oneshot::channel();
+        self.get_inner_mut()?
+            .requests
+            .send(make_request(reply))
+            .await
+            .map_err(|_| ClientError::AlreadyClosed)?;
+        reply_recv.await.map_err(|_| ClientError::AlreadyClosed)
+    }
+
+    /// Joins a channel on the connection with the most room, opening one if needed.
+    pub async fn join(&mut self, channel: &str) -> ClientResult<()> {
+        let channel = channel.to_lowercase();
+        self.request(|reply| Request::Join(channel, reply)).await?
+    }
+
+    pub async fn part(&mut self, channel: &str) -> ClientResult<()> {
+        let channel = channel.to_lowercase();
+        self.request(|reply| Request::Part(channel, reply)).await?
+    }
+
+    /// Sends a message on the connection that `channel` is joined on. Fails with
+    /// `ClientError::NotInChannel` if the pool hasn't joined it.
+    pub async fn send(&mut self, channel: &str, msg: Message) -> ClientResult<()> {
+        msg.validate()?;
+        let channel = channel.to_lowercase();
+        self.request(|reply| Request::Send(channel, msg, reply))
+            .await?
+    }
+
+    /// The channels on each open connection.
+    pub async fn connections(&mut self) -> ClientResult<Vec<Vec<String>>> {
+        self.request(Request::Connections).await
+    }
+
+    pub async fn close(mut self) -> ClientResult<()> {
+        let PoolInner { handle, .. } = self.0.take().unwrap();
+        handle.await?;
+        Ok(())
+    }
+}
+
+impl Drop for ClientPool {
+    fn drop(&mut self) {
+        assert!(
+            self.0.is_none(),
+            "ClientPool was dropped without being waited on."
+        )
+    }
+}
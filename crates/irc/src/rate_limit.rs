@@ -0,0 +1,629 @@
+//! Outbound rate limiting for Twitch.
+//!
+//! Twitch drops messages, and eventually locks the account out, when a bot sends too fast. The
+//! limits are per account, and some depend on the channel:
+//!
+//! - Chat messages: 20 per 30 seconds, or 100 per 30 seconds in channels where we are a moderator
+//!   or the broadcaster. We learn which from USERSTATE.
+//! - JOINs: 20 per 10 seconds.
+//! - Slow mode: in a channel with slow mode on (from ROOMSTATE), one message per slow mode delay,
+//!   unless we are a moderator there.
+//! - Whispers: 3 per second and 100 per minute, counted separately from chat, to at most 40
+//!   different users per day.
+//!
+//! Other IRC servers don't have whispers or per-channel limits, and count every message against
+//! one flood limit instead. `RateLimitConfig::generic` holds clients to that.
+//!
+//! Like `TokenSource` in the server crate, each limit is a pool of tokens that are taken before
+//! sending. A token comes back a full period after it was taken, so no window of that length ever
+//! sees more than the limit. Messages that are over a limit are delayed, never dropped. While
+//! messages wait, the ones with the highest `Priority` go first.
+
+use crate::outbound::Priority;
+use crate::room_state::UserState;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use minibot_irc_raw::twitch_tags::TwitchTags;
+use minibot_irc_raw::{KnownCommand, Message};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Limit {
+    pub count: usize,
+    pub period: Duration,
+}
+
+impl Limit {
+    pub const fn new(count: usize, period: Duration) -> Self {
+        Limit { count, period }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct RateLimitConfig {
+    /// Chat messages in channels where we aren't privileged.
+    pub chat: Limit,
+    /// Chat messages in channels where we are a moderator or the broadcaster.
+    pub privileged_chat: Limit,
+    pub join: Limit,
+    pub whisper_burst: Limit,
+    pub whisper: Limit,
+    /// How many different users we can whisper to.
+    pub whisper_recipients: Limit,
+    /// Whether Twitch's rules apply: a PRIVMSG with a `/w` command is a whisper with limits of its
+    /// own, and only chat to channels counts against the chat limits. Otherwise, every PRIVMSG
+    /// and NOTICE counts against `chat`.
+    pub twitch: bool,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            chat: Limit::new(20, Duration::from_secs(30)),
+            privileged_chat: Limit::new(100, Duration::from_secs(30)),
+            join: Limit::new(20, Duration::from_secs(10)),
+            whisper_burst: Limit::new(3, Duration::from_secs(1)),
+            whisper: Limit::new(100, Duration::from_secs(60)),
+            whisper_recipients: Limit::new(40, Duration::from_secs(24 * 60 * 60)),
+            twitch: true,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    /// Limits for other IRC servers, whose flood protection typically lets a few messages through
+    /// at once and then about one every two seconds.
+    pub fn generic() -> Self {
+        RateLimitConfig {
+            chat: Limit::new(5, Duration::from_secs(10)),
+            privileged_chat: Limit::new(5, Duration::from_secs(10)),
+            join: Limit::new(5, Duration::from_secs(10)),
+            twitch: false,
+            ..RateLimitConfig::default()
+        }
+    }
+}
+
+struct TokenBucket {
+    limit: Limit,
+    // When each token that is still out was taken, oldest first.
+    taken: VecDeque<Instant>,
+}
+
+impl TokenBucket {
+    fn new(limit: Limit) -> Self {
+        TokenBucket {
+            limit,
+            taken: VecDeque::new(),
+        }
+    }
+
+    /// How long until `count` tokens are available, or `None` if they are available now.
+    fn wait_time(&mut self, count: usize, now: Instant) -> Option<Duration> {
+        while let Some(&oldest) = self.taken.front() {
+            if oldest + self.limit.period > now {
+                break;
+            }
+            self.taken.pop_front();
+        }
+
+        // A request for more than the whole bucket waits for the whole bucket.
+        let count = count.min(self.limit.count);
+        let available = self.limit.count - self.taken.len();
+        if count <= available {
+            return None;
+        }
+        let returned_at = self.taken[count - available - 1] + self.limit.period;
+        Some(returned_at - now)
+    }
+
+    fn take(&mut self, count: usize, now: Instant) {
+        for _ in 0..count.min(self.limit.count) {
+            self.taken.push_back(now);
+        }
+    }
+}
+
+#[derive(Default)]
+struct ChannelLimits {
+    privileged: bool,
+    slow: Duration,
+    last_sent: Option<Instant>,
+}
+
+pub struct RateLimiter {
+    // Every chat message takes from the privileged bucket, since that limit covers all channels.
+    // Messages to channels where we aren't privileged also take from the lower limit.
+    chat: TokenBucket,
+    privileged_chat: TokenBucket,
+    join: TokenBucket,
+    channels: BTreeMap<String, ChannelLimits>,
+    whisper_burst: TokenBucket,
+    whisper: TokenBucket,
+    whisper_recipients: Limit,
+    twitch: bool,
+    // When we first whispered each recent recipient, oldest first.
+    recent_recipients: VecDeque<(String, Instant)>,
+}
+
+fn param_text(msg: &Message, index: usize) -> Option<&str> {
+    std::str::from_utf8(msg.params().get(index)?.as_ref()).ok()
+}
+
+/// Returns the recipient if the message is a whisper, which Twitch takes as a `/w` command in a
+/// PRIVMSG.
+pub(crate) fn whisper_recipient(msg: &Message) -> Option<&str> {
+    if !msg.has_command(KnownCommand::Privmsg) {
+        return None;
+    }
+    let text = param_text(msg, 1)?;
+    let rest = text
+        .strip_prefix("/w ")
+        .or_else(|| text.strip_prefix("/whisper "))?;
+    rest.split_whitespace().next()
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            chat: TokenBucket::new(config.chat),
+            privileged_chat: TokenBucket::new(config.privileged_chat),
+            join: TokenBucket::new(config.join),
+            channels: BTreeMap::new(),
+            whisper_burst: TokenBucket::new(config.whisper_burst),
+            whisper: TokenBucket::new(config.whisper),
+            whisper_recipients: config.whisper_recipients,
+            twitch: config.twitch,
+            recent_recipients: VecDeque::new(),
+        }
+    }
+
+    /// Returns false if whispering `user` would go over the daily limit on whisper recipients.
+    /// Unlike the other limits, this one is checked up front: waiting out the rest of the day
+    /// would hold up every other message.
+    pub fn can_whisper(&mut self, user: &str, now: Instant) -> bool {
+        let period = self.whisper_recipients.period;
+        while let Some((_, first_sent)) = self.recent_recipients.front() {
+            if *first_sent + period > now {
+                break;
+            }
+            self.recent_recipients.pop_front();
+        }
+
+        let user = user.to_lowercase();
+        self.recent_recipients.iter().any(|(name, _)| *name == user)
+            || self.recent_recipients.len() < self.whisper_recipients.count
+    }
+
+    /// Counts `user` against the daily limit on whisper recipients. Returns false if they would be
+    /// one too many.
+    pub fn add_whisper_recipient(&mut self, user: &str, now: Instant) -> bool {
+        if !self.can_whisper(user, now) {
+            return false;
+        }
+        let user = user.to_lowercase();
+        if !self.recent_recipients.iter().any(|(name, _)| *name == user) {
+            self.recent_recipients.push_back((user, now));
+        }
+        true
+    }
+
+    /// Updates channel state from a message sent by the server. USERSTATE tells us whether we
+    /// are privileged in a channel, and ROOMSTATE tells us its slow mode setting.
+    pub fn observe(&mut self, msg: &Message) {
+        let channel = match param_text(msg, 0) {
+            Some(channel) => channel,
+            None => return,
+        };
+        let tags = TwitchTags::new(msg);
+
+        match msg.known_command() {
+            Some(KnownCommand::UserState) => {
+                self.channel_mut(channel).privileged = UserState::from_tags("", msg).can_moderate();
+            }
+            // ROOMSTATE updates only carry the settings that changed.
+            Some(KnownCommand::RoomState) => {
+                if let Ok(Some(slow)) = tags.slow() {
+                    self.channel_mut(channel).slow = slow;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Keyed by the lowercase name, since Twitch doesn't care how a channel is written.
+    fn channel_mut(&mut self, channel: &str) -> &mut ChannelLimits {
+        self.channels.entry(channel.to_lowercase()).or_default()
+    }
+
+    /// Takes the tokens needed to send `msg`, if they are all available. Otherwise returns how
+    /// long to wait before trying again, and takes nothing.
+    pub fn try_acquire(&mut self, msg: &Message, now: Instant) -> Result<(), Duration> {
+        if !self.twitch {
+            return self.try_acquire_generic(msg, now);
+        }
+        if whisper_recipient(msg).is_some() {
+            let wait = self
+                .whisper_burst
+                .wait_time(1, now)
+                .max(self.whisper.wait_time(1, now));
+            if let Some(wait) = wait {
+                return Err(wait);
+            }
+            self.whisper_burst.take(1, now);
+            self.whisper.take(1, now);
+            return Ok(());
+        }
+
+        match msg.known_command() {
+            Some(KnownCommand::Privmsg) => {
+                let channel = match param_text(msg, 0) {
+                    Some(channel) if channel.starts_with('#') => channel,
+                    _ => return Ok(()),
+                };
+                let limits = self.channels.get(&channel.to_lowercase());
+                let privileged = limits.is_some_and(|l| l.privileged);
+
+                let mut wait = self.privileged_chat.wait_time(1, now);
+                if !privileged {
+                    wait = wait.max(self.chat.wait_time(1, now));
+                    if let Some(ChannelLimits {
+                        slow,
+                        last_sent: Some(last_sent),
+                        ..
+                    }) = limits
+                    {
+                        let ready_at = *last_sent + *slow;
+                        if ready_at > now {
+                            wait = wait.max(Some(ready_at - now));
+                        }
+                    }
+                }
+                if let Some(wait) = wait {
+                    return Err(wait);
+                }
+
+                self.privileged_chat.take(1, now);
+                if !privileged {
+                    self.chat.take(1, now);
+                }
+                self.channel_mut(channel).last_sent = Some(now);
+                Ok(())
+            }
+            Some(KnownCommand::Join) => {
+                let count = param_text(msg, 0).map_or(1, |channels| channels.split(',').count());
+                if let Some(wait) = self.join.wait_time(count, now) {
+                    return Err(wait);
+                }
+                self.join.take(count, now);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// `try_acquire` for servers other than Twitch. Every message to anyone counts as chat, and
+    /// a `/w` is just text.
+    fn try_acquire_generic(&mut self, msg: &Message, now: Instant) -> Result<(), Duration> {
+        let bucket = match msg.known_command() {
+            Some(KnownCommand::Privmsg) | Some(KnownCommand::Notice) => &mut self.chat,
+            Some(KnownCommand::Join) => &mut self.join,
+            _ => return Ok(()),
+        };
+        if let Some(wait) = bucket.wait_time(1, now) {
+            return Err(wait);
+        }
+        bucket.take(1, now);
+        Ok(())
+    }
+}
+
+/// Orders waiting messages so that the one that should go next is last: the highest priority,
+/// and the oldest among messages with that priority.
+type WaitingKey = (Priority, Reverse<u64>);
+
+/// The target a message has to stay in order with, such as its channel. Messages with no target
+/// stay in order with every other message.
+fn lane(msg: &Message) -> Option<String> {
+    match msg.known_command()? {
+        KnownCommand::Privmsg | KnownCommand::Notice | KnownCommand::Join | KnownCommand::Part => {
+            Some(param_text(msg, 0)?.to_lowercase())
+        }
+        _ => None,
+    }
+}
+
+struct Queue<S> {
+    messages: S,
+    ended: bool,
+    waiting: BTreeMap<WaitingKey, Message>,
+    next_seq: u64,
+}
+
+impl<S> Queue<S> {
+    fn push(&mut self, (priority, msg): (Priority, Message)) {
+        self.waiting.insert((priority, Reverse(self.next_seq)), msg);
+        self.next_seq += 1;
+    }
+
+    /// Takes the first waiting message that the limiter allows and that doesn't have to wait for
+    /// one ahead of it in its lane. If there is none, returns how long until the limiter might
+    /// allow one.
+    fn try_pop(&mut self, limiter: &mut RateLimiter, now: Instant) -> Result<Message, Duration> {
+        // The lanes of the messages that are held up. `None` holds up every lane.
+        let mut held: Vec<Option<String>> = Vec::new();
+        let mut shortest_wait = None::<Duration>;
+        let mut ready = None;
+        for (key, msg) in self.waiting.iter().rev() {
+            let lane = lane(msg);
+            if held
+                .iter()
+                .any(|held| held.is_none() || lane.is_none() || *held == lane)
+            {
+                held.push(lane);
+                continue;
+            }
+            match limiter.try_acquire(msg, now) {
+                Ok(()) => {
+                    ready = Some(*key);
+                    break;
+                }
+                Err(wait) => {
+                    shortest_wait = Some(shortest_wait.map_or(wait, |s| s.min(wait)));
+                    held.push(lane);
+                }
+            }
+        }
+
+        match ready {
+            Some(key) => Ok(self.waiting.remove(&key).unwrap()),
+            None => Err(shortest_wait.unwrap_or_default()),
+        }
+    }
+}
+
+/// Delays messages from the stream until the limiter allows them. The waiting message with the
+/// highest priority goes next, and messages with the same priority keep their order. When the
+/// limiter holds a message back, such as for slow mode in its channel, the messages behind it
+/// that are for other channels can go ahead. Messages for the same channel never pass each other
+/// unless they have a higher priority.
+pub fn limit<S>(messages: S, limiter: Arc<Mutex<RateLimiter>>) -> impl Stream<Item = Message>
+where
+    S: Stream<Item = (Priority, Message)> + Unpin,
+{
+    let queue = Queue {
+        messages,
+        ended: false,
+        waiting: BTreeMap::new(),
+        next_seq: 0,
+    };
+    stream::unfold((queue, limiter), |(mut queue, limiter)| async move {
+        loop {
+            while !queue.ended {
+                match queue.messages.next().now_or_never() {
+                    Some(Some(msg)) => queue.push(msg),
+                    Some(None) => queue.ended = true,
+                    None => break,
+                }
+            }
+
+            if queue.waiting.is_empty() {
+                if queue.ended {
+                    return None;
+                }
+                match queue.messages.next().await {
+                    Some(msg) => queue.push(msg),
+                    None => queue.ended = true,
+                }
+                continue;
+            }
+            let popped = queue.try_pop(&mut limiter.lock().unwrap(), Instant::now());
+            match popped {
+                Ok(msg) => return Some((msg, (queue, limiter))),
+                Err(wait) if queue.ended => tokio::time::sleep(wait).await,
+                // A message that arrives in the meantime might need to go first.
+                Err(wait) => {
+                    let sleep = tokio::time::sleep(wait);
+                    futures::pin_mut!(sleep);
+                    match future::select(sleep, queue.messages.next()).await {
+                        Either::Left(_) => {}
+                        Either::Right((Some(msg), _)) => queue.push(msg),
+                        Either::Right((None, _)) => queue.ended = true,
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use minibot_irc_raw::MessageRef;
+
+    fn parse(line: &str) -> Message {
+        MessageRef::parse(line.as_bytes())
+            .unwrap()
+            .to_owned()
+            .unwrap()
+    }
+
+    #[test]
+    fn tokens_come_back_after_the_period() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(Limit::new(2, Duration::from_secs(10)));
+        bucket.take(1, start);
+        bucket.take(1, start + Duration::from_secs(4));
+        assert_eq!(
+            bucket.wait_time(1, start + Duration::from_secs(5)),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            bucket.wait_time(2, start + Duration::from_secs(5)),
+            Some(Duration::from_secs(9))
+        );
+        assert_eq!(bucket.wait_time(1, start + Duration::from_secs(10)), None);
+    }
+
+    #[test]
+    fn moderators_get_the_higher_limit() {
+        let config = RateLimitConfig {
+            chat: Limit::new(1, Duration::from_secs(30)),
+            privileged_chat: Limit::new(3, Duration::from_secs(30)),
+            ..RateLimitConfig::default()
+        };
+        let mut limiter = RateLimiter::new(config);
+        let now = Instant::now();
+        let to_a = parse("PRIVMSG #a :hi");
+        let to_b = parse("PRIVMSG #b :hi");
+
+        limiter.observe(&parse("@mod=1 :tmi.twitch.tv USERSTATE #b"));
+        assert!(limiter.try_acquire(&to_a, now).is_ok());
+        assert!(limiter.try_acquire(&to_a, now).is_err());
+        assert!(limiter.try_acquire(&to_b, now).is_ok());
+        assert!(limiter.try_acquire(&to_b, now).is_ok());
+        // The higher limit still covers every channel.
+        assert!(limiter.try_acquire(&to_b, now).is_err());
+    }
+
+    #[test]
+    fn whispers_have_their_own_limits() {
+        let config = RateLimitConfig {
+            chat: Limit::new(1, Duration::from_secs(30)),
+            whisper_burst: Limit::new(2, Duration::from_secs(1)),
+            whisper_recipients: Limit::new(1, Duration::from_secs(60)),
+            ..RateLimitConfig::default()
+        };
+        let mut limiter = RateLimiter::new(config);
+        let now = Instant::now();
+        let whisper = parse("PRIVMSG #jtv :/w someone hello");
+        assert_eq!(whisper_recipient(&whisper), Some("someone"));
+
+        assert!(limiter.try_acquire(&parse("PRIVMSG #a :hi"), now).is_ok());
+        assert!(limiter.try_acquire(&whisper, now).is_ok());
+        assert!(limiter.try_acquire(&whisper, now).is_ok());
+        assert_eq!(
+            limiter.try_acquire(&whisper, now),
+            Err(Duration::from_secs(1))
+        );
+
+        assert!(limiter.can_whisper("someone", now));
+        assert!(limiter.add_whisper_recipient("someone", now));
+        assert!(limiter.add_whisper_recipient("SomeOne", now));
+        assert!(!limiter.can_whisper("other", now));
+        assert!(!limiter.add_whisper_recipient("other", now));
+        assert!(limiter.add_whisper_recipient("other", now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn generic_servers_count_every_message() {
+        let config = RateLimitConfig {
+            chat: Limit::new(2, Duration::from_secs(10)),
+            ..RateLimitConfig::generic()
+        };
+        let mut limiter = RateLimiter::new(config);
+        let now = Instant::now();
+        assert!(limiter
+            .try_acquire(&parse("PRIVMSG someone :/w other hi"), now)
+            .is_ok());
+        assert!(limiter
+            .try_acquire(&parse("NOTICE &local :hi"), now)
+            .is_ok());
+        assert_eq!(
+            limiter.try_acquire(&parse("PRIVMSG #a :hi"), now),
+            Err(Duration::from_secs(10))
+        );
+        assert!(limiter
+            .try_acquire(&parse("MODE #a +o someone"), now)
+            .is_ok());
+    }
+
+    #[test]
+    fn slow_mode_delays_messages() {
+        let mut limiter = RateLimiter::new(RateLimitConfig::default());
+        let now = Instant::now();
+        let msg = parse("PRIVMSG #a :hi");
+
+        limiter.observe(&parse("@slow=10 :tmi.twitch.tv ROOMSTATE #a"));
+        assert!(limiter.try_acquire(&msg, now).is_ok());
+        assert_eq!(
+            limiter.try_acquire(&msg, now + Duration::from_secs(4)),
+            Err(Duration::from_secs(6))
+        );
+
+        limiter.observe(&parse("@mod=1 :tmi.twitch.tv USERSTATE #a"));
+        assert!(limiter
+            .try_acquire(&msg, now + Duration::from_secs(4))
+            .is_ok());
+    }
+
+    #[test]
+    fn channel_limits_ignore_case() {
+        let mut limiter = RateLimiter::new(RateLimitConfig::default());
+        let now = Instant::now();
+
+        limiter.observe(&parse("@slow=10 :tmi.twitch.tv ROOMSTATE #room"));
+        assert!(limiter
+            .try_acquire(&parse("PRIVMSG #Room :hi"), now)
+            .is_ok());
+        assert_eq!(
+            limiter.try_acquire(&parse("PRIVMSG #ROOM :hi"), now + Duration::from_secs(4)),
+            Err(Duration::from_secs(6))
+        );
+    }
+
+    #[test]
+    fn higher_priority_goes_first() {
+        let limiter = Arc::new(Mutex::new(RateLimiter::new(RateLimitConfig::default())));
+        let queued = vec![
+            (Priority::Low, parse("PRIVMSG #a :1")),
+            (Priority::Normal, parse("PRIVMSG #a :2")),
+            (Priority::High, parse("PRIVMSG #a :urgent")),
+            (Priority::Normal, parse("PRIVMSG #a :3")),
+        ];
+        let sent = futures::executor::block_on(
+            limit(stream::iter(queued), limiter)
+                .map(|msg| msg.params()[1].clone())
+                .collect::<Vec<_>>(),
+        );
+        let sent = sent
+            .iter()
+            .map(|text| std::str::from_utf8(text.as_ref()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(sent, vec!["urgent", "2", "3", "1"]);
+    }
+
+    #[test]
+    fn held_messages_only_hold_up_their_channel() {
+        let limiter = Arc::new(Mutex::new(RateLimiter::new(RateLimitConfig::default())));
+        {
+            let mut limiter = limiter.lock().unwrap();
+            limiter.observe(&parse("@slow=30 :tmi.twitch.tv ROOMSTATE #a"));
+            limiter
+                .try_acquire(&parse("PRIVMSG #a :0"), Instant::now())
+                .unwrap();
+        }
+        let queued = vec![
+            (Priority::Normal, parse("PRIVMSG #a :1")),
+            (Priority::High, parse("PRIVMSG #a :urgent")),
+            (Priority::Normal, parse("PART #a")),
+            (Priority::Normal, parse("PRIVMSG #b :2")),
+            (Priority::Normal, parse("JOIN #c")),
+        ];
+        // The messages to #a wait out the slow mode, without holding up the others.
+        let sent = futures::executor::block_on(
+            limit(stream::iter(queued), limiter)
+                .take(2)
+                .map(|msg| msg.to_wire())
+                .collect::<Vec<_>>(),
+        );
+        let sent = sent
+            .iter()
+            .map(|line| std::str::from_utf8(line.as_ref()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(sent, vec!["PRIVMSG #b :2", "JOIN :#c"]);
+    }
+}
@@ -21,6 +21,9 @@ pub struct UserLeft {
 pub struct Message {
     pub from: String,
     pub message: String,
+    /// True if this was sent as a CTCP ACTION (`/me`). The CTCP framing is not included in
+    /// `message`.
+    pub is_action: bool,
 }
 
 #[derive(Clone)]
@@ -30,3 +33,15 @@ pub enum RoomEvent {
     UserLeft(UserLeft),
     Message(Message),
 }
+
+#[derive(Clone)]
+pub struct Whisper {
+    pub from: String,
+    pub message: String,
+}
+
+/// Events for the primary that aren't tied to a room.
+#[derive(Clone)]
+pub enum PrimaryEvent {
+    Whisper(Whisper),
+}
@@ -1,4 +1,5 @@
 pub mod events;
 mod room_state;
 
-pub use room_state::MembersList;
+pub(crate) use room_state::ConnectionState;
+pub use room_state::{MembersList, UserState, LOTS_THRESHOLD};
@@ -1,28 +1,96 @@
 // Temporarily disable unused functions to be able to track real issues
 #![allow(dead_code)]
 
-use super::events::{self, MembersListUpdate, RoomEvent};
+use super::events::{self, MembersListUpdate, PrimaryEvent, RoomEvent};
+use crate::event::{Event, SlowSubscriberPolicy};
 use crate::futures_util::event_sink::EventSink;
+use crate::network::ISupport;
 use futures::channel::mpsc;
-use futures::prelude::*;
+use minibot_irc_raw::twitch_tags::{Badge, Color, TwitchTags};
+use minibot_irc_raw::{KnownCommand, Message};
 use std::collections::{btree_map, BTreeMap, BTreeSet};
 
+/// Twitch stops tracking the members of a room once it has this many. NAMES then only lists the
+/// moderators, and JOIN/PART messages stop coming.
+pub const LOTS_THRESHOLD: usize = 1000;
+
+/// How many users a room in `MembersState::Lots` remembers the state of.
+const RECENT_USERS_LIMIT: usize = 500;
+
+/// What we know about a user in a room, from the tags on their last message. For ourselves, this
+/// comes from USERSTATE instead.
+#[derive(Clone, Debug)]
 pub struct UserState {
-    display_name: String,
-    // For future usage:
-    //
-    // is_mod: bool,
-    // is_admin: bool,
-    // is_broadcaster: bool,
-    // is_global_mod: bool,
-    // is_moderator: bool,
-    // is_subscriber: bool,
-    // is_staff: bool,
-    // is_turbo: bool,
+    pub display_name: String,
+    pub color: Option<Color>,
+    pub badges: Vec<Badge>,
+    is_mod: bool,
+    is_subscriber: bool,
+}
+
+impl UserState {
+    /// Reads the state from the tags of a USERSTATE, GLOBALUSERSTATE or PRIVMSG sent by `user`.
+    /// Tags that are missing or malformed are treated as unset.
+    pub fn from_tags(user: &str, message: &Message) -> Self {
+        let tags = TwitchTags::new(message);
+        UserState {
+            display_name: tags
+                .display_name()
+                .filter(|name| !name.is_empty())
+                .unwrap_or(user)
+                .to_string(),
+            color: tags.color().ok().flatten(),
+            badges: tags.badges().ok().flatten().unwrap_or_default(),
+            is_mod: tags.is_mod().ok().flatten().unwrap_or(false),
+            is_subscriber: tags.is_subscriber().ok().flatten().unwrap_or(false),
+        }
+    }
+
+    pub fn has_badge(&self, name: &str) -> bool {
+        self.badges.iter().any(|badge| badge.name == name)
+    }
+
+    pub fn is_broadcaster(&self) -> bool {
+        self.has_badge("broadcaster")
+    }
+
+    pub fn is_mod(&self) -> bool {
+        self.is_mod || self.has_badge("moderator")
+    }
+
+    /// True for the broadcaster and moderators: the users who can use moderation commands.
+    pub fn can_moderate(&self) -> bool {
+        self.is_broadcaster() || self.is_mod()
+    }
+
+    pub fn is_vip(&self) -> bool {
+        self.has_badge("vip")
+    }
+
+    pub fn is_subscriber(&self) -> bool {
+        self.is_subscriber || self.has_badge("subscriber") || self.has_badge("founder")
+    }
+
+    pub fn is_staff(&self) -> bool {
+        self.has_badge("staff")
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.has_badge("admin")
+    }
+
+    pub fn is_global_mod(&self) -> bool {
+        self.has_badge("global_mod")
+    }
+
+    pub fn is_turbo(&self) -> bool {
+        self.has_badge("turbo")
+    }
 }
 
 pub struct BigRoomMembersState {
     num_members: u32,
+    /// Most recently active last.
     recent_users: Vec<(String, UserState)>,
 }
 
@@ -51,7 +119,75 @@ impl MembersState {
     }
 
     pub fn to_list(&self) -> MembersList {
-        todo!()
+        match self {
+            MembersState::Lots(state) => MembersList::Lots(state.num_members),
+            MembersState::Users(members) => MembersList::Users(members.keys().cloned().collect()),
+        }
+    }
+
+    /// Adds a user who joined. Returns true if this pushed the room over `LOTS_THRESHOLD`.
+    fn add_user(&mut self, user: &str) -> bool {
+        match self {
+            MembersState::Users(members) => {
+                members.entry(user.to_string()).or_insert(None);
+                if members.len() < LOTS_THRESHOLD {
+                    return false;
+                }
+                *self = MembersState::Lots(BigRoomMembersState {
+                    num_members: members.len() as u32,
+                    recent_users: Vec::new(),
+                });
+                true
+            }
+            MembersState::Lots(state) => {
+                state.num_members += 1;
+                false
+            }
+        }
+    }
+
+    /// Records the state of a user who sent a message. Someone we haven't seen join is added to
+    /// the members, since Twitch stops sending JOINs once a room is big, and one of the ways to
+    /// notice a big room is its chatters. Returns true if this pushed the room over
+    /// `LOTS_THRESHOLD`.
+    fn update_user(&mut self, user: &str, state: UserState) -> bool {
+        let is_new = matches!(self, MembersState::Users(members) if !members.contains_key(user));
+        let became_lots = is_new && self.add_user(user);
+        match self {
+            MembersState::Users(members) => {
+                members.insert(user.to_string(), Some(state));
+            }
+            MembersState::Lots(big_room) => {
+                let recent_users = &mut big_room.recent_users;
+                recent_users.retain(|(name, _)| name != user);
+                if recent_users.len() >= RECENT_USERS_LIMIT {
+                    recent_users.remove(0);
+                }
+                recent_users.push((user.to_string(), state));
+            }
+        }
+        became_lots
+    }
+
+    fn user_state(&self, user: &str) -> Option<&UserState> {
+        match self {
+            MembersState::Users(members) => members.get(user)?.as_ref(),
+            MembersState::Lots(big_room) => big_room
+                .recent_users
+                .iter()
+                .rev()
+                .find(|(name, _)| name == user)
+                .map(|(_, state)| state),
+        }
+    }
+
+    fn remove_user(&mut self, user: &str) {
+        match self {
+            MembersState::Users(members) => {
+                members.remove(user);
+            }
+            MembersState::Lots(state) => state.num_members = state.num_members.saturating_sub(1),
+        }
     }
 
     pub fn update(&mut self, members_list: MembersList) {
@@ -85,24 +221,24 @@ impl MembersState {
 
             MembersState::Lots(state) => match members_list {
                 MembersList::Lots(num_members) => state.num_members = num_members,
-                MembersList::Users(new_members) => {
-                    *self =
-                        MembersState::Users(new_members.into_iter().map(|n| (n, None)).collect())
-                }
+                // Past `LOTS_THRESHOLD`, NAMES only lists the moderators, so a short list doesn't
+                // mean that the room got smaller.
+                MembersList::Users(_) => {}
             },
         }
     }
 }
 
+/// Events are queued without waiting, so that a slow room listener never holds up the connection.
 pub struct RoomState {
     members: Option<MembersState>,
-    events_sink: mpsc::Sender<super::events::RoomEvent>,
+    events_sink: mpsc::UnboundedSender<super::events::RoomEvent>,
     events_channel: EventSink<super::events::RoomEvent>,
 }
 
 impl RoomState {
     fn new() -> Self {
-        let (tx, rx) = mpsc::channel(3);
+        let (tx, rx) = mpsc::unbounded();
         RoomState {
             members: None,
             events_sink: tx,
@@ -110,77 +246,220 @@ impl RoomState {
         }
     }
 
-    pub async fn update_user_state(&mut self, _user: &str, _display_name: &str) {}
-
-    pub async fn notify_members_list(&mut self, members_list: MembersList) {
-        self.events_sink
-            .send(RoomEvent::MembersListUpdate(MembersListUpdate {
-                members_list: members_list.clone(),
-            }))
-            .await
-            .unwrap();
+    fn send_event(&self, event: RoomEvent) {
+        // The receiving end only goes away along with the room.
+        let _ = self.events_sink.unbounded_send(event);
+    }
 
-        match &mut self.members {
-            Some(members) => members.update(members_list),
-            None => self.members = Some(MembersState::from_list(members_list)),
+    pub fn update_user_state(&mut self, user: &str, state: UserState) {
+        let members = self.members.get_or_insert_with(MembersState::new);
+        if members.update_user(user, state) {
+            let members_list = members.to_list();
+            self.send_event(RoomEvent::MembersListUpdate(MembersListUpdate {
+                members_list,
+            }));
         }
     }
 
-    pub async fn notify_join_room(&mut self, user: &str) {
-        self.events_sink
-            .send(RoomEvent::UserJoined(events::UserJoined {
-                user: user.to_string(),
-            }))
-            .await
-            .unwrap();
+    /// The state of a user, if they have said something since we joined. In big rooms, only
+    /// recently active users are remembered.
+    pub fn user_state(&self, user: &str) -> Option<&UserState> {
+        self.members.as_ref()?.user_state(user)
     }
 
-    pub async fn notify_part_room(&mut self, user: &str) {
-        self.events_sink
-            .send(RoomEvent::UserLeft(events::UserLeft {
-                user: user.to_string(),
-            }))
-            .await
-            .unwrap();
+    /// Updates the members from a NAMES reply. A big room stays big, whatever the reply says.
+    pub fn notify_members_list(&mut self, members_list: MembersList) {
+        let members_list = match members_list {
+            MembersList::Users(users) if users.len() >= LOTS_THRESHOLD => {
+                MembersList::Lots(users.len() as u32)
+            }
+            members_list => members_list,
+        };
+        let members = match &mut self.members {
+            Some(members) => {
+                members.update(members_list);
+                members
+            }
+            None => self.members.insert(MembersState::from_list(members_list)),
+        };
+        let members_list = members.to_list();
+        self.send_event(RoomEvent::MembersListUpdate(MembersListUpdate {
+            members_list,
+        }));
     }
 
-    pub async fn notify_message(&mut self, user: &str, message: &str) {
-        self.events_sink
-            .send(RoomEvent::Message(events::Message {
-                from: user.to_string(),
-                message: message.to_string(),
-            }))
-            .await
-            .unwrap();
+    pub fn notify_join_room(&mut self, user: &str) {
+        self.send_event(RoomEvent::UserJoined(events::UserJoined {
+            user: user.to_string(),
+        }));
+
+        let members = self.members.get_or_insert_with(MembersState::new);
+        if members.add_user(user) {
+            let members_list = members.to_list();
+            self.send_event(RoomEvent::MembersListUpdate(MembersListUpdate {
+                members_list,
+            }));
+        }
+    }
+
+    pub fn notify_part_room(&mut self, user: &str) {
+        self.send_event(RoomEvent::UserLeft(events::UserLeft {
+            user: user.to_string(),
+        }));
+
+        if let Some(members) = &mut self.members {
+            members.remove_user(user);
+        }
+    }
+
+    pub fn notify_message(&mut self, user: &str, message: &str, is_action: bool) {
+        self.send_event(RoomEvent::Message(events::Message {
+            from: user.to_string(),
+            message: message.to_string(),
+            is_action,
+        }));
     }
 
-    pub async fn add_listener(&mut self, mut listener: mpsc::Sender<RoomEvent>) {
+    pub fn add_listener(
+        &mut self,
+        mut listener: mpsc::Sender<RoomEvent>,
+        policy: SlowSubscriberPolicy,
+    ) {
         // Get the listener up to speed by sending an update event for the
-        // current state of the room (if there is any).
+        // current state of the room (if there is any). A new sender always has room for one
+        // message.
         if let Some(members_state) = &self.members {
-            let send_result = listener
-                .send(RoomEvent::MembersListUpdate(MembersListUpdate {
-                    members_list: members_state.to_list(),
-                }))
-                .await;
+            let send_result = listener.try_send(RoomEvent::MembersListUpdate(MembersListUpdate {
+                members_list: members_state.to_list(),
+            }));
 
             // An error indicates the sender was disconnected. No point in
             // adding it.
-            if send_result.is_err() {
+            if send_result.is_err_and(|e| e.is_disconnected()) {
                 return;
             }
         }
 
-        self.events_channel.add_sink(listener);
+        self.events_channel.add_sink_with_policy(listener, policy);
+    }
+
+    /// The current members of the room, if they are known yet.
+    pub fn members(&self) -> Option<MembersList> {
+        self.members.as_ref().map(MembersState::to_list)
     }
 }
 
 pub struct ConnectionState {
     user: String,
+    isupport: ISupport,
     rooms: BTreeMap<String, RoomState>,
+    // Names from RPL_NAMREPLY (353) for each room, until RPL_ENDOFNAMES (366) arrives.
+    pending_names: BTreeMap<String, Vec<String>>,
+    global_user_state: Option<UserState>,
+    events_sink: mpsc::UnboundedSender<PrimaryEvent>,
+    events_channel: EventSink<PrimaryEvent>,
+}
+
+fn room_name(param: &[u8]) -> String {
+    let room = String::from_utf8_lossy(param);
+    room.strip_prefix('#').unwrap_or(&room).to_string()
 }
 
 impl ConnectionState {
+    /// Creates the state for a connection logged in as `user`.
+    pub fn new(user: &str) -> Self {
+        let (tx, rx) = mpsc::unbounded();
+        ConnectionState {
+            user: user.to_string(),
+            isupport: ISupport::new(),
+            rooms: BTreeMap::new(),
+            pending_names: BTreeMap::new(),
+            global_user_state: None,
+            events_sink: tx,
+            events_channel: EventSink::new(rx),
+        }
+    }
+
+    /// Sets how the server compares names and marks member modes in NAMES replies.
+    pub fn with_isupport(mut self, isupport: ISupport) -> Self {
+        self.isupport = isupport;
+        self
+    }
+
+    fn is_self(&self, user: &str) -> bool {
+        self.isupport.same_name(user, &self.user)
+    }
+
+    /// Updates the state from a message sent by the server. Events for listeners are queued
+    /// without waiting for them.
+    pub fn handle_event(&mut self, event: &Event) {
+        match event {
+            Event::Join(join) if self.is_self(&join.user) => {
+                self.notify_join_room(join.channel.clone());
+            }
+            Event::Join(join) => {
+                if let Some(room) = self.rooms.get_mut(&join.channel) {
+                    room.notify_join_room(&join.user);
+                }
+            }
+            // Dropping the room ends the streams of its listeners.
+            Event::Part(part) if self.is_self(&part.user) => {
+                self.rooms.remove(&part.channel);
+                self.pending_names.remove(&part.channel);
+            }
+            Event::Part(part) => {
+                if let Some(room) = self.rooms.get_mut(&part.channel) {
+                    room.notify_part_room(&part.user);
+                }
+            }
+            Event::Chat(chat) if chat.is_private => self.notify_whisper(&chat.sender, &chat.text),
+            Event::Chat(chat) => {
+                if let Some(room) = self.rooms.get_mut(&chat.channel) {
+                    room.update_user_state(
+                        &chat.sender,
+                        UserState::from_tags(&chat.sender, &chat.message),
+                    );
+                    room.notify_message(&chat.sender, &chat.text, chat.is_action);
+                }
+            }
+            Event::UserState(state) => {
+                if let Some(room) = self.rooms.get_mut(&state.channel) {
+                    room.update_user_state(
+                        &self.user,
+                        UserState::from_tags(&self.user, &state.message),
+                    );
+                }
+            }
+            Event::Whisper(whisper) => self.notify_whisper(&whisper.from, &whisper.text),
+            Event::GlobalUserState(msg) => {
+                self.global_user_state = Some(UserState::from_tags(&self.user, msg));
+            }
+            Event::Other(msg) => match (msg.known_command(), msg.params()) {
+                (Some(KnownCommand::RplNamReply), [_, _, channel, names]) => {
+                    let names = String::from_utf8_lossy(names.as_ref());
+                    let isupport = &self.isupport;
+                    self.pending_names
+                        .entry(room_name(channel.as_ref()))
+                        .or_default()
+                        .extend(
+                            names
+                                .split_whitespace()
+                                .map(|name| isupport.split_prefixes(name).1.to_string()),
+                        );
+                }
+                (Some(KnownCommand::RplEndOfNames), [_, channel, ..]) => {
+                    let channel = room_name(channel.as_ref());
+                    let names = self.pending_names.remove(&channel).unwrap_or_default();
+                    if let Some(room) = self.rooms.get_mut(&channel) {
+                        room.notify_members_list(MembersList::Users(names));
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
     pub fn notify_join_room(&mut self, room: String) -> &mut RoomState {
         use btree_map::Entry;
         match self.rooms.entry(room) {
@@ -197,7 +476,91 @@ impl ConnectionState {
         self.rooms.get(room)
     }
 
-    pub fn notify_whisper(&mut self, _user: &str, _message: &str) {
-        todo!()
+    /// Our own state outside of any channel, from GLOBALUSERSTATE.
+    pub fn global_user_state(&self) -> Option<&UserState> {
+        self.global_user_state.as_ref()
+    }
+
+    pub fn notify_whisper(&mut self, user: &str, message: &str) {
+        // The receiving end only goes away along with the connection state.
+        let _ = self
+            .events_sink
+            .unbounded_send(PrimaryEvent::Whisper(events::Whisper {
+                from: user.to_string(),
+                message: message.to_string(),
+            }));
+    }
+
+    pub fn add_listener(
+        &mut self,
+        listener: mpsc::Sender<PrimaryEvent>,
+        policy: SlowSubscriberPolicy,
+    ) {
+        self.events_channel.add_sink_with_policy(listener, policy);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn switches_to_lots_for_big_rooms() {
+        let mut members =
+            MembersState::from_list(MembersList::Users(vec!["a".to_string(), "b".to_string()]));
+        members.remove_user("a");
+        assert!(matches!(members.to_list(), MembersList::Users(users) if users == vec!["b"]));
+
+        for i in 1..LOTS_THRESHOLD - 1 {
+            assert!(!members.add_user(&format!("user{}", i)));
+        }
+        assert!(members.add_user("last"));
+        assert!(matches!(members.to_list(), MembersList::Lots(n) if n as usize == LOTS_THRESHOLD));
+
+        members.add_user("more");
+        assert!(
+            matches!(members.to_list(), MembersList::Lots(n) if n as usize == LOTS_THRESHOLD + 1)
+        );
+    }
+
+    #[test]
+    fn big_rooms_stay_big() {
+        let mut members = MembersState::from_list(MembersList::Lots(5000));
+        members.update(MembersList::Users(vec!["a_mod".to_string()]));
+        assert!(matches!(members.to_list(), MembersList::Lots(5000)));
+
+        // A room that only looked small from NAMES turns out to be big from its chatters.
+        let mut members = MembersState::from_list(MembersList::Users(vec!["a_mod".to_string()]));
+        let user_state = UserState {
+            display_name: String::new(),
+            color: None,
+            badges: Vec::new(),
+            is_mod: false,
+            is_subscriber: false,
+        };
+        for i in 1..LOTS_THRESHOLD - 1 {
+            assert!(!members.update_user(&format!("user{}", i), user_state.clone()));
+        }
+        assert!(members.update_user("last", user_state.clone()));
+        assert!(members.user_state("last").is_some());
+        assert!(matches!(members.to_list(), MembersList::Lots(n) if n as usize == LOTS_THRESHOLD));
+    }
+
+    #[test]
+    fn reads_roles_from_tags() {
+        let message = minibot_irc_raw::MessageRef::parse(
+            b"@badges=broadcaster/1,subscriber/12;color=#FF0000;display-name=;mod=0 \
+              :foo!foo@foo.tmi.twitch.tv PRIVMSG #foo :hi",
+        )
+        .unwrap()
+        .to_owned()
+        .unwrap();
+        let state = UserState::from_tags("foo", &message);
+        assert_eq!(state.display_name, "foo");
+        assert_eq!(state.color, Some(Color { r: 255, g: 0, b: 0 }));
+        assert!(state.is_broadcaster());
+        assert!(state.can_moderate());
+        assert!(state.is_subscriber());
+        assert!(!state.is_vip());
     }
 }
@@ -0,0 +1,357 @@
+//! Ready-made calls for common request/response exchanges.
+//!
+//! Each call claims only the message that finishes it. Everything else, including the JOIN echo
+//! and NAMES replies, still goes to the connection's message handler.
+
+use super::{FilterResult, RpcCall};
+use crate::cap::{cap_request, CapError};
+use minibot_irc_raw::{BuildError, KnownCommand, Message, MessageBuilder};
+use std::collections::BTreeSet;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// The msg-ids of the NOTICEs Twitch sends when a JOIN fails.
+const JOIN_FAILURES: &[&str] = &[
+    "msg_channel_suspended",
+    "msg_banned",
+    "msg_room_not_found",
+    "tos_ban",
+    "invalid_user",
+];
+
+fn param_is(msg: &Message, index: usize, value: &str) -> bool {
+    msg.params()
+        .get(index)
+        .is_some_and(|p| p.as_ref().eq_ignore_ascii_case(value.as_bytes()))
+}
+
+fn param_text(msg: &Message, index: usize) -> String {
+    msg.params()
+        .get(index)
+        .map(|p| String::from_utf8_lossy(p.as_ref()).into_owned())
+        .unwrap_or_default()
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum JoinError {
+    #[error("Could not join #{channel}: {text}")]
+    Refused {
+        channel: String,
+        /// The `msg-id` tag of the NOTICE, e.g. `msg_channel_suspended`.
+        msg_id: String,
+        text: String,
+    },
+}
+
+/// Confirmation that a channel was joined.
+#[derive(Clone, Debug)]
+pub struct Joined {
+    pub channel: String,
+    /// The ROOMSTATE or end of NAMES (366) that confirmed the join.
+    pub confirmation: Message,
+}
+
+/// Joins a channel, finishing on the first ROOMSTATE or end of NAMES for it, or failing on a
+/// NOTICE that Twitch sends for JOINs it refuses.
+pub struct JoinChannel {
+    channel: String,
+    target: String,
+    message: Message,
+}
+
+impl JoinChannel {
+    /// `channel` is given without the leading `#`.
+    pub fn new(channel: &str) -> Result<Self, BuildError> {
+        let target = format!("#{}", channel);
+        Ok(JoinChannel {
+            channel: channel.to_string(),
+            message: MessageBuilder::named("JOIN").param(&target).build()?,
+            target,
+        })
+    }
+}
+
+impl RpcCall for JoinChannel {
+    type Output = Joined;
+    type Err = JoinError;
+
+    fn send_messages(&self) -> Vec<Message> {
+        vec![self.message.clone()]
+    }
+
+    fn msg_filter(&self, msg: &Message) -> Result<FilterResult, JoinError> {
+        match msg.known_command() {
+            Some(KnownCommand::RoomState) if param_is(msg, 0, &self.target) => {
+                Ok(FilterResult::End)
+            }
+            Some(KnownCommand::RplEndOfNames) if param_is(msg, 1, &self.target) => {
+                Ok(FilterResult::End)
+            }
+            Some(KnownCommand::Notice) if param_is(msg, 0, &self.target) => {
+                match msg.tag("msg-id") {
+                    Some(msg_id) if JOIN_FAILURES.contains(&msg_id) => Err(JoinError::Refused {
+                        channel: self.channel.clone(),
+                        msg_id: msg_id.to_string(),
+                        text: param_text(msg, 1),
+                    }),
+                    _ => Ok(FilterResult::Skip),
+                }
+            }
+            _ => Ok(FilterResult::Skip),
+        }
+    }
+
+    fn recv_messages(&self, mut msgs: Vec<Message>) -> Result<Joined, JoinError> {
+        Ok(Joined {
+            channel: self.channel.clone(),
+            confirmation: msgs.pop().expect("The call ends on a message"),
+        })
+    }
+}
+
+/// Leaves a channel, finishing when the server echoes our PART.
+pub struct PartChannel {
+    nick: String,
+    target: String,
+    message: Message,
+}
+
+impl PartChannel {
+    /// `nick` is the user we are logged in as, which the server's PART echo comes from.
+    pub fn new(nick: &str, channel: &str) -> Result<Self, BuildError> {
+        let target = format!("#{}", channel);
+        Ok(PartChannel {
+            nick: nick.to_string(),
+            message: MessageBuilder::named("PART").param(&target).build()?,
+            target,
+        })
+    }
+}
+
+impl RpcCall for PartChannel {
+    type Output = ();
+    type Err = Infallible;
+
+    fn send_messages(&self) -> Vec<Message> {
+        vec![self.message.clone()]
+    }
+
+    fn msg_filter(&self, msg: &Message) -> Result<FilterResult, Infallible> {
+        let is_ours = msg
+            .source()
+            .and_then(|source| source.nick())
+            .is_some_and(|nick| nick.eq_ignore_ascii_case(&self.nick));
+        if is_ours && msg.has_command(KnownCommand::Part) && param_is(msg, 0, &self.target) {
+            Ok(FilterResult::End)
+        } else {
+            Ok(FilterResult::Skip)
+        }
+    }
+
+    fn recv_messages(&self, _msgs: Vec<Message>) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+/// Requests caps after registration. Succeeds with the acknowledged caps, or fails with
+/// `CapError::Rejected` if the server NAKs them. The server answers a request as a whole.
+pub struct CapRequest {
+    caps: BTreeSet<String>,
+    message: Message,
+}
+
+impl CapRequest {
+    pub fn new<T: IntoIterator<Item = S>, S: AsRef<str>>(caps: T) -> Result<Self, CapError> {
+        let caps = caps
+            .into_iter()
+            .map(|cap| cap.as_ref().to_string())
+            .collect::<BTreeSet<_>>();
+        Ok(CapRequest {
+            message: cap_request(&caps)?,
+            caps,
+        })
+    }
+
+    fn is_answer(&self, msg: &Message) -> bool {
+        let caps = param_text(msg, 2);
+        caps.split_whitespace().collect::<BTreeSet<_>>()
+            == self.caps.iter().map(String::as_str).collect()
+    }
+}
+
+impl RpcCall for CapRequest {
+    type Output = Vec<String>;
+    type Err = CapError;
+
+    fn send_messages(&self) -> Vec<Message> {
+        vec![self.message.clone()]
+    }
+
+    fn msg_filter(&self, msg: &Message) -> Result<FilterResult, CapError> {
+        if !msg.has_command(KnownCommand::Cap) || !self.is_answer(msg) {
+            return Ok(FilterResult::Skip);
+        }
+        if param_is(msg, 1, "ACK") {
+            Ok(FilterResult::End)
+        } else if param_is(msg, 1, "NAK") {
+            Err(CapError::Rejected(self.caps.iter().cloned().collect()))
+        } else {
+            Ok(FilterResult::Skip)
+        }
+    }
+
+    fn recv_messages(&self, _msgs: Vec<Message>) -> Result<Vec<String>, CapError> {
+        Ok(self.caps.iter().cloned().collect())
+    }
+}
+
+/// Sends a PING with a unique token and measures the time until the matching PONG. Time spent
+/// waiting for other calls to finish sending isn't counted.
+pub struct Ping {
+    token: String,
+    sent_at: Mutex<Option<Instant>>,
+    answered_at: Mutex<Option<Instant>>,
+}
+
+impl Ping {
+    pub fn new() -> Self {
+        static NEXT_TOKEN: AtomicU64 = AtomicU64::new(0);
+        Ping {
+            token: format!("minibot-rpc-{}", NEXT_TOKEN.fetch_add(1, Ordering::Relaxed)),
+            sent_at: Mutex::new(None),
+            answered_at: Mutex::new(None),
+        }
+    }
+}
+
+impl Default for Ping {
+    fn default() -> Self {
+        Ping::new()
+    }
+}
+
+impl RpcCall for Ping {
+    /// The round trip time.
+    type Output = Duration;
+    type Err = Infallible;
+
+    fn send_messages(&self) -> Vec<Message> {
+        *self.sent_at.lock().unwrap() = Some(Instant::now());
+        vec![Message::from_named_command_params("PING", [&self.token])]
+    }
+
+    fn msg_filter(&self, msg: &Message) -> Result<FilterResult, Infallible> {
+        let is_answer = msg.has_command(KnownCommand::Pong)
+            && msg
+                .params()
+                .last()
+                .is_some_and(|token| token.eq_bytes(self.token.as_bytes()));
+        if is_answer {
+            *self.answered_at.lock().unwrap() = Some(Instant::now());
+            Ok(FilterResult::End)
+        } else {
+            Ok(FilterResult::Skip)
+        }
+    }
+
+    fn recv_messages(&self, _msgs: Vec<Message>) -> Result<Duration, Infallible> {
+        let sent_at = self.sent_at.lock().unwrap().expect("PING was sent");
+        let answered_at = self.answered_at.lock().unwrap().expect("PONG was received");
+        Ok(answered_at - sent_at)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use minibot_irc_raw::MessageRef;
+
+    fn parse(line: &str) -> Message {
+        MessageRef::parse(line.as_bytes())
+            .unwrap()
+            .to_owned()
+            .unwrap()
+    }
+
+    #[test]
+    fn join_ends_on_confirmation_or_refusal() {
+        let join = JoinChannel::new("foo").unwrap();
+        assert!(matches!(
+            join.msg_filter(&parse(":me!me@me.tmi.twitch.tv JOIN #foo")),
+            Ok(FilterResult::Skip)
+        ));
+        assert!(matches!(
+            join.msg_filter(&parse(":me.tmi.twitch.tv 366 me #bar :End of /NAMES list")),
+            Ok(FilterResult::Skip)
+        ));
+        assert!(matches!(
+            join.msg_filter(&parse(":me.tmi.twitch.tv 366 me #foo :End of /NAMES list")),
+            Ok(FilterResult::End)
+        ));
+        assert!(matches!(
+            join.msg_filter(&parse(
+                "@msg-id=msg_channel_suspended :tmi.twitch.tv NOTICE #foo :This channel has been suspended."
+            )),
+            Err(JoinError::Refused { .. })
+        ));
+    }
+
+    #[test]
+    fn cap_request_matches_its_answer() {
+        let request = CapRequest::new(["b", "a"]).unwrap();
+        assert!(matches!(
+            request.msg_filter(&parse("CAP * ACK :a")),
+            Ok(FilterResult::Skip)
+        ));
+        assert!(matches!(
+            request.msg_filter(&parse("CAP * ACK :a b")),
+            Ok(FilterResult::End)
+        ));
+        assert!(matches!(
+            request.msg_filter(&parse("CAP * NAK :a b")),
+            Err(CapError::Rejected(_))
+        ));
+    }
+
+    #[test]
+    fn part_ends_on_our_echo() {
+        let part = PartChannel::new("Me", "foo").unwrap();
+        assert!(matches!(
+            part.msg_filter(&parse(":other!other@other.tmi.twitch.tv PART #foo")),
+            Ok(FilterResult::Skip)
+        ));
+        assert!(matches!(
+            part.msg_filter(&parse(":me!me@me.tmi.twitch.tv PART #bar")),
+            Ok(FilterResult::Skip)
+        ));
+        assert!(matches!(
+            part.msg_filter(&parse(":me!me@me.tmi.twitch.tv PART #foo")),
+            Ok(FilterResult::End)
+        ));
+    }
+
+    #[test]
+    fn ping_measures_until_its_pong() {
+        let ping = Ping::new();
+        let sent = ping.send_messages();
+        let token = String::from_utf8(sent[0].params()[0].as_ref().to_vec()).unwrap();
+        assert!(matches!(
+            ping.msg_filter(&parse(":tmi.twitch.tv PONG tmi.twitch.tv :other")),
+            Ok(FilterResult::Skip)
+        ));
+        assert!(matches!(
+            ping.msg_filter(&parse(&format!(
+                ":tmi.twitch.tv PONG tmi.twitch.tv :{}",
+                token
+            ))),
+            Ok(FilterResult::End)
+        ));
+        let rtt = ping.recv_messages(Vec::new()).unwrap();
+        assert!(rtt < Duration::from_secs(1));
+
+        // Every PING gets its own token.
+        assert!(!Ping::new().send_messages()[0].params()[0].eq_bytes(token.as_bytes()));
+    }
+}
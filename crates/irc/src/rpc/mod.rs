@@ -0,0 +1,395 @@
+pub mod calls;
+
+use crate::connection::{IrcSink, IrcStream};
+use futures::channel::oneshot;
+use futures::prelude::*;
+use minibot_irc_raw::Message;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Copy, Clone, Debug)]
+pub enum FilterResult {
+    Next,
+    Skip,
+    End,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("")]
+    ConnectionError(#[from] crate::connection::Error),
+
+    #[error("")]
+    RpcCancelledError,
+
+    #[error("")]
+    HandlerError(#[source] Box<dyn std::error::Error + Send>),
+
+    #[error(transparent)]
+    Irc(#[from] minibot_irc_raw::Error),
+}
+
+struct RpcState {
+    response_messages: Vec<Message>,
+    call: Box<dyn ObjectSafeRpcCall + Sync + Send>,
+}
+
+type CallResult = Result<RpcState, Box<dyn std::any::Any + Send + 'static>>;
+
+struct RpcStateAndChannel {
+    id: u64,
+    state: RpcState,
+    channel: oneshot::Sender<CallResult>,
+}
+
+/// The calls that are waiting for responses, oldest first.
+#[derive(Default)]
+struct StreamState {
+    next_id: u64,
+    calls: VecDeque<RpcStateAndChannel>,
+}
+
+impl StreamState {
+    fn register(
+        &mut self,
+        call: Box<dyn ObjectSafeRpcCall + Sync + Send>,
+    ) -> (u64, oneshot::Receiver<CallResult>) {
+        let (tx, rx) = oneshot::channel();
+        let id = self.next_id;
+        self.next_id += 1;
+        self.calls.push_back(RpcStateAndChannel {
+            id,
+            state: RpcState {
+                response_messages: Vec::new(),
+                call,
+            },
+            channel: tx,
+        });
+        (id, rx)
+    }
+
+    fn deregister(&mut self, id: u64) {
+        self.calls.retain(|rpc| rpc.id != id);
+    }
+
+    /// Gives the message to the oldest call that doesn't skip it. Returns the message if every
+    /// call skipped it.
+    fn route(&mut self, msg: Message) -> Option<Message> {
+        for i in 0..self.calls.len() {
+            let rpc = &mut self.calls[i];
+            match rpc.state.call.msg_filter(&msg) {
+                Ok(FilterResult::Skip) => continue,
+                Ok(FilterResult::Next) => rpc.state.response_messages.push(msg),
+                Ok(FilterResult::End) => {
+                    let RpcStateAndChannel {
+                        mut state, channel, ..
+                    } = self.calls.remove(i).unwrap();
+                    state.response_messages.push(msg);
+                    let _ = channel.send(Ok(state));
+                }
+                Err(e) => {
+                    let RpcStateAndChannel { channel, .. } = self.calls.remove(i).unwrap();
+                    let _ = channel.send(Err(e));
+                }
+            }
+            return None;
+        }
+        Some(msg)
+    }
+}
+
+/// Removes a call from the stream state when the caller stops waiting for it, whether it
+/// finished, timed out or was dropped.
+struct Registration<'a> {
+    id: u64,
+    stream_state: &'a Mutex<StreamState>,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.stream_state.lock().unwrap().deregister(self.id);
+    }
+}
+
+/// Calls that don't finish within this long fail with `RpcCallError::Timeout`, unless the
+/// connection is built with a different timeout.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct IrcRpcConnection {
+    sink: futures::lock::Mutex<IrcSink>,
+    stream_state: Arc<Mutex<StreamState>>,
+    timeout: Duration,
+}
+
+pub trait RpcCall {
+    type Output;
+    type Err: std::error::Error + std::any::Any + Send + 'static;
+    /// The messages to send. This is called once the call has the sink to itself, right before
+    /// the messages are written.
+    fn send_messages(&self) -> Vec<Message>;
+    fn msg_filter(&self, msg: &Message) -> Result<FilterResult, Self::Err>;
+    fn recv_messages(&self, msgs: Vec<Message>) -> Result<Self::Output, Self::Err>;
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum RpcCallError<E: std::error::Error + 'static> {
+    #[error("Error while processing: {0}")]
+    CallError(#[source] E),
+
+    #[error("Rpc cancelled by stream")]
+    RpcCancelledError,
+
+    #[error("Rpc timed out")]
+    Timeout,
+}
+
+trait ObjectSafeRpcCall {
+    fn msg_filter(
+        &self,
+        msg: &Message,
+    ) -> Result<FilterResult, Box<dyn std::any::Any + Send + 'static>>;
+    fn to_inner(self: Box<Self>) -> Box<dyn std::any::Any + 'static>;
+}
+
+struct ObjectSafeCallWrapper<T>(T);
+
+impl<T: RpcCall + 'static> ObjectSafeRpcCall for ObjectSafeCallWrapper<T> {
+    fn msg_filter(
+        &self,
+        msg: &Message,
+    ) -> Result<FilterResult, Box<dyn std::any::Any + Send + 'static>> {
+        self.0.msg_filter(msg).map_err(|e| {
+            let new_e: Box<dyn std::any::Any + Send + 'static> = Box::new(e);
+            new_e
+        })
+    }
+
+    fn to_inner(self: Box<Self>) -> Box<dyn std::any::Any + 'static> {
+        let ObjectSafeCallWrapper(t) = *self;
+        Box::new(t)
+    }
+}
+
+impl IrcRpcConnection {
+    pub fn new<F, Fut, E>(mut stream: IrcStream, sink: IrcSink, mut msg_handler: F) -> Self
+    where
+        F: FnMut(Message) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), E>> + Send,
+        E: std::error::Error + Send + 'static,
+    {
+        let stream_state = Arc::new(Mutex::new(StreamState::default()));
+        let handler_future = {
+            let stream_state = stream_state.clone();
+            async move {
+                let result = async {
+                    while let Some(m) = stream.next().await {
+                        let m = match m {
+                            Ok(m) => m,
+                            Err(e) if e.is_recoverable() => continue,
+                            Err(e) => return Err(e.into()),
+                        };
+                        // The handler is called without holding the lock, so that calls can
+                        // still be registered and dropped while it runs.
+                        let unclaimed = stream_state.lock().unwrap().route(m);
+                        if let Some(m) = unclaimed {
+                            msg_handler(m)
+                                .await
+                                .map_err(|e| Error::HandlerError(Box::new(e)))?;
+                        }
+                    }
+                    Ok::<(), Error>(())
+                }
+                .await;
+                // Nothing else will answer the outstanding calls, so cancel them.
+                stream_state.lock().unwrap().calls.clear();
+                result
+            }
+        };
+        let (handler_future, _) = future::abortable(handler_future);
+        tokio::spawn(handler_future);
+
+        IrcRpcConnection {
+            sink: futures::lock::Mutex::new(sink),
+            stream_state,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Sets how long a call can wait for its responses.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sends the call's messages and waits for its responses. Several calls can be in flight at
+    /// once; each incoming message goes to the oldest call whose `msg_filter` doesn't skip it.
+    pub async fn call<T: RpcCall + Sync + Send + 'static>(
+        &self,
+        call: T,
+    ) -> Result<T::Output, RpcCallError<T::Err>> {
+        let exchange = async {
+            let mut sink = self.sink.lock().await;
+            let messages = call.send_messages();
+            // Registering while holding the sink puts calls in the order their messages go out,
+            // and registering before sending makes sure no response can arrive before the call
+            // is listening for it.
+            let (id, rx) = self
+                .stream_state
+                .lock()
+                .unwrap()
+                .register(Box::new(ObjectSafeCallWrapper(call)));
+            let _registration = Registration {
+                id,
+                stream_state: &self.stream_state,
+            };
+            sink.send_all(&mut stream::iter(messages).map(Ok))
+                .await
+                .map_err(|_| RpcCallError::RpcCancelledError)?;
+            drop(sink);
+            rx.await.map_err(|_| RpcCallError::RpcCancelledError)
+        };
+        let result = tokio::time::timeout(self.timeout, exchange)
+            .await
+            .map_err(|_| RpcCallError::Timeout)??;
+        match result {
+            Ok(state) => {
+                let RpcState {
+                    response_messages,
+                    call,
+                } = state;
+                let call = call.to_inner().downcast::<T>().unwrap();
+                Ok(call
+                    .recv_messages(response_messages)
+                    .map_err(RpcCallError::CallError)?)
+            }
+            Err(e) => Err(RpcCallError::CallError(*e.downcast::<T::Err>().unwrap())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use minibot_irc_raw::MessageRef;
+
+    /// Collects messages with the given command until one with the end command.
+    struct Collect(&'static str, &'static str);
+
+    #[derive(thiserror::Error, Debug)]
+    #[error("unused")]
+    struct Unused;
+
+    impl RpcCall for Collect {
+        type Output = Vec<Message>;
+        type Err = Unused;
+        fn send_messages(&self) -> Vec<Message> {
+            Vec::new()
+        }
+        fn msg_filter(&self, msg: &Message) -> Result<FilterResult, Unused> {
+            Ok(if msg.has_named_command(self.1) {
+                FilterResult::End
+            } else if msg.has_named_command(self.0) {
+                FilterResult::Next
+            } else {
+                FilterResult::Skip
+            })
+        }
+        fn recv_messages(&self, msgs: Vec<Message>) -> Result<Vec<Message>, Unused> {
+            Ok(msgs)
+        }
+    }
+
+    fn parse(line: &str) -> Message {
+        MessageRef::parse(line.as_bytes())
+            .unwrap()
+            .to_owned()
+            .unwrap()
+    }
+
+    #[test]
+    fn routes_to_the_oldest_accepting_call() {
+        let mut state = StreamState::default();
+        let (_, mut first) = state.register(Box::new(ObjectSafeCallWrapper(Collect("A", "END"))));
+        let (second_id, mut second) =
+            state.register(Box::new(ObjectSafeCallWrapper(Collect("B", "END"))));
+        let (_, mut third) = state.register(Box::new(ObjectSafeCallWrapper(Collect("B", "END"))));
+
+        assert!(state.route(parse("B :1")).is_none());
+        assert!(state.route(parse("C :2")).is_some());
+        assert!(state.route(parse("END")).is_none());
+        let response = first.try_recv().unwrap().unwrap().ok().unwrap();
+        assert_eq!(response.response_messages.len(), 1);
+
+        state.deregister(second_id);
+        assert!(second.try_recv().is_err());
+        assert!(state.route(parse("END")).is_none());
+        let response = third.try_recv().unwrap().unwrap().ok().unwrap();
+        assert_eq!(response.response_messages.len(), 1);
+    }
+
+    /// A connection whose other end is driven by the test: `server_send` feeds the stream, and
+    /// `client_sent` gets everything the connection sends.
+    fn connection() -> (
+        IrcRpcConnection,
+        futures::channel::mpsc::UnboundedSender<Message>,
+        futures::channel::mpsc::UnboundedReceiver<Message>,
+    ) {
+        use futures::channel::mpsc;
+        let (server_send, stream) = mpsc::unbounded::<Message>();
+        let (sink, client_sent) = mpsc::unbounded::<Message>();
+        let connection = IrcRpcConnection::new(
+            IrcStream::from_stream(stream.map(Ok)),
+            IrcSink::from_sink(sink.sink_map_err(|_| {
+                minibot_irc_raw::Error::Io(std::io::ErrorKind::BrokenPipe.into())
+            })),
+            |_| future::ready(Ok::<_, Unused>(())),
+        );
+        (connection, server_send, client_sent)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn calls_time_out_and_deregister() {
+        let (connection, server_send, _client_sent) = connection();
+        let connection = connection.with_timeout(Duration::from_secs(5));
+
+        let result = connection.call(Collect("A", "END")).await;
+        assert!(matches!(result, Err(RpcCallError::Timeout)));
+        assert!(connection.stream_state.lock().unwrap().calls.is_empty());
+
+        // A dropped call is deregistered too.
+        let mut call = Box::pin(connection.call(Collect("A", "END")));
+        assert!(futures::poll!(call.as_mut()).is_pending());
+        assert_eq!(connection.stream_state.lock().unwrap().calls.len(), 1);
+        drop(call);
+        assert!(connection.stream_state.lock().unwrap().calls.is_empty());
+        drop(server_send);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn calls_are_cancelled_when_the_stream_ends() {
+        let (connection, server_send, _client_sent) = connection();
+
+        let mut call = Box::pin(connection.call(Collect("A", "END")));
+        assert!(futures::poll!(call.as_mut()).is_pending());
+        drop(server_send);
+        assert!(matches!(call.await, Err(RpcCallError::RpcCancelledError)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn calls_get_responses_in_send_order() {
+        let (connection, server_send, _client_sent) = connection();
+
+        let mut first = Box::pin(connection.call(Collect("A", "END")));
+        let mut second = Box::pin(connection.call(Collect("A", "END")));
+        // The second call gets the sink first, so it is the oldest.
+        assert!(futures::poll!(second.as_mut()).is_pending());
+        assert!(futures::poll!(first.as_mut()).is_pending());
+
+        server_send.unbounded_send(parse("A :1")).unwrap();
+        server_send.unbounded_send(parse("END")).unwrap();
+        assert_eq!(second.await.unwrap().len(), 2);
+        server_send.unbounded_send(parse("END")).unwrap();
+        assert_eq!(first.await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,484 @@
+//! A client that stays connected.
+//!
+//! `SupervisedClient` owns a `Client` and replaces it whenever the connection is lost: the stream
+//! dropped, the keepalive timed out, or Twitch sent `RECONNECT`. Attempts are spaced out with
+//! jittered exponential backoff, and each new connection goes through capability negotiation and
+//! login again before rejoining the channels that were joined.
+
+use crate::cap::CapError;
+use crate::client::{
+    Client, ClientError, ClientFactory, ClientResult, ConnectionEvent, DisconnectReason, LoginError,
+};
+use crate::event::{Event, SlowSubscriberPolicy};
+use crate::futures_util::event_sink::EventSink;
+use futures::channel::{mpsc, oneshot};
+use futures::prelude::*;
+use futures::select;
+use minibot_irc_raw::{Command, Message};
+use rand::Rng;
+use std::collections::{BTreeSet, VecDeque};
+use std::time::Duration;
+
+#[derive(Copy, Clone, Debug)]
+pub struct BackoffConfig {
+    /// The delay before the first retry.
+    pub initial: Duration,
+    /// The longest delay between attempts.
+    pub max: Duration,
+    pub multiplier: f64,
+    /// The fraction of each delay that is randomized, so that many clients dropped at once don't
+    /// all come back at once.
+    pub jitter: f64,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        BackoffConfig {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(120),
+            multiplier: 2.0,
+            jitter: 0.5,
+        }
+    }
+}
+
+impl BackoffConfig {
+    fn delay(&self, attempt: u32) -> Duration {
+        // Capped before it becomes a `Duration`, which can't hold what the growth reaches during a
+        // long outage.
+        let growth = self.multiplier.powi(attempt.min(i32::MAX as u32) as i32);
+        let base = (self.initial.as_secs_f64() * growth)
+            .min(self.max.as_secs_f64())
+            .max(0.0);
+        let jitter = self.jitter.clamp(0.0, 1.0) * rand::thread_rng().gen::<f64>();
+        Duration::from_secs_f64(base * (1.0 - jitter))
+    }
+}
+
+/// What happens to messages sent while there is no connection.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OutgoingPolicy {
+    /// Keep up to this many messages, and send them once connected again. Sends past the limit
+    /// fail with `ClientError::NotConnected`.
+    Buffer(usize),
+    /// Fail every send with `ClientError::NotConnected`.
+    Reject,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct SupervisorConfig {
+    pub backoff: BackoffConfig,
+    pub outgoing: OutgoingPolicy,
+    /// How long connecting and logging in may take before the attempt counts as failed.
+    pub connect_timeout: Duration,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        SupervisorConfig {
+            backoff: BackoffConfig::default(),
+            outgoing: OutgoingPolicy::Buffer(100),
+            connect_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ConnectionState {
+    /// Connecting and logging in. `attempt` counts the failures since the last good connection.
+    Connecting {
+        attempt: u32,
+    },
+    Connected,
+    Disconnected(DisconnectReason),
+    /// Waiting this long before the next attempt.
+    Waiting(Duration),
+    /// Gave up for good, because retrying wouldn't help (e.g. the login was rejected).
+    Failed(String),
+    Closed,
+}
+
+enum Request {
+    Send(Message, oneshot::Sender<ClientResult<()>>),
+    Join(String, oneshot::Sender<ClientResult<()>>),
+    Part(String, oneshot::Sender<ClientResult<()>>),
+}
+
+struct Endpoint {
+    factory: ClientFactory,
+    host: String,
+    port: u16,
+    user: String,
+    token: String,
+}
+
+/// The state that carries over from one connection to the next.
+struct Session {
+    outgoing: OutgoingPolicy,
+    channels: BTreeSet<String>,
+    queue: VecDeque<Message>,
+    states: mpsc::Sender<ConnectionState>,
+    events: mpsc::Sender<Event>,
+}
+
+/// Errors that will happen again on every attempt.
+fn is_fatal(e: &ClientError) -> bool {
+    matches!(
+        e,
+        ClientError::Login(LoginError::AuthenticationFailed(_))
+            | ClientError::Login(LoginError::Cap(CapError::Unavailable(_)))
+            | ClientError::Login(LoginError::Cap(CapError::Rejected(_)))
+    )
+}
+
+impl Session {
+    async fn set_state(&mut self, state: ConnectionState) {
+        let _ = self.states.send(state).await;
+    }
+
+    fn queue(&mut self, msg: Message) -> ClientResult<()> {
+        match self.outgoing {
+            OutgoingPolicy::Buffer(limit) if self.queue.len() < limit => {
+                self.queue.push_back(msg);
+                Ok(())
+            }
+            _ => Err(ClientError::NotConnected),
+        }
+    }
+
+    fn handle_offline(&mut self, request: Request) {
+        match request {
+            Request::Send(msg, reply) => {
+                let _ = reply.send(self.queue(msg));
+            }
+            Request::Join(channel, reply) => {
+                self.channels.insert(channel);
+                let _ = reply.send(Ok(()));
+            }
+            Request::Part(channel, reply) => {
+                self.channels.remove(&channel);
+                let _ = reply.send(Ok(()));
+            }
+        }
+    }
+
+    async fn handle_online(&mut self, client: &mut Client, request: Request) {
+        match request {
+            Request::Send(msg, reply) => {
+                let result = match client.send(msg.clone()).await {
+                    // The connection is going away. Keep the message for the next one.
+                    Err(ClientError::AlreadyClosed) => self.queue(msg),
+                    result => result,
+                };
+                let _ = reply.send(result);
+            }
+            Request::Join(channel, reply) => {
+                let result = client.join(&channel).await;
+                if result.is_ok() {
+                    self.channels.insert(channel);
+                }
+                let _ = reply.send(result);
+            }
+            Request::Part(channel, reply) => {
+                let result = client.part(&channel).await;
+                self.channels.remove(&channel);
+                let _ = reply.send(result);
+            }
+        }
+    }
+
+    /// Gets a new connection up to date with the session.
+    async fn restore(&mut self, client: &mut Client) -> ClientResult<()> {
+        for channel in &self.channels {
+            client.join(channel).await?;
+        }
+        while let Some(msg) = self.queue.pop_front() {
+            client.send(msg).await?;
+        }
+        Ok(())
+    }
+
+    /// Serves requests until the connection is lost, returning why. Returns `None` if the
+    /// supervised client was closed.
+    async fn run_connected(
+        &mut self,
+        mut client: Client,
+        requests: &mut mpsc::Receiver<Request>,
+    ) -> Option<DisconnectReason> {
+        let (events_sink, mut events) = mpsc::channel(1);
+        let (inbound_sink, mut inbound) = mpsc::channel(16);
+        let restored = match client
+            .add_connection_listener(events_sink)
+            .and_then(|()| client.add_event_listener(inbound_sink, SlowSubscriberPolicy::Block))
+        {
+            Ok(()) => self.restore(&mut client).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = restored {
+            let _ = client.close().await;
+            return Some(DisconnectReason::Error(e.to_string()));
+        }
+        // Forwarded on the side, so that requests are still served while listeners are slow. The
+        // stream ends when the client closes.
+        let mut session_events = self.events.clone();
+        tokio::spawn(async move {
+            while let Some(event) = inbound.next().await {
+                if session_events.send(event).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        loop {
+            select! {
+                event = events.next() => {
+                    let reason = match event {
+                        Some(ConnectionEvent::Disconnected(reason)) => reason,
+                        Some(ConnectionEvent::IgnoredMessage(_)) => continue,
+                        None => DisconnectReason::Closed,
+                    };
+                    let _ = client.close().await;
+                    return Some(reason);
+                }
+                request = requests.next() => match request {
+                    Some(request) => self.handle_online(&mut client, request).await,
+                    None => {
+                        let _ = client.send(Message::from_command(Command::from_name("QUIT"))).await;
+                        let _ = client.close().await;
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn run_supervisor(
+    endpoint: Endpoint,
+    config: SupervisorConfig,
+    mut session: Session,
+    mut requests: mpsc::Receiver<Request>,
+) {
+    let mut attempt = 0;
+    loop {
+        session
+            .set_state(ConnectionState::Connecting { attempt })
+            .await;
+
+        let connect = tokio::time::timeout(
+            config.connect_timeout,
+            endpoint.factory.connect(
+                &endpoint.host,
+                endpoint.port,
+                &endpoint.user,
+                &endpoint.token,
+            ),
+        )
+        .map(|connected| connected.unwrap_or(Err(ClientError::ConnectTimeout)))
+        .fuse();
+        futures::pin_mut!(connect);
+        let connected = loop {
+            select! {
+                connected = connect => break connected,
+                request = requests.next() => match request {
+                    Some(request) => session.handle_offline(request),
+                    None => {
+                        session.set_state(ConnectionState::Closed).await;
+                        return;
+                    }
+                }
+            }
+        };
+
+        match connected {
+            Ok(client) => {
+                attempt = 0;
+                session.set_state(ConnectionState::Connected).await;
+                match session.run_connected(client, &mut requests).await {
+                    Some(reason) => {
+                        session
+                            .set_state(ConnectionState::Disconnected(reason))
+                            .await
+                    }
+                    None => {
+                        session.set_state(ConnectionState::Closed).await;
+                        return;
+                    }
+                }
+            }
+            Err(e) if is_fatal(&e) => {
+                session
+                    .set_state(ConnectionState::Failed(e.to_string()))
+                    .await;
+                return;
+            }
+            Err(e) => {
+                session
+                    .set_state(ConnectionState::Disconnected(DisconnectReason::Error(
+                        e.to_string(),
+                    )))
+                    .await
+            }
+        }
+
+        let delay = config.backoff.delay(attempt);
+        attempt += 1;
+        session.set_state(ConnectionState::Waiting(delay)).await;
+
+        let sleep = tokio::time::sleep(delay).fuse();
+        futures::pin_mut!(sleep);
+        loop {
+            select! {
+                () = sleep => break,
+                request = requests.next() => match request {
+                    Some(request) => session.handle_offline(request),
+                    None => {
+                        session.set_state(ConnectionState::Closed).await;
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct SupervisedInner {
+    requests: mpsc::Sender<Request>,
+    states_channel: EventSink<ConnectionState>,
+    inbound_channel: EventSink<Event>,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+pub struct SupervisedClient(Option<SupervisedInner>);
+
+impl SupervisedClient {
+    /// Starts connecting in the background. Requests made before the first connection is up are
+    /// handled as if the connection had dropped.
+    pub fn start(
+        factory: ClientFactory,
+        host: &str,
+        port: u16,
+        user: &str,
+        token: &str,
+        config: SupervisorConfig,
+    ) -> Self {
+        let (requests, requests_stream) = mpsc::channel(3);
+        let (states_sink, states_stream) = mpsc::channel(3);
+        let (events_sink, events_stream) = mpsc::channel(3);
+
+        let endpoint = Endpoint {
+            factory,
+            host: host.to_string(),
+            port,
+            user: user.to_string(),
+            token: token.to_string(),
+        };
+        let session = Session {
+            outgoing: config.outgoing,
+            channels: BTreeSet::new(),
+            queue: VecDeque::new(),
+            states: states_sink,
+            events: events_sink,
+        };
+
+        let handle = tokio::spawn(run_supervisor(endpoint, config, session, requests_stream));
+
+        SupervisedClient(Some(SupervisedInner {
+            requests,
+            states_channel: EventSink::new(states_stream),
+            inbound_channel: EventSink::new(events_stream),
+            handle,
+        }))
+    }
+
+    fn get_inner_mut(&mut self) -> ClientResult<&mut SupervisedInner> {
+        self.0.as_mut().ok_or(ClientError::AlreadyClosed)
+    }
+
+    /// Adds a listener for changes to the connection state.
+    pub fn add_state_listener(
+        &mut self,
+        listener: mpsc::Sender<ConnectionState>,
+    ) -> ClientResult<()> {
+        self.get_inner_mut()?.states_channel.add_sink(listener);
+        Ok(())
+    }
+
+    /// Adds a listener for events from the server, across every connection.
+    pub fn add_event_listener(
+        &mut self,
+        listener: mpsc::Sender<Event>,
+        policy: SlowSubscriberPolicy,
+    ) -> ClientResult<()> {
+        self.get_inner_mut()?
+            .inbound_channel
+            .add_sink_with_policy(listener, policy);
+        Ok(())
+    }
+
+    async fn request(
+        &mut self,
+        make_request: impl FnOnce(oneshot::Sender<ClientResult<()>>) -> Request,
+    ) -> ClientResult<()> {
+        let (reply, reply_recv) = oneshot::channel();
+        self.get_inner_mut()?
+            .requests
+            .send(make_request(reply))
+            .await
+            .map_err(|_| ClientError::AlreadyClosed)?;
+        reply_recv.await.map_err(|_| ClientError::AlreadyClosed)?
+    }
+
+    /// Sends a message, or handles it according to the `OutgoingPolicy` if there is no
+    /// connection. A buffered message counts as sent.
+    pub async fn send(&mut self, msg: Message) -> ClientResult<()> {
+        msg.validate()?;
+        self.request(|reply| Request::Send(msg, reply)).await
+    }
+
+    /// Joins a channel, now if connected, and again after every reconnect.
+    pub async fn join(&mut self, channel: &str) -> ClientResult<()> {
+        let channel = channel.to_string();
+        self.request(|reply| Request::Join(channel, reply)).await
+    }
+
+    pub async fn part(&mut self, channel: &str) -> ClientResult<()> {
+        let channel = channel.to_string();
+        self.request(|reply| Request::Part(channel, reply)).await
+    }
+
+    pub async fn close(mut self) -> ClientResult<()> {
+        let SupervisedInner { handle, .. } = self.0.take().unwrap();
+        handle.await?;
+        Ok(())
+    }
+}
+
+impl Drop for SupervisedClient {
+    fn drop(&mut self) {
+        assert!(
+            self.0.is_none(),
+            "SupervisedClient was dropped without being waited on."
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_to_the_limit() {
+        let config = BackoffConfig {
+            jitter: 0.0,
+            ..BackoffConfig::default()
+        };
+        assert_eq!(config.delay(0), Duration::from_secs(1));
+        assert_eq!(config.delay(3), Duration::from_secs(8));
+        assert_eq!(config.delay(20), config.max);
+        assert_eq!(config.delay(u32::MAX), config.max);
+
+        let jittered = BackoffConfig::default().delay(3);
+        assert!(jittered <= Duration::from_secs(8) && jittered >= Duration::from_secs(4));
+    }
+}
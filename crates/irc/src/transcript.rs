@@ -0,0 +1,522 @@
+//! Recording and replaying of IRC traffic.
+//!
+//! A transcript has one entry per line, in the form `<millis since epoch> <direction> <line>`,
+//! where the direction is `<` for lines from the server and `>` for lines from the client. Line
+//! contents are escaped so that a stray CR or LF from a malformed message can't break the
+//! transcript. Passwords are redacted before they are written.
+//!
+//! A recorded transcript can be replayed as an `IrcStream`, which can be passed to
+//! `ClientFactory::connect_with` to reproduce a session locally.
+
+use futures::channel::oneshot;
+use futures::prelude::*;
+use minibot_byte_string::ByteString;
+use minibot_irc_raw::{Error as IrcError, IrcSink, IrcStream, KnownCommand, Message, MessageRef};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const REDACTED_PASS: &[u8] = b"PASS <redacted>";
+const REDACTED_AUTHENTICATE: &[u8] = b"AUTHENTICATE <redacted>";
+const REDACTED_IDENTIFY: &[u8] = b"PRIVMSG NickServ :IDENTIFY <redacted>";
+
+// AUTHENTICATE parameters that aren't credentials: SASL mechanisms, an empty response, and an
+// abort.
+const AUTHENTICATE_CLEAR: &[&[u8]] = &[b"PLAIN", b"EXTERNAL", b"+", b"*"];
+
+/// Returns what to record instead of a message that carries a password.
+fn redacted(msg: &Message) -> Option<&'static [u8]> {
+    match msg.known_command()? {
+        KnownCommand::Pass => Some(REDACTED_PASS),
+        KnownCommand::Authenticate => match msg.params() {
+            [param] if AUTHENTICATE_CLEAR.iter().any(|clear| param.eq_bytes(clear)) => None,
+            _ => Some(REDACTED_AUTHENTICATE),
+        },
+        KnownCommand::Privmsg => match msg.params() {
+            [target, text]
+                if target.as_ref().eq_ignore_ascii_case(b"NickServ")
+                    && text
+                        .as_ref()
+                        .get(..9)
+                        .is_some_and(|start| start.eq_ignore_ascii_case(b"IDENTIFY ")) =>
+            {
+                Some(REDACTED_IDENTIFY)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TranscriptError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("Invalid transcript entry on line {line}: {reason}")]
+    Parse { line: usize, reason: String },
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// From the server to the client.
+    Incoming,
+    /// From the client to the server.
+    Outgoing,
+}
+
+impl Direction {
+    fn marker(self) -> u8 {
+        match self {
+            Direction::Incoming => b'<',
+            Direction::Outgoing => b'>',
+        }
+    }
+
+    fn from_marker(marker: &[u8]) -> Option<Self> {
+        match marker {
+            b"<" => Some(Direction::Incoming),
+            b">" => Some(Direction::Outgoing),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    pub timestamp: SystemTime,
+    pub direction: Direction,
+    pub line: ByteString,
+}
+
+fn escape_line(line: &[u8], out: &mut Vec<u8>) {
+    for &b in line {
+        match b {
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b => out.push(b),
+        }
+    }
+}
+
+fn unescape_line(line: &[u8]) -> Result<Vec<u8>, String> {
+    let mut result = Vec::with_capacity(line.len());
+    let mut iter = line.iter().copied();
+    while let Some(b) = iter.next() {
+        if b != b'\\' {
+            result.push(b);
+            continue;
+        }
+
+        result.push(match iter.next() {
+            Some(b'\\') => b'\\',
+            Some(b'r') => b'\r',
+            Some(b'n') => b'\n',
+            other => return Err(format!("Invalid escape: {:?}", other.map(char::from))),
+        });
+    }
+    Ok(result)
+}
+
+impl Entry {
+    fn write_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        let millis = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let mut text = format!("{} ", millis).into_bytes();
+        text.push(self.direction.marker());
+        text.push(b' ');
+        escape_line(self.line.as_ref(), &mut text);
+        text.push(b'\n');
+        out.write_all(&text)
+    }
+
+    fn parse(line_num: usize, text: &[u8]) -> Result<Self, TranscriptError> {
+        let parse_error = |reason: &str| TranscriptError::Parse {
+            line: line_num,
+            reason: reason.to_string(),
+        };
+
+        let mut parts = text.splitn(3, |b| b == &b' ');
+        let millis = parts
+            .next()
+            .and_then(|m| std::str::from_utf8(m).ok())
+            .and_then(|m| m.parse::<u64>().ok())
+            .ok_or_else(|| parse_error("Invalid timestamp"))?;
+        let direction = parts
+            .next()
+            .and_then(Direction::from_marker)
+            .ok_or_else(|| parse_error("Invalid direction"))?;
+        let line = unescape_line(parts.next().ok_or_else(|| parse_error("Missing line"))?)
+            .map_err(|e| parse_error(&e))?;
+
+        Ok(Entry {
+            timestamp: UNIX_EPOCH + Duration::from_millis(millis),
+            direction,
+            line: ByteString::from(line),
+        })
+    }
+}
+
+/// How long written entries can sit in the output's buffer before they are flushed.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+enum WriterRequest {
+    Entry(Entry),
+    Flush(oneshot::Sender<io::Result<()>>),
+}
+
+fn keep_first_error(result: io::Result<()>, error: &mut Option<io::Error>) {
+    if let Err(e) = result {
+        error.get_or_insert(e);
+    }
+}
+
+fn run_writer(mut out: Box<dyn Write + Send>, requests: mpsc::Receiver<WriterRequest>) {
+    // The first error since the last `flush()`, which reports it.
+    let mut error = None;
+    // Set while there are entries that haven't been flushed.
+    let mut flush_at: Option<Instant> = None;
+    loop {
+        let request = match flush_at {
+            Some(at) if at <= Instant::now() => Err(RecvTimeoutError::Timeout),
+            Some(at) => requests.recv_timeout(at - Instant::now()),
+            None => requests.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match request {
+            Ok(WriterRequest::Entry(entry)) => {
+                keep_first_error(entry.write_to(&mut *out), &mut error);
+                flush_at.get_or_insert_with(|| Instant::now() + FLUSH_INTERVAL);
+            }
+            Ok(WriterRequest::Flush(reply)) => {
+                keep_first_error(out.flush(), &mut error);
+                flush_at = None;
+                let _ = reply.send(error.take().map_or(Ok(()), Err));
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                keep_first_error(out.flush(), &mut error);
+                flush_at = None;
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                let _ = out.flush();
+                return;
+            }
+        }
+    }
+}
+
+/// Writes transcript entries. The writing happens on a thread of its own, so that a slow disk
+/// doesn't hold up the connection. Entries are flushed every `FLUSH_INTERVAL`, on `flush()`, and
+/// once the writer and all its clones are dropped. Clones share the same output.
+#[derive(Clone)]
+pub struct TranscriptWriter(mpsc::Sender<WriterRequest>);
+
+impl TranscriptWriter {
+    pub fn new<W: Write + Send + 'static>(out: W) -> Self {
+        let (requests, requests_recv) = mpsc::channel();
+        std::thread::spawn(move || run_writer(Box::new(out), requests_recv));
+        TranscriptWriter(requests)
+    }
+
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(TranscriptWriter::new(io::BufWriter::new(
+            std::fs::File::create(path)?,
+        )))
+    }
+
+    /// Flushes every entry recorded so far. Returns the first error that happened while writing
+    /// since the last call, since a broken transcript doesn't take down the connection it is
+    /// recording.
+    pub async fn flush(&self) -> io::Result<()> {
+        let (reply, reply_recv) = oneshot::channel();
+        let closed = || io::Error::new(io::ErrorKind::BrokenPipe, "Transcript writer has stopped");
+        self.0
+            .send(WriterRequest::Flush(reply))
+            .map_err(|_| closed())?;
+        reply_recv.await.map_err(|_| closed())?
+    }
+
+    fn record(&self, direction: Direction, line: &[u8]) {
+        let entry = Entry {
+            timestamp: SystemTime::now(),
+            direction,
+            line: ByteString::from_slice(line),
+        };
+        // The writer thread only stops if it panicked, and then there is nothing left to do.
+        let _ = self.0.send(WriterRequest::Entry(entry));
+    }
+
+    fn record_message(&self, direction: Direction, msg: &Message) {
+        match redacted(msg) {
+            Some(line) => self.record(direction, line),
+            None => self.record(direction, msg.to_wire().as_ref()),
+        }
+    }
+}
+
+/// Wraps a stream and sink so that every line through them is written to the transcript.
+pub fn record(stream: IrcStream, sink: IrcSink, writer: TranscriptWriter) -> (IrcStream, IrcSink) {
+    let stream = {
+        let writer = writer.clone();
+        stream.inspect(move |item| match item {
+            Ok(msg) => writer.record_message(Direction::Incoming, msg),
+            Err(IrcError::Malformed { raw, .. }) => {
+                writer.record(Direction::Incoming, raw.as_ref())
+            }
+            Err(_) => {}
+        })
+    };
+
+    let sink = sink.with(move |msg: Message| {
+        writer.record_message(Direction::Outgoing, &msg);
+        future::ready(Ok::<_, IrcError>(msg))
+    });
+
+    (IrcStream::from_stream(stream), IrcSink::from_sink(sink))
+}
+
+/// How fast a transcript is replayed.
+#[derive(Copy, Clone, Debug)]
+pub enum ReplaySpeed {
+    /// The same gaps between messages as when they were recorded.
+    Original,
+    /// Gaps are divided by the factor, so `Scaled(10.0)` replays ten times as fast. A factor that
+    /// isn't positive, or is NaN, replays like `Immediate`.
+    Scaled(f64),
+    /// No gaps at all.
+    Immediate,
+}
+
+impl ReplaySpeed {
+    fn delay(self, gap: Duration) -> Option<Duration> {
+        match self {
+            ReplaySpeed::Original => Some(gap),
+            ReplaySpeed::Scaled(factor) if factor > 0.0 => Some(
+                Duration::try_from_secs_f64(gap.as_secs_f64() / factor).unwrap_or(Duration::MAX),
+            ),
+            ReplaySpeed::Scaled(_) | ReplaySpeed::Immediate => None,
+        }
+    }
+}
+
+pub struct Transcript {
+    entries: Vec<Entry>,
+}
+
+impl Transcript {
+    pub fn read_from<R: BufRead>(input: R) -> Result<Self, TranscriptError> {
+        let mut entries = Vec::new();
+        for (i, line) in input.split(b'\n').enumerate() {
+            let line = line?;
+            if !line.is_empty() {
+                entries.push(Entry::parse(i + 1, &line)?);
+            }
+        }
+        Ok(Transcript { entries })
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, TranscriptError> {
+        Transcript::read_from(io::BufReader::new(std::fs::File::open(path)?))
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries[..]
+    }
+
+    /// Replays the incoming side of the transcript. Lines that don't parse are yielded as
+    /// `Error::Malformed`, as a lenient stream would. Everything sent to the returned sink is
+    /// discarded.
+    pub fn replay(self, speed: ReplaySpeed) -> (IrcStream, IrcSink) {
+        let incoming = self
+            .entries
+            .into_iter()
+            .filter(|entry| entry.direction == Direction::Incoming);
+
+        let stream = stream::unfold(
+            (incoming, None::<SystemTime>),
+            move |(mut incoming, prev_timestamp)| async move {
+                let entry = incoming.next()?;
+                if let Some(prev_timestamp) = prev_timestamp {
+                    let gap = entry
+                        .timestamp
+                        .duration_since(prev_timestamp)
+                        .unwrap_or_default();
+                    if let Some(delay) = speed.delay(gap) {
+                        tokio::time::sleep(delay).await;
+                    }
+                }
+
+                let item = MessageRef::parse(entry.line.as_ref())
+                    .and_then(|msg| msg.to_owned())
+                    .map_err(|error| IrcError::Malformed {
+                        raw: ByteString::from_slice(entry.line.as_ref()),
+                        error,
+                    });
+                Some((item, (incoming, Some(entry.timestamp))))
+            },
+        );
+
+        let sink = sink::drain().sink_map_err(IrcError::from);
+
+        (
+            IrcStream::from_stream(Box::pin(stream)),
+            IrcSink::from_sink(sink),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::channel::mpsc as async_mpsc;
+    use std::sync::{Arc, Mutex};
+
+    struct SharedVec(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedVec {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let entry = Entry {
+            timestamp: UNIX_EPOCH + Duration::from_millis(1234),
+            direction: Direction::Incoming,
+            line: ByteString::from_slice(b"PRIVMSG #a :back\\slash\r\nnewline"),
+        };
+
+        let mut text = Vec::new();
+        entry.write_to(&mut text).unwrap();
+        assert_eq!(
+            &text[..],
+            &b"1234 < PRIVMSG #a :back\\\\slash\\r\\nnewline\n"[..]
+        );
+
+        let parsed = Entry::parse(1, &text[..text.len() - 1]).unwrap();
+        assert_eq!(parsed.timestamp, entry.timestamp);
+        assert_eq!(parsed.direction, Direction::Incoming);
+        assert!(parsed.line.eq_bytes(entry.line.as_ref()));
+    }
+
+    #[test]
+    fn redacts_passwords() {
+        use futures::executor::block_on;
+
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let writer = TranscriptWriter::new(SharedVec(out.clone()));
+        writer.record_message(
+            Direction::Outgoing,
+            &Message::from_named_command_params("PASS", ["oauth:secret"]),
+        );
+        block_on(writer.flush()).unwrap();
+        let text = String::from_utf8(out.lock().unwrap().clone()).unwrap();
+        assert!(text.ends_with("> PASS <redacted>\n"));
+
+        for (msg, recorded) in [
+            (["PLAIN"].as_ref(), "AUTHENTICATE :PLAIN"),
+            (["Ym90AGJvdABodW50ZXIy"].as_ref(), "AUTHENTICATE <redacted>"),
+        ] {
+            writer.record_message(
+                Direction::Outgoing,
+                &Message::from_named_command_params("AUTHENTICATE", msg),
+            );
+            block_on(writer.flush()).unwrap();
+            let text = String::from_utf8(out.lock().unwrap().clone()).unwrap();
+            assert!(text.ends_with(&format!("> {}\n", recorded)));
+        }
+
+        writer.record_message(
+            Direction::Outgoing,
+            &Message::from_named_command_params("PRIVMSG", ["nickserv", "identify bot secret"]),
+        );
+        block_on(writer.flush()).unwrap();
+        let text = String::from_utf8(out.lock().unwrap().clone()).unwrap();
+        assert!(text.ends_with("> PRIVMSG NickServ :IDENTIFY <redacted>\n"));
+    }
+
+    #[test]
+    fn flush_reports_write_errors() {
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk full"))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let writer = TranscriptWriter::new(Broken);
+        writer.record(Direction::Incoming, b"PING :a");
+        writer.record(Direction::Incoming, b"PING :b");
+        let error = futures::executor::block_on(writer.flush()).unwrap_err();
+        assert_eq!(error.to_string(), "disk full");
+
+        // Each error is only reported once.
+        futures::executor::block_on(writer.flush()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn record_passes_lines_through() {
+        let (server_send, incoming) = async_mpsc::unbounded::<Message>();
+        let (outgoing, mut client_sent) = async_mpsc::unbounded::<Message>();
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let writer = TranscriptWriter::new(SharedVec(out.clone()));
+        let (mut stream, mut sink) = record(
+            IrcStream::from_stream(incoming.map(Ok)),
+            IrcSink::from_sink(
+                outgoing.sink_map_err(|_| IrcError::Io(io::ErrorKind::BrokenPipe.into())),
+            ),
+            writer.clone(),
+        );
+
+        let ping = Message::from_named_command_params("PING", ["a"]);
+        server_send.unbounded_send(ping.clone()).unwrap();
+        let received = stream.next().await.unwrap().unwrap();
+        assert!(received.to_wire().eq_bytes(ping.to_wire().as_ref()));
+        let pong = Message::from_named_command_params("PONG", ["a"]);
+        sink.send(pong.clone()).await.unwrap();
+        let sent = client_sent.next().await.unwrap();
+        assert!(sent.to_wire().eq_bytes(pong.to_wire().as_ref()));
+
+        writer.flush().await.unwrap();
+        let text = String::from_utf8(out.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = text
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().1)
+            .collect();
+        assert_eq!(lines, ["< PING :a", "> PONG :a"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replay_keeps_the_gaps() {
+        let text = b"1000 < PING :a\n3000 > PONG :a\n3000 < PING :b\n3500 < PING :c\n";
+        for (speed, gaps) in [
+            (ReplaySpeed::Original, [2000, 500]),
+            (ReplaySpeed::Scaled(2.0), [1000, 250]),
+            (ReplaySpeed::Scaled(0.0), [0, 0]),
+            (ReplaySpeed::Scaled(f64::NAN), [0, 0]),
+            (ReplaySpeed::Immediate, [0, 0]),
+        ] {
+            let transcript = Transcript::read_from(&text[..]).unwrap();
+            let (mut stream, _sink) = transcript.replay(speed);
+            stream.next().await.unwrap().unwrap();
+            for gap in gaps {
+                let start = tokio::time::Instant::now();
+                stream.next().await.unwrap().unwrap();
+                assert_eq!(start.elapsed(), Duration::from_millis(gap), "{:?}", speed);
+            }
+            assert!(stream.next().await.is_none());
+        }
+    }
+}